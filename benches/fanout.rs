@@ -0,0 +1,63 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::{runtime::Runtime, sync::mpsc};
+use pub_sub_rust::{cluster::ForwardedEvent, handler, serialize::EventKind, store::{Client, Subscribers}, subscriptions::{self, SubscriptionsTx}};
+
+const TOPIC: &str = "hot";
+
+/// Subscribes `count` clients to the hot topic, each draining its own socket channel.
+fn subscribe(runtime: &Runtime, count: usize) -> SubscriptionsTx {
+    runtime.block_on(async {
+        let subscriptions_tx = subscriptions::spawn();
+        for index in 0..count {
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+            tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+            let client = Client { user_id: index.to_string(), sender: Some(sender), ..Default::default() };
+            let _ = Subscribers::add_subscriber(String::from(TOPIC), client, subscriptions_tx.clone()).await;
+        }
+        subscriptions_tx
+    })
+}
+
+/// Latency of one publish to a topic with thousands of subscribers, from the subscriber lookup to
+/// the last message queued on a socket.
+fn publish_latency(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let mut group = c.benchmark_group("publish_latency");
+    for subscribers in [1_000, 5_000, 10_000] {
+        let subscriptions_tx = subscribe(&runtime, subscribers);
+        group.throughput(Throughput::Elements(subscribers as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(subscribers), &subscribers, |b, _| {
+            b.to_async(&runtime).iter(|| {
+                let event = ForwardedEvent { topic: String::from(TOPIC), kind: EventKind::Document, value: String::from("{\"price\":1}"), patch: None, user_id: String::from("publisher") };
+                handler::cluster_publish_handler(event, subscriptions_tx.clone())
+            });
+        });
+    }
+    group.finish();
+}
+
+/// Concurrent publishes while subscribers come and go: lookups read the snapshot and do not queue
+/// behind the subscribe commands on the actor.
+fn publish_during_churn(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let subscriptions_tx = subscribe(&runtime, 1_000);
+    let churn_tx = subscriptions_tx.clone();
+    runtime.spawn(async move {
+        let mut index = 0u64;
+        loop {
+            let client = Client { user_id: format!("churn:{}", index % 64), ..Default::default() };
+            let _ = Subscribers::add_subscriber(String::from(TOPIC), client.clone(), churn_tx.clone()).await;
+            let _ = Subscribers::remove_subscriber(String::from(TOPIC), client, churn_tx.clone()).await;
+            index += 1;
+        }
+    });
+    c.bench_function("publish_during_churn/1000", |b| {
+        b.to_async(&runtime).iter(|| {
+            let event = ForwardedEvent { topic: String::from(TOPIC), kind: EventKind::Document, value: String::from("{\"price\":1}"), patch: None, user_id: String::from("publisher") };
+            handler::cluster_publish_handler(event, subscriptions_tx.clone())
+        });
+    });
+}
+
+criterion_group!(benches, publish_latency, publish_during_churn);
+criterion_main!(benches);
@@ -0,0 +1,53 @@
+use std::thread;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::future::join_all;
+use serde_json::json;
+use tokio::{runtime::Runtime, sync::mpsc};
+use pub_sub_rust::{shard::{self, StoreShards}, store::Store};
+
+/// Concurrent clients, each setting its own keys.
+const CLIENTS: usize = 64;
+const SETS_PER_CLIENT: usize = 256;
+
+fn spawn_store(runtime: &Runtime, shards: usize) -> StoreShards {
+    let _guard = runtime.enter();
+    let (keyspace_tx, mut keyspace_rx) = mpsc::unbounded_channel();
+    let (replication_tx, mut replication_rx) = mpsc::unbounded_channel();
+    // Drain the notifications the store actors send, as the keyspace notifier and replication log would.
+    tokio::spawn(async move { while keyspace_rx.recv().await.is_some() {} });
+    tokio::spawn(async move { while replication_rx.recv().await.is_some() {} });
+    shard::spawn(shards, keyspace_tx, replication_tx)
+}
+
+async fn set_keys(store: StoreShards) {
+    join_all((0..CLIENTS).map(|client| {
+        let store = store.clone();
+        tokio::spawn(async move {
+            for index in 0..SETS_PER_CLIENT {
+                let key = format!("client:{}:key:{}", client, index % 16);
+                let _ = Store::set(key, json!({ "client": client, "index": index }), store.clone()).await;
+            }
+        })
+    })).await;
+}
+
+/// Throughput of concurrent Sets as the store is split across more actors. With one shard every
+/// command queues on a single actor; with one shard per core they run in parallel.
+fn sharded_sets(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+    let mut group = c.benchmark_group("sharded_sets");
+    group.throughput(Throughput::Elements((CLIENTS * SETS_PER_CLIENT) as u64));
+    let mut shards = 1;
+    while shards <= cores * 2 {
+        let store = spawn_store(&runtime, shards);
+        group.bench_with_input(BenchmarkId::from_parameter(shards), &shards, |b, _| {
+            b.to_async(&runtime).iter(|| set_keys(store.clone()));
+        });
+        shards *= 2;
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sharded_sets);
+criterion_main!(benches);
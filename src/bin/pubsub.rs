@@ -0,0 +1,203 @@
+//! Command-line client of the server: registers, opens a socket, and runs one command or a REPL.
+
+use std::{env, process};
+use futures::stream::{SelectAll, StreamExt};
+use serde_json::{json, Value};
+use tokio::io::{self, AsyncBufReadExt, BufReader};
+use pub_sub_rust::client::{ClientError, Event, PubSubClient, Subscription};
+use pub_sub_rust::serialize::{RequestAction, SocketEvent, SocketRequest};
+
+const USAGE: &str = "Usage: pubsub [--url URL] [--json] COMMAND
+Commands:
+  subscribe TOPIC...        print the events of the topics until interrupted
+  unsubscribe TOPIC         stop the events of a topic, in the repl
+  set TOPIC VALUE           set a key, VALUE is JSON or else taken as a string
+  unset TOPIC               unset a key
+  get TOPIC                 print the value of a key
+  collection TOPIC          print the members of a collection
+  add TOPIC VALUE           add a member to a collection
+  remove TOPIC VALUE        remove a member from a collection
+  repl                      read commands from stdin, one per line, while printing events
+Options:
+  --url URL                 server to register with, http://127.0.0.1:8000 by default
+  --json                    print each event and reply as one line of compact JSON";
+
+struct Options {
+    url: String,
+    json: bool,
+    command: Vec<String>
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(message) = run(args).await {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+async fn run(args: Vec<String>) -> Result<(), String> {
+    let options = parse_options(args)?;
+    let name = options.command.first().ok_or(USAGE)?.as_str();
+    if matches!(name, "help" | "--help" | "-h") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    // Checked before connecting, so that a mistyped command neither registers a client nor waits on the server.
+    let request = match name {
+        "subscribe" if options.command.len() < 2 => return Err(format!("subscribe needs a topic\n{}", USAGE)),
+        "subscribe" | "repl" => None,
+        _ => Some(command_request(&options.command)?)
+    };
+    let client = PubSubClient::connect(&options.url).await.map_err(|err| err.to_string())?;
+    let mut events = SelectAll::new();
+    match (name, request) {
+        (_, Some(request)) => execute(&client, request, &mut events, options.json).await,
+        ("repl", None) => repl(&client, &mut events, options.json).await,
+        _ => {
+            for topic in &options.command[1..] {
+                let request = SocketRequest::new(RequestAction::Subscribe, String::new(), topic.clone(), None);
+                execute(&client, request, &mut events, options.json).await?;
+            }
+            while let Some(event) = events.next().await {
+                print(&event_value(event), options.json);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options { url: String::from("http://127.0.0.1:8000"), json: false, command: Vec::new() };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--url" if options.command.is_empty() => {
+                options.url = args.next().ok_or(format!("--url needs a value\n{}", USAGE))?.trim_end_matches('/').to_string();
+            },
+            "--json" if options.command.is_empty() => options.json = true,
+            _ => options.command.push(arg)
+        }
+    }
+    Ok(options)
+}
+
+/// The request of a one-shot command, from its words: the command, the topic, then the value.
+fn command_request(words: &[String]) -> Result<SocketRequest, String> {
+    let topic = words.get(1).cloned().ok_or(format!("{} needs a topic\n{}", words[0], USAGE));
+    let value = || words.get(2..).filter(|rest| !rest.is_empty()).map(|rest| parse_value(&rest.join(" "))).ok_or(format!("{} needs a value\n{}", words[0], USAGE));
+    let (action, message) = match words[0].as_str() {
+        "subscribe" => (RequestAction::Subscribe, None),
+        "unsubscribe" => (RequestAction::Unsubscribe, None),
+        "set" => (RequestAction::Set, Some(value()?)),
+        "unset" => (RequestAction::Unset, None),
+        "get" => (RequestAction::Get, None),
+        "collection" => (RequestAction::GetCollection, None),
+        "add" => (RequestAction::AddToCollection, Some(value()?)),
+        "remove" => (RequestAction::RemoveFromCollection, Some(value()?)),
+        command => return Err(format!("Unknown command {}\n{}", command, USAGE))
+    };
+    Ok(SocketRequest::new(action, String::new(), topic?, message))
+}
+
+/// JSON when it parses, so that `set a 1` stores a number, and a plain string otherwise.
+fn parse_value(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(String::from(text)))
+}
+
+/// Sends `request` and waits for the server to handle it, printing the reply of a read. The events of
+/// a Subscribe join `events`.
+async fn execute(client: &PubSubClient, request: SocketRequest, events: &mut SelectAll<Subscription>, json: bool) -> Result<(), String> {
+    let result = match request.action {
+        RequestAction::Subscribe => client.subscribe(&request.topic).await.map(|subscription| events.push(subscription)),
+        RequestAction::Unsubscribe => client.unsubscribe(&request.topic).await,
+        RequestAction::Get | RequestAction::GetCollection => {
+            let (action, topic) = (request.action, request.topic.clone());
+            client.query(request).await.map(|value| print(&json!(SocketEvent { action, topic, value }), json))
+        },
+        _ => client.publish(request).await
+    };
+    result.map_err(|err| failure(err, json))
+}
+
+/// The message of a failed request. Refusals are only echoed as JSON lines for scripts.
+fn failure(err: ClientError, json: bool) -> String {
+    match err {
+        ClientError::Refused(error) => {
+            if json {
+                print(&json!(error), json);
+            }
+            format!("{:?} on {}: {}", error.error, error.topic, error.message)
+        },
+        err => err.to_string()
+    }
+}
+
+async fn repl(client: &PubSubClient, events: &mut SelectAll<Subscription>, json: bool) -> Result<(), String> {
+    let mut lines = BufReader::new(io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = match line.map_err(|err| err.to_string())? {
+                    Some(line) => line,
+                    None => return Ok(())
+                };
+                let words: Vec<String> = line.split_whitespace().map(String::from).collect();
+                match words.first().map(String::as_str) {
+                    None => continue,
+                    Some("quit" | "exit") => return Ok(()),
+                    Some("help") => println!("{}", USAGE),
+                    Some(_) => match command_request(&words) {
+                        Ok(request) => if let Err(message) = execute(client, request, events, json).await {
+                            eprintln!("{}", message);
+                        },
+                        Err(message) => eprintln!("{}", message)
+                    }
+                }
+            },
+            Some(event) = events.next() => print(&event_value(event), json)
+        }
+    }
+}
+
+/// An event as the server sent it: the document itself, or the change or presence event.
+fn event_value(event: Event) -> Value {
+    match event {
+        Event::Document { value, .. } => value,
+        Event::Change(change) => json!(change),
+        Event::Presence(presence) => json!(presence)
+    }
+}
+
+fn print(event: &Value, json: bool) {
+    if json {
+        println!("{}", event);
+    } else {
+        println!("{}", serde_json::to_string_pretty(event).unwrap_or_default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::{command_request, parse_options};
+
+    fn words(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_command_request() {
+        assert_eq!(json!(command_request(&words("set cart {\"items\": 2}")).unwrap()), json!({ "action": "Set", "user_id": "", "topic": "cart", "message": { "items": 2 } }));
+        assert_eq!(json!(command_request(&words("add tags hello world")).unwrap()), json!({ "action": "AddToCollection", "user_id": "", "topic": "tags", "message": "hello world" }));
+        assert_eq!(json!(command_request(&words("get cart")).unwrap()), json!({ "action": "Get", "user_id": "", "topic": "cart" }));
+        assert!(command_request(&words("set cart")).unwrap_err().starts_with("set needs a value"));
+        assert!(command_request(&words("fly cart")).unwrap_err().starts_with("Unknown command fly"));
+
+        let options = parse_options(words("--json --url http://host:9000/ set a --json")).unwrap();
+        assert!(options.json);
+        assert_eq!(options.url, "http://host:9000");
+        assert_eq!(options.command, words("set a --json"));
+    }
+}
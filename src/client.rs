@@ -0,0 +1,601 @@
+//! Typed async client of the socket protocol, for services that talk to the server from Rust.
+//!
+//! A `PubSubClient` hands its requests to a connection task that owns the socket. The task follows
+//! each request with a GetUsage: the socket handles requests in order, so the answer to the GetUsage
+//! settles the request before it, with the read's reply or the error the server sent back. When the
+//! connection drops, the task reconnects to the same socket, so that the server redelivers the events
+//! left unacked, and resubscribes to the topics still listened to. It registers again only once the
+//! server has forgotten the client.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::{SinkExt, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_tungstenite::{connect_async, tungstenite::{self, Message}, MaybeTlsStream, WebSocketStream};
+use warp::hyper;
+use crate::protocol::{FEATURES, PROTOCOL_VERSION};
+use crate::serialize::{EventKind, Hello, PresenceEvent, RegisterResponse, ReliableEvent, RequestAction, SocketError, SocketEvent, SocketRequest};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type Responder = oneshot::Sender<Result<Option<Value>, ClientError>>;
+
+/// Delays between attempts to reconnect, doubling from the first to the last.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientError {
+    /// The server could not be reached, or refused the registration or the Hello.
+    Connect(String),
+    /// The server answered the request with an error.
+    Refused(SocketError),
+    /// The connection dropped before the request was answered, which may or may not have been applied.
+    Disconnected,
+    /// The reply did not have the type asked for.
+    Decode(String)
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Connect(message) => write!(f, "{}", message),
+            ClientError::Refused(error) => write!(f, "{:?} on {:?} refused with {:?}: {}", error.action, error.topic, error.error, error.message),
+            ClientError::Disconnected => write!(f, "The connection dropped before the request was answered"),
+            ClientError::Decode(message) => write!(f, "Unexpected reply: {}", message)
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// What a subscription delivers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The new document of the topic, null once unset. Keyspace events and dead letters come as the
+    /// documents of their topics.
+    Document { topic: String, value: Value },
+    /// A change to the list, hash or sorted set of the topic, such as a PushToList.
+    Change(SocketEvent),
+    /// A subscriber with presence joined or left the topic.
+    Presence(PresenceEvent)
+}
+
+impl Event {
+    /// Decodes a delivery by the kind the server tagged it with, so that documents of any shape stay documents.
+    fn delivered(kind: EventKind, topic: String, value: Value) -> Event {
+        let decoded = match kind {
+            EventKind::Change => serde_json::from_value(value.clone()).map(Event::Change).ok(),
+            EventKind::Presence => serde_json::from_value(value.clone()).map(Event::Presence).ok(),
+            _ => None
+        };
+        decoded.unwrap_or(Event::Document { topic, value })
+    }
+
+    pub fn topic(&self) -> &str {
+        match self {
+            Event::Document { topic, .. } => topic,
+            Event::Change(change) => &change.topic,
+            Event::Presence(presence) => &presence.topic
+        }
+    }
+}
+
+/// The events of a topic, until the subscription is dropped or purged by an administrator.
+pub struct Subscription {
+    topic: String,
+    events: UnboundedReceiverStream<Event>
+}
+
+impl Subscription {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+/// A message from the server, as told apart by its fields.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    Error(SocketError),
+    Delivery(ReliableEvent),
+    Reply(SocketEvent),
+    Other(Value)
+}
+
+/// What the socket handed out by `/register` is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Registration {
+    pub user_id: String,
+    pub socket_url: String
+}
+
+/// Registers with the server at `url`, such as `http://127.0.0.1:8000`.
+pub async fn register(url: &str) -> Result<Registration, ClientError> {
+    let url = url.trim_end_matches('/');
+    let uri = format!("{}/register", url).parse::<hyper::Uri>().map_err(|err| ClientError::Connect(format!("Invalid URL {}: {}", url, err)))?;
+    let response = hyper::Client::new().get(uri).await.map_err(|err| ClientError::Connect(format!("Cannot reach {}: {}", url, err)))?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.map_err(|err| ClientError::Connect(err.to_string()))?;
+    if !status.is_success() {
+        return Err(ClientError::Connect(format!("Registration refused with {}: {}", status, String::from_utf8_lossy(&body))));
+    }
+    let registered: RegisterResponse = serde_json::from_slice(&body).map_err(|err| ClientError::Connect(format!("Invalid registration: {}", err)))?;
+    socket_registration(url, &registered.url)
+}
+
+/// The server names itself by its local address, so the socket path is joined to the URL registered with.
+fn socket_registration(url: &str, registered: &str) -> Result<Registration, ClientError> {
+    let path = registered.find("/ws/").map(|start| &registered[start..]).ok_or(ClientError::Connect(format!("Invalid socket URL {}", registered)))?;
+    Ok(Registration { user_id: String::from(&path["/ws/".len()..]), socket_url: format!("{}{}", url.replacen("http", "ws", 1), path) })
+}
+
+/// Connects to the socket of `registration` and says Hello with every feature, so that failed requests are
+/// reported. The messages received before the Hello reply, such as the events redelivered on a reconnect,
+/// are returned with the socket. None when the server no longer knows the client.
+async fn open(registration: &Registration) -> Result<Option<(Socket, Vec<String>)>, ClientError> {
+    let Registration { user_id, socket_url } = registration;
+    let mut socket = match connect_async(socket_url.as_str()).await {
+        Ok((socket, _)) => socket,
+        Err(tungstenite::Error::Http(response)) if response.status() == tungstenite::http::StatusCode::NOT_FOUND => return Ok(None),
+        Err(err) => return Err(ClientError::Connect(format!("Cannot connect to {}: {}", socket_url, err)))
+    };
+    let hello = Hello { version: PROTOCOL_VERSION, features: FEATURES.to_vec() };
+    let request = SocketRequest::new(RequestAction::Hello, user_id.clone(), String::new(), Some(json!(hello)));
+    send(&mut socket, &request).await.map_err(|_| ClientError::Connect(String::from("The server closed the connection")))?;
+    let mut early = Vec::new();
+    while let Some(message) = socket.next().await {
+        let text = match message {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Binary(bytes)) => String::from_utf8_lossy(&bytes).into_owned(),
+            Ok(_) => continue,
+            Err(err) => return Err(ClientError::Connect(format!("Connection lost: {}", err)))
+        };
+        match serde_json::from_str(&text) {
+            Ok(Incoming::Error(error)) if error.action == RequestAction::Hello => return Err(ClientError::Connect(format!("Hello refused: {}", error.message))),
+            Ok(Incoming::Reply(reply)) if reply.action == RequestAction::Hello => return Ok(Some((socket, early))),
+            _ => early.push(text)
+        }
+    }
+    Err(ClientError::Connect(String::from("The server closed the connection")))
+}
+
+/// Registers and opens the new registration's socket.
+async fn open_registered(url: &str) -> Result<(Socket, Vec<String>, Registration), ClientError> {
+    let registration = register(url).await?;
+    match open(&registration).await? {
+        Some((socket, early)) => Ok((socket, early, registration)),
+        None => Err(ClientError::Connect(format!("Client {} was unregistered before it connected", registration.user_id)))
+    }
+}
+
+async fn send(socket: &mut Socket, request: &SocketRequest) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    socket.send(Message::text(json!(request).to_string())).await
+}
+
+enum ClientCommand {
+    Request { request: Box<SocketRequest>, responder: Responder },
+    Subscribe { topic: String, events: mpsc::UnboundedSender<Event>, responder: Responder },
+    Unsubscribe { topic: String, responder: Responder }
+}
+
+/// A request awaiting the answer to the GetUsage sent after it. Requests sent by the connection task
+/// itself, such as resubscriptions, have no responder.
+struct Pending {
+    action: RequestAction,
+    topic: String,
+    reply: Option<Value>,
+    error: Option<SocketError>,
+    responder: Option<Responder>
+}
+
+/// Handle on a connection task. Clones share the connection, which closes once they are all dropped.
+#[derive(Clone)]
+pub struct PubSubClient {
+    commands: mpsc::UnboundedSender<ClientCommand>
+}
+
+impl PubSubClient {
+    /// Connects to the server at `url`, such as `http://127.0.0.1:8000`. Only this first connection
+    /// fails fast: later ones are retried until they succeed.
+    pub async fn connect(url: &str) -> Result<PubSubClient, ClientError> {
+        let url = String::from(url.trim_end_matches('/'));
+        let (socket, _, Registration { user_id, socket_url }) = open_registered(&url).await?;
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let connection = Connection { url, socket_url, socket, user_id, pending: VecDeque::new(), subscriptions: HashMap::new() };
+        tokio::spawn(connection.run(commands_rx));
+        Ok(PubSubClient { commands })
+    }
+
+    /// Subscribes to `topic`. Events are delivered reliably and acked once handed to the subscription,
+    /// so that events lost with a connection are redelivered on reconnecting, unless the server
+    /// forgot the client in the meantime.
+    pub async fn subscribe(&self, topic: &str) -> Result<Subscription, ClientError> {
+        let (events, events_rx) = mpsc::unbounded_channel();
+        self.call(|responder| ClientCommand::Subscribe { topic: String::from(topic), events, responder }).await?;
+        Ok(Subscription { topic: String::from(topic), events: UnboundedReceiverStream::new(events_rx) })
+    }
+
+    /// Ends every subscription to `topic`.
+    pub async fn unsubscribe(&self, topic: &str) -> Result<(), ClientError> {
+        self.call(|responder| ClientCommand::Unsubscribe { topic: String::from(topic), responder }).await.map(|_| ())
+    }
+
+    /// Sends a request changing the store, such as a PushToList. Its `user_id` is filled in.
+    pub async fn publish(&self, request: SocketRequest) -> Result<(), ClientError> {
+        self.call(|responder| ClientCommand::Request { request: Box::new(request), responder }).await.map(|_| ())
+    }
+
+    /// Sends a read request, such as a GetListRange, and returns the `value` of its reply.
+    pub async fn query(&self, request: SocketRequest) -> Result<Value, ClientError> {
+        self.call(|responder| ClientCommand::Request { request: Box::new(request), responder }).await.map(Option::unwrap_or_default)
+    }
+
+    pub async fn set<T: Serialize>(&self, topic: &str, value: &T) -> Result<(), ClientError> {
+        let value = serde_json::to_value(value).map_err(|err| ClientError::Decode(err.to_string()))?;
+        self.publish(SocketRequest::new(RequestAction::Set, String::new(), String::from(topic), Some(value))).await
+    }
+
+    pub async fn unset(&self, topic: &str) -> Result<(), ClientError> {
+        self.publish(SocketRequest::new(RequestAction::Unset, String::new(), String::from(topic), None)).await
+    }
+
+    /// The document of `topic`, or None when unset.
+    pub async fn get<T: DeserializeOwned>(&self, topic: &str) -> Result<Option<T>, ClientError> {
+        let value = self.query(SocketRequest::new(RequestAction::Get, String::new(), String::from(topic), None)).await?;
+        serde_json::from_value(value).map_err(|err| ClientError::Decode(err.to_string()))
+    }
+
+    async fn call(&self, command: impl FnOnce(Responder) -> ClientCommand) -> Result<Option<Value>, ClientError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.commands.send(command(resp_tx)).map_err(|_| ClientError::Disconnected)?;
+        resp_rx.await.map_err(|_| ClientError::Disconnected)?
+    }
+}
+
+/// The connection task's state: the socket, the requests it awaits answers to, and where each
+/// subscribed topic's events go.
+struct Connection {
+    url: String,
+    socket_url: String,
+    socket: Socket,
+    user_id: String,
+    pending: VecDeque<Pending>,
+    subscriptions: HashMap<String, Vec<mpsc::UnboundedSender<Event>>>
+}
+
+impl Connection {
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<ClientCommand>) {
+        loop {
+            let connected = tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => self.command(command).await,
+                    None => break
+                },
+                message = self.socket.next() => match message {
+                    Some(Ok(Message::Text(text))) => self.receive(&text).await,
+                    Some(Ok(Message::Binary(bytes))) => self.receive(&String::from_utf8_lossy(&bytes)).await,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => false,
+                    Some(Ok(_)) => true
+                }
+            };
+            if !connected && !self.reconnect(&commands).await {
+                break;
+            }
+        }
+        if self.socket.close(None).await.is_err() {
+            debug!("Connection of client {} already closed", self.user_id);
+        }
+    }
+
+    /// Returns false when the connection dropped.
+    async fn command(&mut self, command: ClientCommand) -> bool {
+        match command {
+            ClientCommand::Request { request, responder } => self.request(*request, Some(responder)).await,
+            ClientCommand::Subscribe { topic, events, responder } => {
+                if let Some(senders) = self.subscriptions.get_mut(&topic) {
+                    senders.push(events);
+                    let _ = responder.send(Ok(None));
+                    return true;
+                }
+                self.subscriptions.insert(topic.clone(), vec![events]);
+                self.request(subscribe_request(topic), Some(responder)).await
+            },
+            ClientCommand::Unsubscribe { topic, responder } => {
+                self.subscriptions.remove(&topic);
+                self.request(SocketRequest::new(RequestAction::Unsubscribe, String::new(), topic, None), Some(responder)).await
+            }
+        }
+    }
+
+    /// Sends `request` followed by the GetUsage which settles it.
+    async fn request(&mut self, mut request: SocketRequest, responder: Option<Responder>) -> bool {
+        request.user_id = self.user_id.clone();
+        self.pending.push_back(Pending { action: request.action, topic: request.topic.clone(), reply: None, error: None, responder });
+        let barrier = SocketRequest::new(RequestAction::GetUsage, self.user_id.clone(), String::new(), None);
+        send(&mut self.socket, &request).await.is_ok() && send(&mut self.socket, &barrier).await.is_ok()
+    }
+
+    async fn receive(&mut self, text: &str) -> bool {
+        let incoming = match serde_json::from_str(text) {
+            Ok(incoming) => incoming,
+            Err(_) => Incoming::Other(Value::String(String::from(text)))
+        };
+        match incoming {
+            Incoming::Error(error) if error.action == RequestAction::Ack => {
+                warn!("Ack of client {} refused: {}", self.user_id, error.message);
+                true
+            },
+            Incoming::Error(error) if error.action == RequestAction::GetUsage => {
+                // The GetUsage itself failed, as when throttled, so no answer will settle the request.
+                self.settle(Some(error));
+                true
+            },
+            Incoming::Error(error) => {
+                match self.pending.front_mut() {
+                    Some(pending) => {
+                        pending.error.get_or_insert(error);
+                    },
+                    None => warn!("Error with no request pending: {:?}", error)
+                }
+                true
+            },
+            Incoming::Delivery(ReliableEvent { id, topic, kind, value }) => {
+                let ack = SocketRequest::new(RequestAction::Ack, self.user_id.clone(), topic.clone(), Some(Value::String(id)));
+                let connected = send(&mut self.socket, &ack).await.is_ok();
+                self.dispatch(Event::delivered(kind, topic.clone(), value));
+                if self.subscriptions.contains_key(&topic) || !connected {
+                    return connected;
+                }
+                // Every subscription to the topic was dropped since the last event.
+                self.request(SocketRequest::new(RequestAction::Unsubscribe, String::new(), topic, None), None).await
+            },
+            Incoming::Reply(reply) if reply.action == RequestAction::GetUsage => {
+                self.settle(None);
+                true
+            },
+            Incoming::Reply(reply) => {
+                match self.pending.front_mut() {
+                    Some(pending) if pending.action == reply.action && pending.topic == reply.topic => pending.reply = Some(reply.value),
+                    // An administrator purged the topic, which ends its subscriptions.
+                    _ if reply.action == RequestAction::Unsubscribe => {
+                        let topic = reply.topic.clone();
+                        self.dispatch(Event::Change(reply));
+                        self.subscriptions.remove(&topic);
+                    },
+                    _ => debug!("Ignoring a reply with no request pending: {:?}", reply)
+                }
+                true
+            },
+            Incoming::Other(value) => {
+                debug!("Ignoring message {}", value);
+                true
+            }
+        }
+    }
+
+    /// Answers the oldest pending request, failing it with `error` or the error it received.
+    fn settle(&mut self, error: Option<SocketError>) {
+        let pending = match self.pending.pop_front() {
+            Some(pending) => pending,
+            None => return
+        };
+        let result = match error.or(pending.error) {
+            Some(error) => {
+                if pending.action == RequestAction::Subscribe && pending.responder.is_some() {
+                    self.subscriptions.remove(&pending.topic);
+                }
+                Err(ClientError::Refused(error))
+            },
+            None => Ok(pending.reply)
+        };
+        match pending.responder {
+            Some(responder) => {
+                let _ = responder.send(result);
+            },
+            None => if let Err(err) = result {
+                warn!("Request of client {} failed: {}", self.user_id, err);
+            }
+        }
+    }
+
+    /// Hands `event` to the subscriptions of its topic, forgetting those dropped and the topic with the last of them.
+    fn dispatch(&mut self, event: Event) {
+        let topic = String::from(event.topic());
+        if let Some(senders) = self.subscriptions.get_mut(&topic) {
+            senders.retain(|sender| sender.send(event.clone()).is_ok());
+            if senders.is_empty() {
+                self.subscriptions.remove(&topic);
+            }
+        }
+    }
+
+    /// Fails the requests in flight, then reconnects until it succeeds, acks the events redelivered
+    /// and resubscribes. Returns false when every handle was dropped meanwhile.
+    async fn reconnect(&mut self, commands: &mpsc::UnboundedReceiver<ClientCommand>) -> bool {
+        warn!("Client {} lost its connection, reconnecting", self.user_id);
+        for pending in self.pending.drain(..) {
+            if let Some(responder) = pending.responder {
+                let _ = responder.send(Err(ClientError::Disconnected));
+            }
+        }
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            if commands.is_closed() {
+                return false;
+            }
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            let early = match self.reopen().await {
+                Ok(early) => early,
+                Err(err) => {
+                    debug!("Reconnection to {} failed: {}", self.url, err);
+                    continue;
+                }
+            };
+            self.subscriptions.retain(|_, senders| {
+                senders.retain(|sender| !sender.is_closed());
+                !senders.is_empty()
+            });
+            let mut connected = true;
+            for text in early {
+                connected = connected && self.receive(&text).await;
+            }
+            let topics: Vec<String> = self.subscriptions.keys().cloned().collect();
+            for topic in topics {
+                connected = connected && self.request(subscribe_request(topic), None).await;
+            }
+            if connected {
+                return true;
+            }
+        }
+    }
+
+    /// Opens the client's socket again, or that of a new registration once the server has forgotten the
+    /// client, as it does when no event was left unacked. Returns the messages received before the Hello reply.
+    async fn reopen(&mut self) -> Result<Vec<String>, ClientError> {
+        let registration = Registration { user_id: self.user_id.clone(), socket_url: self.socket_url.clone() };
+        if let Some((socket, early)) = open(&registration).await? {
+            info!("Client {} reconnected", self.user_id);
+            self.socket = socket;
+            return Ok(early);
+        }
+        let (socket, early, Registration { user_id, socket_url }) = open_registered(&self.url).await?;
+        info!("Client {} was forgotten by the server, reconnected as {}", self.user_id, user_id);
+        self.socket = socket;
+        self.user_id = user_id;
+        self.socket_url = socket_url;
+        Ok(early)
+    }
+}
+
+fn subscribe_request(topic: String) -> SocketRequest {
+    let mut request = SocketRequest::new(RequestAction::Subscribe, String::new(), topic, None);
+    request.reliable = Some(true);
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+    use crate::serialize::{EventKind, PresenceAction, PresenceEvent, ReliableEvent, RequestAction, SocketEvent, SocketRequest};
+    use super::{socket_registration, Event, PubSubClient, Registration};
+
+    type ServerSocket = WebSocketStream<TcpStream>;
+
+    /// Answers the next connection as `/register` would, handing out the socket of `user_id`.
+    async fn serve_registration(listener: &TcpListener, user_id: &str) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let body = json!({ "url": format!("ws://127.0.0.1:8000/ws/{}", user_id) }).to_string();
+        let response = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", body.len(), body);
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    /// Accepts the next socket, with the path it was opened on.
+    async fn accept(listener: &TcpListener) -> (ServerSocket, String) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut line = [0; 64];
+        let read = stream.peek(&mut line).await.unwrap();
+        let path = String::from_utf8_lossy(&line[..read]).split(' ').nth(1).map(String::from).unwrap_or_default();
+        (accept_async(stream).await.unwrap(), path)
+    }
+
+    async fn next_request(socket: &mut ServerSocket) -> SocketRequest {
+        loop {
+            if let Message::Text(text) = socket.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    async fn reply(socket: &mut ServerSocket, message: Value) {
+        socket.send(Message::text(message.to_string())).await.unwrap();
+    }
+
+    /// Answers the next request, which must be `action`, and the GetUsage following it unless it is a Hello.
+    async fn answer(socket: &mut ServerSocket, action: RequestAction) -> SocketRequest {
+        let request = next_request(socket).await;
+        assert_eq!(request.action, action);
+        reply(socket, json!(SocketEvent { action, topic: request.topic.clone(), value: Value::Null })).await;
+        if action != RequestAction::Hello {
+            assert_eq!(next_request(socket).await.action, RequestAction::GetUsage);
+            reply(socket, json!(SocketEvent { action: RequestAction::GetUsage, topic: String::new(), value: Value::Null })).await;
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_redelivers_unacked_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let event = json!(ReliableEvent { id: String::from("e1"), topic: String::from("jobs"), kind: EventKind::Document, value: json!({ "n": 1 }) });
+        let server = tokio::spawn(async move {
+            serve_registration(&listener, "u1").await;
+            let (mut socket, path) = accept(&listener).await;
+            assert_eq!(path, "/ws/u1");
+            answer(&mut socket, RequestAction::Hello).await;
+            assert_eq!(answer(&mut socket, RequestAction::Subscribe).await.reliable, Some(true));
+            // The socket drops before the server reads the Ack of the event.
+            reply(&mut socket, event.clone()).await;
+            drop(socket);
+
+            let (mut socket, path) = accept(&listener).await;
+            assert_eq!(path, "/ws/u1");
+            // The server redelivers the unacked events as soon as the socket opens, before the Hello.
+            reply(&mut socket, event).await;
+            answer(&mut socket, RequestAction::Hello).await;
+            let ack = next_request(&mut socket).await;
+            assert_eq!((ack.action, ack.user_id, ack.message), (RequestAction::Ack, String::from("u1"), Some(json!("e1"))));
+            assert_eq!(answer(&mut socket, RequestAction::Subscribe).await.topic, "jobs");
+        });
+
+        let client = PubSubClient::connect(&url).await.unwrap();
+        let mut subscription = client.subscribe("jobs").await.unwrap();
+        let delivered = Event::Document { topic: String::from("jobs"), value: json!({ "n": 1 }) };
+        assert_eq!(subscription.next().await, Some(delivered.clone()));
+        assert_eq!(subscription.next().await, Some(delivered));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_protocol_types_round_trip() {
+        let mut request = SocketRequest::new(RequestAction::Subscribe, String::from("u1"), String::from("cart"), None);
+        request.reliable = Some(true);
+        assert_eq!(json!(request), json!({ "action": "Subscribe", "user_id": "u1", "topic": "cart", "reliable": true }));
+
+        let registration = socket_registration("http://host:9000", "ws://127.0.0.1:8000/ws/u1").unwrap();
+        assert_eq!(registration, Registration { user_id: String::from("u1"), socket_url: String::from("ws://host:9000/ws/u1") });
+
+        let change = json!({ "action": "PushToList", "topic": "jobs", "value": 3 });
+        assert_eq!(Event::delivered(EventKind::Change, String::from("jobs"), change.clone()), Event::Change(SocketEvent { action: RequestAction::PushToList, topic: String::from("jobs"), value: json!(3) }));
+        let presence = json!({ "presence": "Join", "topic": "room", "user_id": "u2", "meta": null });
+        assert_eq!(Event::delivered(EventKind::Presence, String::from("room"), presence.clone()), Event::Presence(PresenceEvent { presence: PresenceAction::Join, topic: String::from("room"), user_id: String::from("u2"), meta: json!(null) }));
+        assert_eq!(Event::delivered(EventKind::Document, String::from("cart"), json!({ "items": 2 })), Event::Document { topic: String::from("cart"), value: json!({ "items": 2 }) });
+        // Documents shaped like change or presence events are still documents.
+        assert_eq!(Event::delivered(EventKind::Document, String::from("jobs"), change.clone()), Event::Document { topic: String::from("jobs"), value: change });
+        assert_eq!(Event::delivered(EventKind::Document, String::from("room"), presence.clone()), Event::Document { topic: String::from("room"), value: presence });
+    }
+}
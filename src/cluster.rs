@@ -0,0 +1,227 @@
+use std::{collections::{HashMap, HashSet}, time::{Duration, Instant}};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use tokio::{sync::{mpsc::{Receiver, Sender}, oneshot::{self, error::RecvError}}, time};
+use warp::hyper::{self, Body, Request};
+use crate::{serialize::EventKind, store::{Responder, Subscribers}, subscriptions::SubscriptionsTx};
+
+/// Commands of the cluster actor, which shares this node's subscribed topics with its peers and
+/// forwards publishes to the peers with subscribers of their topic.
+#[derive(Debug)]
+pub enum ClusterCommand {
+    /// Sends this node's subscribed topics to every peer now rather than at the next sync.
+    Announce,
+    /// Replaces the topics a peer has subscribers for.
+    SyncPeer {
+        topics: PeerTopics,
+    },
+    /// Sends an event to every peer with subscribers of its topic, replying with how many there were.
+    Forward {
+        event: ForwardedEvent,
+        responder: Responder<usize>,
+    },
+    GetPeers {
+        responder: Responder<Vec<PeerTopics>>,
+    }
+}
+
+/// Sent by a node to its peers, listing the topics it has subscribers for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerTopics {
+    pub node: String,
+    pub topics: Vec<String>
+}
+
+/// A publish made on another node, delivered to this node's subscribers as if it had been made here.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ForwardedEvent {
+    pub topic: String,
+    /// What `value` holds. `patch` is always a Patch.
+    #[serde(default)]
+    pub kind: EventKind,
+    pub value: String,
+    pub patch: Option<String>,
+    pub user_id: String
+}
+
+pub struct Cluster;
+impl Cluster {
+    pub async fn announce(cluster_tx: Sender<ClusterCommand>) {
+        send(ClusterCommand::Announce, cluster_tx).await;
+    }
+
+    pub async fn sync_peer(topics: PeerTopics, cluster_tx: Sender<ClusterCommand>) {
+        send(ClusterCommand::SyncPeer { topics }, cluster_tx).await;
+    }
+
+    pub async fn forward(event: ForwardedEvent, cluster_tx: Sender<ClusterCommand>) -> Result<usize, RecvError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        send(ClusterCommand::Forward { event, responder: resp_tx }, cluster_tx).await;
+        resp_rx.await
+    }
+
+    pub async fn get_peers(cluster_tx: Sender<ClusterCommand>) -> Result<Vec<PeerTopics>, RecvError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        send(ClusterCommand::GetPeers { responder: resp_tx }, cluster_tx).await;
+        resp_rx.await
+    }
+}
+
+async fn send(command: ClusterCommand, cluster_tx: Sender<ClusterCommand>) {
+    match cluster_tx.send(command).await {
+        Ok(result) => debug!("#cluster success: {:?}", result),
+        Err(err) => error!("#cluster error: {}", err)
+    }
+}
+
+struct Peer {
+    topics: HashSet<String>,
+    seen_at: Instant
+}
+
+/// What this node knows of the cluster: its own URL, the URLs of its peers and the topics of
+/// the peers that synced recently.
+pub struct ClusterState {
+    node: String,
+    peers: Vec<String>,
+    secret: Option<String>,
+    remote: HashMap<String, Peer>,
+    http: hyper::Client<hyper::client::HttpConnector>
+}
+
+impl ClusterState {
+    pub fn new(node: String, peers: Vec<String>, secret: Option<String>) -> ClusterState {
+        ClusterState { node, peers, secret, remote: HashMap::new(), http: hyper::Client::new() }
+    }
+
+    async fn handle(&mut self, cmd: ClusterCommand, subscriptions_tx: &SubscriptionsTx) {
+        match cmd {
+            ClusterCommand::Announce => self.announce(subscriptions_tx).await,
+            ClusterCommand::SyncPeer { topics } if !self.peers.contains(&topics.node) => {
+                warn!("Ignoring the topics of {}, which is not a configured peer", topics.node);
+            },
+            ClusterCommand::SyncPeer { topics } => {
+                info!("Peer {} has subscribers for {} topics", topics.node, topics.topics.len());
+                self.remote.insert(topics.node, Peer { topics: topics.topics.into_iter().collect(), seen_at: Instant::now() });
+            },
+            ClusterCommand::Forward { event, responder } => {
+                let nodes = self.nodes_for(&event.topic);
+                for node in &nodes {
+                    self.post(format!("{}/cluster/publish", node), json!(event));
+                }
+                let _ = responder.send(nodes.len());
+            },
+            ClusterCommand::GetPeers { responder } => {
+                let mut peers: Vec<PeerTopics> = self.remote.iter().map(|(node, peer)| {
+                    let mut topics: Vec<String> = peer.topics.iter().cloned().collect();
+                    topics.sort();
+                    PeerTopics { node: node.clone(), topics }
+                }).collect();
+                peers.sort_by(|a, b| a.node.cmp(&b.node));
+                let _ = responder.send(peers);
+            }
+        }
+    }
+
+    fn nodes_for(&self, topic: &str) -> Vec<String> {
+        self.remote.iter().filter(|(_, peer)| peer.topics.contains(topic)).map(|(node, _)| node.clone()).collect()
+    }
+
+    async fn announce(&self, subscriptions_tx: &SubscriptionsTx) {
+        if self.peers.is_empty() {
+            return;
+        }
+        let topics = match Subscribers::get_topics(subscriptions_tx.clone()).await {
+            Ok(topics) => topics,
+            Err(_) => {
+                error!("Error getting the topics to announce to the cluster");
+                return;
+            }
+        };
+        let topics = json!(PeerTopics { node: self.node.clone(), topics });
+        for peer in &self.peers {
+            self.post(format!("{}/cluster/topics", peer), topics.clone());
+        }
+    }
+
+    /// Drops the peers that have not synced for `timeout`, most likely because they are down.
+    fn expire(&mut self, timeout: Duration) {
+        self.remote.retain(|node, peer| {
+            let alive = peer.seen_at.elapsed() < timeout;
+            if !alive {
+                warn!("Peer {} stopped syncing, forgetting its topics", node);
+            }
+            alive
+        });
+    }
+
+    fn post(&self, url: String, body: Value) {
+        let http = self.http.clone();
+        let mut request = Request::post(&url).header("content-type", "application/json");
+        if let Some(secret) = &self.secret {
+            request = request.header("authorization", format!("Bearer {}", secret));
+        }
+        tokio::spawn(async move {
+            let request = match request.body(Body::from(body.to_string())) {
+                Ok(request) => request,
+                Err(err) => {
+                    error!("Invalid cluster request to {}: {}", url, err);
+                    return;
+                }
+            };
+            match http.request(request).await {
+                Ok(response) if response.status().is_success() => debug!("Cluster request to {} succeeded", url),
+                Ok(response) => warn!("Cluster request to {} failed with {}", url, response.status()),
+                Err(err) => debug!("Cluster request to {} failed: {}", url, err)
+            }
+        });
+    }
+}
+
+pub async fn run(mut cluster_rx: Receiver<ClusterCommand>, mut state: ClusterState, subscriptions_tx: SubscriptionsTx, interval: Duration) {
+    let mut sync = time::interval(interval);
+    loop {
+        tokio::select! {
+            cmd = cluster_rx.recv() => match cmd {
+                Some(cmd) => state.handle(cmd, &subscriptions_tx).await,
+                None => break
+            },
+            _ = sync.tick() => {
+                state.expire(interval * 3);
+                state.announce(&subscriptions_tx).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+    use crate::subscriptions;
+    use super::{ClusterCommand, ClusterState, PeerTopics};
+
+    #[tokio::test]
+    async fn test_sync_and_expire_peers() {
+        let subscriptions_tx = subscriptions::spawn();
+        let mut state = ClusterState::new(String::from("http://127.0.0.1:8000"), vec![String::from("http://127.0.0.1:8001")], None);
+        let topics = PeerTopics { node: String::from("http://127.0.0.1:8001"), topics: vec![String::from("b"), String::from("a")] };
+        state.handle(ClusterCommand::SyncPeer { topics }, &subscriptions_tx).await;
+
+        assert_eq!(state.nodes_for("a"), vec![String::from("http://127.0.0.1:8001")]);
+        assert!(state.nodes_for("c").is_empty());
+
+        let forged = PeerTopics { node: String::from("http://internal.example"), topics: vec![String::from("a")] };
+        state.handle(ClusterCommand::SyncPeer { topics: forged }, &subscriptions_tx).await;
+        assert_eq!(state.nodes_for("a"), vec![String::from("http://127.0.0.1:8001")]);
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        state.handle(ClusterCommand::GetPeers { responder: resp_tx }, &subscriptions_tx).await;
+        assert_eq!(resp_rx.await.unwrap(), vec![PeerTopics { node: String::from("http://127.0.0.1:8001"), topics: vec![String::from("a"), String::from("b")] }]);
+
+        state.expire(Duration::from_secs(60));
+        assert_eq!(state.nodes_for("a").len(), 1);
+        state.expire(Duration::ZERO);
+        assert!(state.nodes_for("a").is_empty());
+    }
+}
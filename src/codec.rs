@@ -0,0 +1,260 @@
+use std::{collections::HashMap, io::Write};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{write::DeflateEncoder, Compression};
+use serde_json::{Map, Number, Value};
+use warp::ws::Message;
+use crate::config::config;
+use crate::serialize::SocketRequest;
+
+/// WebSocket subprotocol of clients exchanging JSON text frames, the default.
+pub const JSON_SUBPROTOCOL: &str = "pubsub.json";
+/// WebSocket subprotocol of clients exchanging MessagePack binary frames.
+pub const MSGPACK_SUBPROTOCOL: &str = "pubsub.msgpack";
+/// Suffix of the subprotocols under which the server deflates the frames it sends once they reach
+/// the compression threshold. Every binary frame sent under them starts with a flag byte, `DEFLATED`
+/// or `PLAIN`, followed by the payload; text frames are never compressed. Unlike permessage-deflate,
+/// which compresses on each socket, a publish is deflated once for all the subscribers using these.
+pub const DEFLATE_SUFFIX: &str = "+deflate";
+pub const PLAIN: u8 = 0;
+pub const DEFLATED: u8 = 1;
+/// Values are stored as JSON, so MessagePack binaries are kept as `{"$binary": "<base64>"}` and turned
+/// back into binaries for MessagePack clients. JSON clients see the object as is.
+const BINARY_KEY: &str = "$binary";
+
+/// Serialization of the frames sent to a client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Codec {
+    #[default]
+    Json,
+    MessagePack
+}
+
+impl Codec {
+    /// Turns an outbound JSON event into a frame of this codec.
+    pub fn encode(self, text: String) -> Message {
+        match self {
+            Codec::Json => Message::text(text),
+            Codec::MessagePack => match serde_json::from_str(&text) {
+                Ok(value) => {
+                    let mut bytes = Vec::new();
+                    match rmpv::encode::write_value(&mut bytes, &to_msgpack(value)) {
+                        Ok(_) => Message::binary(bytes),
+                        Err(err) => {
+                            error!("Error encoding an event as MessagePack: {}", err);
+                            Message::text(text)
+                        }
+                    }
+                },
+                Err(_) => Message::text(text)
+            }
+        }
+    }
+}
+
+/// How the frames sent to a client are encoded, negotiated when its socket is opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Encoding {
+    pub codec: Codec,
+    pub deflate: bool
+}
+
+impl Encoding {
+    /// The encoding of the first subprotocol this server speaks among those offered in `Sec-WebSocket-Protocol`.
+    pub fn negotiate(offered: Option<&str>) -> Option<Encoding> {
+        offered?.split(',').map(str::trim).find_map(|protocol| {
+            let (protocol, deflate) = match protocol.strip_suffix(DEFLATE_SUFFIX) {
+                Some(protocol) => (protocol, true),
+                None => (protocol, false)
+            };
+            let codec = match protocol {
+                JSON_SUBPROTOCOL => Codec::Json,
+                MSGPACK_SUBPROTOCOL => Codec::MessagePack,
+                _ => return None
+            };
+            Some(Encoding { codec, deflate })
+        })
+    }
+
+    pub fn subprotocol(self) -> &'static str {
+        match (self.codec, self.deflate) {
+            (Codec::Json, false) => JSON_SUBPROTOCOL,
+            (Codec::Json, true) => "pubsub.json+deflate",
+            (Codec::MessagePack, false) => MSGPACK_SUBPROTOCOL,
+            (Codec::MessagePack, true) => "pubsub.msgpack+deflate"
+        }
+    }
+
+    /// Turns an outbound JSON event into a frame of this encoding, deflated if it is large enough.
+    pub fn encode(self, text: String) -> Message {
+        let message = self.codec.encode(text);
+        if !self.deflate {
+            return message;
+        }
+        let payload = message.as_bytes();
+        if payload.len() < config().compression_threshold {
+            return if message.is_binary() { Message::binary([&[PLAIN], payload].concat()) } else { message };
+        }
+        let mut encoder = DeflateEncoder::new(vec![DEFLATED], Compression::fast());
+        match encoder.write_all(payload).and_then(|_| encoder.finish()) {
+            Ok(compressed) => Message::binary(compressed),
+            Err(err) => {
+                error!("Error deflating an event: {}", err);
+                message
+            }
+        }
+    }
+}
+
+/// An outbound event, encoded at most once per encoding so that a publish serializes and compresses
+/// its payload once however many subscribers share an encoding.
+pub struct Frames<'a> {
+    text: &'a str,
+    encoded: HashMap<Encoding, Message>
+}
+
+impl<'a> Frames<'a> {
+    pub fn new(text: &'a str) -> Frames<'a> {
+        Frames { text, encoded: HashMap::new() }
+    }
+
+    pub fn text(&self) -> &'a str {
+        self.text
+    }
+
+    pub fn get(&mut self, encoding: Encoding) -> Message {
+        let text = self.text;
+        self.encoded.entry(encoding).or_insert_with(|| encoding.encode(String::from(text))).clone()
+    }
+}
+
+/// Parses a request from a text frame as JSON, or from a binary frame as MessagePack, whichever
+/// codec the socket negotiated.
+pub fn decode_request(message: &Message) -> Result<SocketRequest, String> {
+    if message.is_text() {
+        let text = message.to_str().map_err(|_| String::from("invalid text frame"))?;
+        return serde_json::from_str(text).map_err(|err| err.to_string());
+    }
+    if message.is_binary() {
+        let value = rmpv::decode::read_value(&mut message.as_bytes()).map_err(|err| err.to_string())?;
+        return serde_json::from_value(to_json(value)).map_err(|err| err.to_string());
+    }
+    Err(String::from("not a data frame"))
+}
+
+fn to_json(value: rmpv::Value) -> Value {
+    match value {
+        rmpv::Value::Nil => Value::Null,
+        rmpv::Value::Boolean(boolean) => Value::Bool(boolean),
+        rmpv::Value::Integer(integer) => match (integer.as_u64(), integer.as_i64()) {
+            (Some(unsigned), _) => Value::from(unsigned),
+            (_, Some(signed)) => Value::from(signed),
+            _ => Value::Null
+        },
+        rmpv::Value::F32(float) => Number::from_f64(float as f64).map_or(Value::Null, Value::Number),
+        rmpv::Value::F64(float) => Number::from_f64(float).map_or(Value::Null, Value::Number),
+        rmpv::Value::String(string) => match string.into_str() {
+            Some(string) => Value::String(string),
+            None => Value::Null
+        },
+        rmpv::Value::Binary(bytes) | rmpv::Value::Ext(_, bytes) => {
+            let mut binary = Map::new();
+            binary.insert(String::from(BINARY_KEY), Value::String(STANDARD.encode(bytes)));
+            Value::Object(binary)
+        },
+        rmpv::Value::Array(values) => Value::Array(values.into_iter().map(to_json).collect()),
+        rmpv::Value::Map(entries) => Value::Object(entries.into_iter().map(|(key, value)| {
+            let key = match key {
+                rmpv::Value::String(string) => string.into_str().unwrap_or_default(),
+                key => key.to_string()
+            };
+            (key, to_json(value))
+        }).collect())
+    }
+}
+
+fn to_msgpack(value: Value) -> rmpv::Value {
+    match value {
+        Value::Null => rmpv::Value::Nil,
+        Value::Bool(boolean) => rmpv::Value::Boolean(boolean),
+        Value::Number(number) => match (number.as_u64(), number.as_i64()) {
+            (Some(unsigned), _) => rmpv::Value::from(unsigned),
+            (_, Some(signed)) => rmpv::Value::from(signed),
+            _ => rmpv::Value::F64(number.as_f64().unwrap_or_default())
+        },
+        Value::String(string) => rmpv::Value::String(string.into()),
+        Value::Array(values) => rmpv::Value::Array(values.into_iter().map(to_msgpack).collect()),
+        Value::Object(object) => {
+            if let Some(bytes) = binary(&object) {
+                return rmpv::Value::Binary(bytes);
+            }
+            rmpv::Value::Map(object.into_iter().map(|(key, value)| (rmpv::Value::String(key.into()), to_msgpack(value))).collect())
+        }
+    }
+}
+
+fn binary(object: &Map<String, Value>) -> Option<Vec<u8>> {
+    if object.len() != 1 {
+        return None;
+    }
+    STANDARD.decode(object.get(BINARY_KEY)?.as_str()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use warp::ws::Message;
+    use crate::serialize::RequestAction;
+    use super::{decode_request, to_json, Codec, Encoding, Frames, DEFLATED, PLAIN};
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::negotiate(None), None);
+        assert_eq!(Encoding::negotiate(Some("chat, pubsub.msgpack")), Some(Encoding { codec: Codec::MessagePack, deflate: false }));
+        assert_eq!(Encoding::negotiate(Some("pubsub.json+deflate,pubsub.msgpack")), Some(Encoding { codec: Codec::Json, deflate: true }));
+        assert_eq!(Encoding::negotiate(Some("chat")), None);
+        assert_eq!(Encoding { codec: Codec::MessagePack, deflate: true }.subprotocol(), "pubsub.msgpack+deflate");
+    }
+
+    #[test]
+    fn test_deflate_above_threshold() {
+        let json = Encoding { codec: Codec::Json, deflate: true };
+        assert!(json.encode(String::from(r#"{"a":1}"#)).is_text());
+        let msgpack = Encoding { codec: Codec::MessagePack, deflate: true };
+        assert_eq!(msgpack.encode(String::from("1")).as_bytes(), &[PLAIN, 1]);
+
+        let large = json!({ "items": vec!["repeated"; 1000] }).to_string();
+        let mut frames = Frames::new(&large);
+        let frame = frames.get(json);
+        assert!(frame.is_binary());
+        assert_eq!(frame.as_bytes()[0], DEFLATED);
+        assert!(frame.as_bytes().len() < large.len() / 10);
+        let mut inflated = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::DeflateDecoder::new(&frame.as_bytes()[1..]), &mut inflated).unwrap();
+        assert_eq!(inflated, large);
+        assert_eq!(frames.get(json).as_bytes(), frame.as_bytes());
+        assert!(frames.get(Encoding::default()).is_text());
+    }
+
+    #[test]
+    fn test_binary_request_and_event() {
+        let request = rmpv::Value::Map(vec![
+            (rmpv::Value::from("action"), rmpv::Value::from("Set")),
+            (rmpv::Value::from("user_id"), rmpv::Value::from("1")),
+            (rmpv::Value::from("topic"), rmpv::Value::from("avatar")),
+            (rmpv::Value::from("message"), rmpv::Value::Binary(vec![0, 159, 255]))
+        ]);
+        let mut bytes = Vec::new();
+        rmpv::encode::write_value(&mut bytes, &request).unwrap();
+        let request = decode_request(&Message::binary(bytes)).unwrap();
+        assert_eq!(request.action, RequestAction::Set);
+        assert_eq!(request.message, Some(json!({ "$binary": "AJ//" })));
+
+        let event = Codec::MessagePack.encode(json!({ "value": request.message, "count": -2 }).to_string());
+        assert!(event.is_binary());
+        let event = rmpv::decode::read_value(&mut event.as_bytes()).unwrap();
+        assert_eq!(event["value"], rmpv::Value::Binary(vec![0, 159, 255]));
+        assert_eq!(to_json(event), json!({ "value": { "$binary": "AJ//" }, "count": -2 }));
+
+        assert!(Codec::Json.encode(String::from("{}")).is_text());
+    }
+}
@@ -1,5 +1,6 @@
-use crate::store::Responder;
-use std::collections::HashSet;
+use crate::store::{Responder, ScanFilter};
+use crate::serialize::StoredValue;
+use std::time::Instant;
 use tokio::sync::{mpsc::Sender, oneshot::{self, error::RecvError}};
 
 
@@ -15,30 +16,142 @@ pub enum Command<T> {
         value: T,
         responder: Responder<Option<T>>,
     },
+    /// Sets `value` under a new `key`, unless `max` items are already held. Responds with whether it was set.
+    InsertItem {
+        key: String,
+        value: T,
+        max: Option<usize>,
+        responder: Responder<bool>,
+    },
     UnsetItem {
         key: String,
         responder: Responder<Option<T>>,
     },
+    /// Removes the client under `key` if it has stayed offline since `since`, with no socket attached.
+    ExpireItem {
+        key: String,
+        since: Instant,
+        responder: Responder<Option<T>>,
+    },
+    UpdateItem {
+        key: String,
+        path: String,
+        update: PathUpdate<T>,
+        responder: Responder<Result<(Option<T>, T), String>>,
+    },
     GetCollection {
         key: String,
-        responder: Responder<Option<HashSet<T>>>,
+        responder: Responder<Option<Vec<T>>>,
     },
     AddToCollection {
         key: String,
         value: T,
-        responder: Responder<bool>,
+        /// Fails when the collection is already at the maximum number of members.
+        responder: Responder<Result<bool, String>>,
     },
     RemoveFromCollection {
         key: String,
         value: T,
         responder: Responder<bool>,
+    },
+    RemoveFromAllCollections {
+        value: T,
+        responder: Responder<Vec<(String, T)>>,
+    },
+    GetKeys {
+        responder: Responder<Vec<String>>,
+    },
+    PushToList {
+        key: String,
+        value: T,
+        front: bool,
+        /// Fails when the list is already at the maximum number of members.
+        responder: Responder<Result<usize, String>>,
+    },
+    PopFromList {
+        key: String,
+        front: bool,
+        responder: Responder<Option<T>>,
+    },
+    GetListRange {
+        key: String,
+        start: i64,
+        stop: i64,
+        responder: Responder<Vec<T>>,
+    },
+    SetField {
+        key: String,
+        field: String,
+        value: T,
+        /// Fails when the field is new and the hash is already at the maximum number of members.
+        responder: Responder<Result<Option<T>, String>>,
+    },
+    GetField {
+        key: String,
+        field: String,
+        responder: Responder<Option<T>>,
+    },
+    DeleteField {
+        key: String,
+        field: String,
+        responder: Responder<Option<T>>,
+    },
+    AddToSortedSet {
+        key: String,
+        value: T,
+        score: f64,
+        /// Whether the member is new or rescored. Fails when the member is new and the sorted set is
+        /// already at the maximum number of members.
+        responder: Responder<Result<bool, String>>,
+    },
+    GetSortedSetRangeByRank {
+        key: String,
+        start: i64,
+        stop: i64,
+        responder: Responder<Vec<(T, f64)>>,
+    },
+    GetSortedSetRangeByScore {
+        key: String,
+        min: f64,
+        max: f64,
+        responder: Responder<Vec<(T, f64)>>,
+    },
+    /// Every value held under `key`, whatever its kind.
+    InspectKey {
+        key: String,
+        responder: Responder<Vec<StoredValue>>,
+    },
+    /// Removes every value held under `key`. Responds with whether there was any.
+    DeleteKey {
+        key: String,
+        responder: Responder<bool>,
+    },
+    /// Replaces the value of `value`'s kind held under `key`. Responds with whether there was one.
+    RestoreKey {
+        key: String,
+        value: StoredValue,
+        responder: Responder<bool>,
+    },
+    /// The first `count` keys in byte order, of any kind, that pass `filter`.
+    ScanKeys {
+        filter: ScanFilter,
+        count: usize,
+        responder: Responder<Vec<String>>,
     }
 }
 
+/// A partial update applied to the value stored under a key, at a JSON Pointer path.
+#[derive(Debug)]
+pub enum PathUpdate<T> {
+    Set(T),
+    Merge(T),
+    Delete
+}
+
 pub async fn get_value<T>(key: String, sender: Sender<Command<T>>) -> Result<Option<T>, RecvError> {
     let (resp_tx, resp_rx) = oneshot::channel();
     let command =  Command::<T>::GetItem {
-        key: key,
+        key,
         responder: resp_tx
     };
     // TODO: better error handling here
@@ -53,8 +166,8 @@ pub async fn get_value<T>(key: String, sender: Sender<Command<T>>) -> Result<Opt
 pub async fn set_value<T>(key: String, value: T, sender: Sender<Command<T>>) -> Result<Option<T>, RecvError> {
     let (resp_tx, resp_rx) = oneshot::channel();
     let command =  Command::<T>::SetItem {
-        key: key,
-        value: value,
+        key,
+        value,
         responder: resp_tx
     };
     match sender.send(command).await {
@@ -68,7 +181,7 @@ pub async fn set_value<T>(key: String, value: T, sender: Sender<Command<T>>) ->
 pub async fn remove_value<T>(key: String, sender: Sender<Command<T>>) -> Result<Option<T>, RecvError> {
     let (resp_tx, resp_rx) = oneshot::channel();
     let command =  Command::<T>::UnsetItem {
-        key: key,
+        key,
         responder: resp_tx
     };
     match sender.send(command).await {
@@ -79,11 +192,11 @@ pub async fn remove_value<T>(key: String, sender: Sender<Command<T>>) -> Result<
     resp_rx.await
 }
 
-pub async fn add_value_to_collection<T>(key: String, value: T, sender: Sender<Command<T>>) -> Result<bool, RecvError> {
+pub async fn add_value_to_collection<T>(key: String, value: T, sender: Sender<Command<T>>) -> Result<Result<bool, String>, RecvError> {
     let (resp_tx, resp_rx) = oneshot::channel();
     let command =  Command::<T>::AddToCollection {
-        key: key,
-        value: value,
+        key,
+        value,
         responder: resp_tx
     };
     match sender.send(command).await {
@@ -97,8 +210,8 @@ pub async fn add_value_to_collection<T>(key: String, value: T, sender: Sender<Co
 pub async fn remove_value_from_collection<T>(key: String, value: T, sender: Sender<Command<T>>) -> Result<bool, RecvError> {
     let (resp_tx, resp_rx) = oneshot::channel();
     let command =  Command::<T>::RemoveFromCollection {
-        key: key,
-        value: value,
+        key,
+        value,
         responder: resp_tx
     };
     match sender.send(command).await {
@@ -109,10 +222,55 @@ pub async fn remove_value_from_collection<T>(key: String, value: T, sender: Send
     resp_rx.await
 }
 
-pub async fn get_collection<T>(key: String, sender: Sender<Command<T>>) -> Result<Option<HashSet<T>>, RecvError> {
+/// Applies `update` at `path` and responds with the previous and the updated value.
+pub async fn update_value<T>(key: String, path: String, update: PathUpdate<T>, sender: Sender<Command<T>>) -> Result<Result<(Option<T>, T), String>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::UpdateItem {
+        key,
+        path,
+        update,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => info!("#update_value success: {:?}", result),
+        Err(err) => error!("#update_value error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+/// Removes `value` from every collection holding it and responds with the keys and the removed entries.
+pub async fn remove_value_from_all_collections<T>(value: T, sender: Sender<Command<T>>) -> Result<Vec<(String, T)>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::RemoveFromAllCollections {
+        value,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => info!("#remove_value_from_all_collections success: {:?}", result),
+        Err(err) => error!("#remove_value_from_all_collections error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn get_keys<T>(sender: Sender<Command<T>>) -> Result<Vec<String>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::GetKeys {
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => debug!("#get_keys success: {:?}", result),
+        Err(err) => error!("#get_keys error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn get_collection<T>(key: String, sender: Sender<Command<T>>) -> Result<Option<Vec<T>>, RecvError> {
     let (resp_tx, resp_rx) = oneshot::channel();
     let command =  Command::<T>::GetCollection {
-        key: key,
+        key,
         responder: resp_tx
     };
     match sender.send(command).await {
@@ -123,13 +281,243 @@ pub async fn get_collection<T>(key: String, sender: Sender<Command<T>>) -> Resul
     resp_rx.await
 }
 
+pub async fn push_to_list<T>(key: String, value: T, front: bool, sender: Sender<Command<T>>) -> Result<Result<usize, String>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::PushToList {
+        key,
+        value,
+        front,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => info!("#push_to_list success: {:?}", result),
+        Err(err) => error!("#push_to_list error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn pop_from_list<T>(key: String, front: bool, sender: Sender<Command<T>>) -> Result<Option<T>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::PopFromList {
+        key,
+        front,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => info!("#pop_from_list success: {:?}", result),
+        Err(err) => error!("#pop_from_list error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn get_list_range<T>(key: String, start: i64, stop: i64, sender: Sender<Command<T>>) -> Result<Vec<T>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::GetListRange {
+        key,
+        start,
+        stop,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => debug!("#get_list_range success: {:?}", result),
+        Err(err) => error!("#get_list_range error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn set_field<T>(key: String, field: String, value: T, sender: Sender<Command<T>>) -> Result<Result<Option<T>, String>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::SetField {
+        key,
+        field,
+        value,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => info!("#set_field success: {:?}", result),
+        Err(err) => error!("#set_field error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn get_field<T>(key: String, field: String, sender: Sender<Command<T>>) -> Result<Option<T>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::GetField {
+        key,
+        field,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => debug!("#get_field success: {:?}", result),
+        Err(err) => error!("#get_field error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn delete_field<T>(key: String, field: String, sender: Sender<Command<T>>) -> Result<Option<T>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::DeleteField {
+        key,
+        field,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => info!("#delete_field success: {:?}", result),
+        Err(err) => error!("#delete_field error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn add_to_sorted_set<T>(key: String, value: T, score: f64, sender: Sender<Command<T>>) -> Result<Result<bool, String>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::AddToSortedSet {
+        key,
+        value,
+        score,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => info!("#add_to_sorted_set success: {:?}", result),
+        Err(err) => error!("#add_to_sorted_set error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn get_sorted_set_range_by_rank<T>(key: String, start: i64, stop: i64, sender: Sender<Command<T>>) -> Result<Vec<(T, f64)>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::GetSortedSetRangeByRank {
+        key,
+        start,
+        stop,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => debug!("#get_sorted_set_range_by_rank success: {:?}", result),
+        Err(err) => error!("#get_sorted_set_range_by_rank error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn get_sorted_set_range_by_score<T>(key: String, min: f64, max: f64, sender: Sender<Command<T>>) -> Result<Vec<(T, f64)>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::GetSortedSetRangeByScore {
+        key,
+        min,
+        max,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => debug!("#get_sorted_set_range_by_score success: {:?}", result),
+        Err(err) => error!("#get_sorted_set_range_by_score error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn inspect_key<T>(key: String, sender: Sender<Command<T>>) -> Result<Vec<StoredValue>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::InspectKey {
+        key,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => debug!("#inspect_key success: {:?}", result),
+        Err(err) => error!("#inspect_key error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn scan_keys<T>(filter: ScanFilter, count: usize, sender: Sender<Command<T>>) -> Result<Vec<String>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::ScanKeys {
+        filter,
+        count,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => debug!("#scan_keys success: {:?}", result),
+        Err(err) => error!("#scan_keys error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn restore_key<T>(key: String, value: StoredValue, sender: Sender<Command<T>>) -> Result<bool, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::RestoreKey {
+        key,
+        value,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => debug!("#restore_key success: {:?}", result),
+        Err(err) => error!("#restore_key error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn insert_value<T>(key: String, value: T, max: Option<usize>, sender: Sender<Command<T>>) -> Result<bool, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::InsertItem {
+        key,
+        value,
+        max,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => info!("#insert_value success: {:?}", result),
+        Err(err) => error!("#insert_value error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn expire_value<T>(key: String, since: Instant, sender: Sender<Command<T>>) -> Result<Option<T>, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::ExpireItem {
+        key,
+        since,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => info!("#expire_value success: {:?}", result),
+        Err(err) => error!("#expire_value error: {}", err)
+    }
+
+    resp_rx.await
+}
+
+pub async fn delete_key<T>(key: String, sender: Sender<Command<T>>) -> Result<bool, RecvError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command =  Command::<T>::DeleteKey {
+        key,
+        responder: resp_tx
+    };
+    match sender.send(command).await {
+        Ok(result) => info!("#delete_key success: {:?}", result),
+        Err(err) => error!("#delete_key error: {}", err)
+    }
+
+    resp_rx.await
+}
+
 #[cfg(test)]
 mod tests {
 
 use super::*;
 use tokio::sync::mpsc::channel;
 use crate::store::{Client, Clients, Subscriptions};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio::sync::Mutex;
 use std::sync::Arc;
 use std::println as info;
@@ -140,7 +528,8 @@ use std::println as info;
         let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
         let client = Client {
             user_id: String::from("1"),
-            sender: None
+            sender: None,
+            ..Default::default()
         };
         clients.lock().await.insert(key, client);
         let (clients_tx, mut clients_rx) = channel::<Command<Client>>(32);
@@ -168,7 +557,8 @@ use std::println as info;
         let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
         let client = Client {
             user_id: String::from("1"),
-            sender: None
+            sender: None,
+            ..Default::default()
         };
         let (clients_tx, mut clients_rx) = channel::<Command<Client>>(32);
         tokio::spawn(async move {
@@ -196,7 +586,8 @@ use std::println as info;
         let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
         let client = Client {
             user_id: String::from("1"),
-            sender: None
+            sender: None,
+            ..Default::default()
         };
         clients.lock().await.insert(key, client);
         let (clients_tx, mut clients_rx) = channel::<Command<Client>>(32);
@@ -227,7 +618,8 @@ use std::println as info;
         let key: String = String::from("hello");
         let client = Client {
             user_id: String::from("1"),
-            sender: None
+            sender: None,
+            ..Default::default()
         };
         let mut clients = HashSet::new();
         clients.insert(client.clone());
@@ -238,7 +630,7 @@ use std::println as info;
                     Command::GetCollection { key, responder } => {
                         if let Some(result) = subscriptions.lock().await.get(&key) {
                         info!("Get key {:?} from the subscriptions store. Result: {:?}", key, result);
-                        let _ = responder.send(Some(result.clone()));
+                        let _ = responder.send(Some(result.iter().cloned().collect()));
                         }
                     },
                     _ => {
@@ -257,7 +649,8 @@ use std::println as info;
         let (subscriptions_tx, mut subscriptions_rx) = channel::<Command<Client>>(32);
         let client = Client {
             user_id: String::from("1"),
-            sender: None
+            sender: None,
+            ..Default::default()
         };
         let key: String = String::from("hello");
         tokio::spawn(async move {
@@ -272,14 +665,10 @@ use std::println as info;
                             let mut subscription = HashSet::new();
                             subscription.insert(value);
                             let insert_result = subscriptions.insert(key, subscription);
-                            if insert_result.is_some() {
-                                false
-                            } else {
-                                true
-                            }
+                            insert_result.is_none()
                         }
                         };
-                        let _ = responder.send(result);
+                        let _ = responder.send(Ok(result));
                     }
                     _ => {
                         error!("Only Get, Set and Unset may be used with subscriptions.");
@@ -297,7 +686,8 @@ use std::println as info;
         let (subscriptions_tx, mut subscriptions_rx) = channel::<Command<Client>>(32);
         let client = Client {
             user_id: String::from("1"),
-            sender: None
+            sender: None,
+            ..Default::default()
         };
         let mut clients = HashSet::new();
         clients.insert(client.clone());
@@ -328,4 +718,182 @@ use std::println as info;
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_push_to_list() {
+        let (store_tx, mut store_rx) = channel::<Command<String>>(32);
+        tokio::spawn(async move {
+            let mut list = std::collections::VecDeque::new();
+            while let Some(cmd) = store_rx.recv().await {
+                match cmd {
+                    Command::PushToList { value, front, responder, .. } => {
+                        if front {
+                            list.push_front(value);
+                        } else {
+                            list.push_back(value);
+                        }
+                        let _ = responder.send(Ok(list.len()));
+                    },
+                    _ => {
+                        error!("Only PushToList may be used.");
+                    }
+                }
+            }
+        });
+
+        let result = push_to_list(String::from("hello"), String::from("a"), false, store_tx.clone()).await;
+        assert_eq!(result.unwrap(), Ok(1));
+        let result = push_to_list(String::from("hello"), String::from("b"), true, store_tx).await;
+        assert_eq!(result.unwrap(), Ok(2));
+    }
+
+    #[tokio::test]
+    async fn test_get_list_range() {
+        let (store_tx, mut store_rx) = channel::<Command<String>>(32);
+        tokio::spawn(async move {
+            while let Some(cmd) = store_rx.recv().await {
+                match cmd {
+                    Command::GetListRange { start, stop, responder, .. } => {
+                        assert_eq!((start, stop), (0, -1));
+                        let _ = responder.send(vec![String::from("a"), String::from("b")]);
+                    },
+                    _ => {
+                        error!("Only GetListRange may be used.");
+                    }
+                }
+            }
+        });
+
+        let result = get_list_range(String::from("hello"), 0, -1, store_tx).await;
+        assert_eq!(result.unwrap(), vec![String::from("a"), String::from("b")]);
+    }
+
+    #[tokio::test]
+    async fn test_set_field() {
+        let (store_tx, mut store_rx) = channel::<Command<String>>(32);
+        tokio::spawn(async move {
+            let mut hash = HashMap::new();
+            while let Some(cmd) = store_rx.recv().await {
+                match cmd {
+                    Command::SetField { field, value, responder, .. } => {
+                        let _ = responder.send(Ok(hash.insert(field, value)));
+                    },
+                    _ => {
+                        error!("Only SetField may be used.");
+                    }
+                }
+            }
+        });
+
+        let result = set_field(String::from("hello"), String::from("name"), String::from("a"), store_tx.clone()).await;
+        assert_eq!(result.unwrap(), Ok(None));
+        let result = set_field(String::from("hello"), String::from("name"), String::from("b"), store_tx).await;
+        assert_eq!(result.unwrap(), Ok(Some(String::from("a"))));
+    }
+
+    #[tokio::test]
+    async fn test_get_sorted_set_range_by_score() {
+        let (store_tx, mut store_rx) = channel::<Command<String>>(32);
+        tokio::spawn(async move {
+            while let Some(cmd) = store_rx.recv().await {
+                match cmd {
+                    Command::GetSortedSetRangeByScore { min, max, responder, .. } => {
+                        assert_eq!((min, max), (1.0, 2.0));
+                        let _ = responder.send(vec![(String::from("a"), 1.5)]);
+                    },
+                    _ => {
+                        error!("Only GetSortedSetRangeByScore may be used.");
+                    }
+                }
+            }
+        });
+
+        let result = get_sorted_set_range_by_score(String::from("hello"), 1.0, 2.0, store_tx).await;
+        assert_eq!(result.unwrap(), vec![(String::from("a"), 1.5)]);
+    }
+
+    #[tokio::test]
+    async fn test_update_value() {
+        let (store_tx, mut store_rx) = channel::<Command<serde_json::Value>>(32);
+        tokio::spawn(async move {
+            let mut document = serde_json::json!({ "name": "a" });
+            while let Some(cmd) = store_rx.recv().await {
+                match cmd {
+                    Command::UpdateItem { path, update: PathUpdate::Set(value), responder, .. } => {
+                        let previous = document.clone();
+                        let result = crate::document::set_path(&mut document, &path, value).map(|_| (Some(previous), document.clone()));
+                        let _ = responder.send(result);
+                    },
+                    _ => {
+                        error!("Only UpdateItem with Set may be used.");
+                    }
+                }
+            }
+        });
+
+        let result = update_value(String::from("hello"), String::from("/age"), PathUpdate::Set(serde_json::json!(1)), store_tx.clone()).await;
+        assert_eq!(result.unwrap(), Ok((Some(serde_json::json!({ "name": "a" })), serde_json::json!({ "name": "a", "age": 1 }))));
+        let result = update_value(String::from("hello"), String::from("/missing/age"), PathUpdate::Set(serde_json::json!(1)), store_tx).await;
+        assert!(result.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remove_value_from_all_collections() {
+        let subscriptions: Subscriptions = Arc::new(Mutex::new(HashMap::new()));
+        let (subscriptions_tx, mut subscriptions_rx) = channel::<Command<Client>>(32);
+        let client = Client {
+            user_id: String::from("1"),
+            sender: None,
+            ..Default::default()
+        };
+        for topic in ["a", "b"] {
+            let mut clients = HashSet::new();
+            clients.insert(client.clone());
+            subscriptions.lock().await.insert(String::from(topic), clients);
+        }
+        subscriptions.lock().await.insert(String::from("c"), HashSet::new());
+
+        tokio::spawn(async move {
+            while let Some(cmd) = subscriptions_rx.recv().await {
+                match cmd {
+                    Command::RemoveFromAllCollections { value, responder } => {
+                        let mut result = Vec::new();
+                        for (topic, subscribers) in subscriptions.lock().await.iter_mut() {
+                            if let Some(subscriber) = subscribers.take(&value) {
+                                result.push((topic.clone(), subscriber));
+                            }
+                        }
+                        let _ = responder.send(result);
+                    },
+                    _ => {
+                        error!("Only RemoveFromAllCollections may be used.");
+                    }
+                }
+            }
+        });
+
+        let result = remove_value_from_all_collections(client, subscriptions_tx).await;
+        let mut topics: Vec<String> = result.unwrap().into_iter().map(|(topic, _)| topic).collect();
+        topics.sort();
+        assert_eq!(topics, vec![String::from("a"), String::from("b")]);
+    }
+
+    #[tokio::test]
+    async fn test_get_keys() {
+        let (subscriptions_tx, mut subscriptions_rx) = channel::<Command<Client>>(32);
+        tokio::spawn(async move {
+            while let Some(cmd) = subscriptions_rx.recv().await {
+                match cmd {
+                    Command::GetKeys { responder } => {
+                        let _ = responder.send(vec![String::from("a"), String::from("b")]);
+                    },
+                    _ => {
+                        error!("Only GetKeys may be used.");
+                    }
+                }
+            }
+        });
+
+        let result = get_keys(subscriptions_tx).await;
+        assert_eq!(result.unwrap(), vec![String::from("a"), String::from("b")]);
+    }
 }
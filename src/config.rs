@@ -0,0 +1,196 @@
+use std::{env, path::PathBuf, str::FromStr, sync::OnceLock, thread, time::Duration};
+use serde::Serialize;
+
+/// Server settings, read once from `PUBSUB_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How long a Request waits for a Reply before the requester gets a Timeout error.
+    pub request_timeout: Duration,
+    /// How long a reliable event waits for its Ack before being redelivered.
+    pub ack_timeout: Duration,
+    /// Deliveries of a reliable event before it is moved to the dead-letter topic.
+    pub max_delivery_attempts: u32,
+    /// How long a client that disconnected with unacked events stays registered for its reconnect. Its events are
+    /// then moved to the dead-letter topics.
+    pub offline_timeout: Duration,
+    /// Where the event log and consumer positions are persisted. Kept in memory only when unset.
+    pub data_dir: Option<PathBuf>,
+    /// Events the log keeps for each topic with durable consumers. Consumers behind the oldest one skip ahead.
+    pub log_retention: usize,
+    /// Port the server listens on, on 127.0.0.1.
+    pub port: u16,
+    /// Base URL under which the other nodes of a cluster reach this one.
+    pub advertise_url: String,
+    /// Base URLs of the other nodes of the cluster, comma separated. Runs standalone when empty.
+    pub cluster_peers: Vec<String>,
+    /// How often this node sends its subscribed topics to its peers. Peers silent for three intervals are dropped.
+    pub cluster_sync_interval: Duration,
+    /// Bearer token the nodes of a cluster, and a primary and its replicas, present to each other. The cluster and
+    /// replication routes are disabled when unset.
+    pub cluster_secret: Option<String>,
+    /// Base URL of the primary this node replicates its store from. The node is a primary when unset.
+    pub replica_of: Option<String>,
+    /// Mutations a primary keeps for its replicas to catch up from. A replica further behind resyncs from a snapshot.
+    pub replication_backlog: usize,
+    /// How often a primary sends a heartbeat down its replication streams.
+    pub heartbeat_interval: Duration,
+    /// How long a replica waits without hearing from its primary before promoting itself. Only manual promotion when unset.
+    pub failover_timeout: Option<Duration>,
+    /// Number of store actors the keyspace is partitioned across. Defaults to the number of cores.
+    pub store_shards: usize,
+    /// Size in bytes from which events are deflated for clients that negotiated compression.
+    pub compression_threshold: usize,
+    /// How often the server pings each socket.
+    pub ping_interval: Duration,
+    /// Pings a socket may leave unanswered before it is considered dead and disconnected.
+    pub max_missed_pongs: u32,
+    pub rate_limits: RateLimits,
+    pub quotas: Quotas,
+    /// Bearer token of the admin API. The admin routes are disabled when unset.
+    pub admin_token: Option<String>,
+    /// Snapshot file merged into the store before the server starts listening.
+    pub import_snapshot: Option<PathBuf>,
+}
+
+/// Token-bucket rates, per second, with a burst of one second's worth. Unlimited when unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rates {
+    /// Requests that change the store or send messages to other clients.
+    pub publishes: Option<f64>,
+    /// Bytes of incoming frames. A frame larger than one second's worth is always refused.
+    pub bytes: Option<f64>,
+}
+
+/// Bounds on what a client may store or hold. Unlimited when unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Quotas {
+    /// Bytes of a topic, key or hash field.
+    pub max_key_length: Option<usize>,
+    /// Bytes of the JSON `message` of a request.
+    pub max_value_size: Option<usize>,
+    /// Members of a collection, list, hash or sorted set.
+    pub max_collection_members: Option<usize>,
+    /// Registered clients, connected or not.
+    pub max_clients: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimits {
+    /// Applied to each socket.
+    pub connection: Rates,
+    /// Applied to each user id, across its reconnects.
+    pub identity: Rates,
+    /// Applied to the publishes on each topic, whoever makes them.
+    pub topic: Rates,
+    /// Topics a socket may be subscribed to at once.
+    pub max_subscriptions: Option<usize>,
+    /// Sockets that may be subscribed to a topic at once, on this node.
+    pub max_topic_subscribers: Option<usize>,
+    /// Throttled requests in a row after which the socket is disconnected. Never disconnected when unset.
+    pub disconnect_after: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            request_timeout: Duration::from_millis(5000),
+            ack_timeout: Duration::from_millis(10000),
+            max_delivery_attempts: 5,
+            offline_timeout: Duration::from_millis(60000),
+            data_dir: None,
+            log_retention: 100000,
+            port: 8000,
+            advertise_url: String::from("http://127.0.0.1:8000"),
+            cluster_peers: Vec::new(),
+            cluster_sync_interval: Duration::from_millis(1000),
+            cluster_secret: None,
+            replica_of: None,
+            replication_backlog: 100000,
+            heartbeat_interval: Duration::from_millis(1000),
+            failover_timeout: None,
+            store_shards: thread::available_parallelism().map_or(1, |cores| cores.get()),
+            compression_threshold: 1024,
+            ping_interval: Duration::from_millis(15000),
+            max_missed_pongs: 3,
+            rate_limits: RateLimits::default(),
+            quotas: Quotas::default(),
+            admin_token: None,
+            import_snapshot: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let default = Config::default();
+        let port = env_or("PUBSUB_PORT", default.port);
+        Config {
+            request_timeout: Duration::from_millis(env_or("PUBSUB_REQUEST_TIMEOUT_MS", default.request_timeout.as_millis() as u64)),
+            ack_timeout: Duration::from_millis(env_or("PUBSUB_ACK_TIMEOUT_MS", default.ack_timeout.as_millis() as u64)),
+            max_delivery_attempts: env_or("PUBSUB_MAX_DELIVERY_ATTEMPTS", default.max_delivery_attempts),
+            offline_timeout: Duration::from_millis(env_or("PUBSUB_OFFLINE_TIMEOUT_MS", default.offline_timeout.as_millis() as u64)),
+            data_dir: env::var("PUBSUB_DATA_DIR").ok().map(PathBuf::from),
+            log_retention: env_or("PUBSUB_LOG_RETENTION", default.log_retention).max(1),
+            port,
+            advertise_url: env::var("PUBSUB_ADVERTISE_URL").unwrap_or(format!("http://127.0.0.1:{}", port)),
+            cluster_peers: env::var("PUBSUB_CLUSTER_PEERS").map(|peers| parse_list(&peers)).unwrap_or_default(),
+            cluster_sync_interval: Duration::from_millis(env_or("PUBSUB_CLUSTER_SYNC_MS", default.cluster_sync_interval.as_millis() as u64).max(1)),
+            cluster_secret: env::var("PUBSUB_CLUSTER_SECRET").ok().filter(|secret| !secret.is_empty()),
+            replica_of: env::var("PUBSUB_REPLICA_OF").ok().map(|url| String::from(url.trim_end_matches('/'))),
+            replication_backlog: env_or("PUBSUB_REPLICATION_BACKLOG", default.replication_backlog).max(1),
+            heartbeat_interval: Duration::from_millis(env_or("PUBSUB_HEARTBEAT_MS", default.heartbeat_interval.as_millis() as u64).max(1)),
+            failover_timeout: match env_or("PUBSUB_FAILOVER_TIMEOUT_MS", 0) {
+                0 => None,
+                timeout => Some(Duration::from_millis(timeout))
+            },
+            store_shards: env_or("PUBSUB_STORE_SHARDS", default.store_shards).max(1),
+            compression_threshold: env_or("PUBSUB_COMPRESSION_THRESHOLD", default.compression_threshold),
+            ping_interval: Duration::from_millis(env_or("PUBSUB_PING_INTERVAL_MS", default.ping_interval.as_millis() as u64).max(1)),
+            max_missed_pongs: env_or("PUBSUB_MAX_MISSED_PONGS", default.max_missed_pongs).max(1),
+            rate_limits: RateLimits {
+                connection: Rates { publishes: limit("PUBSUB_CONNECTION_PUBLISH_RATE"), bytes: limit("PUBSUB_CONNECTION_BYTE_RATE") },
+                identity: Rates { publishes: limit("PUBSUB_IDENTITY_PUBLISH_RATE"), bytes: limit("PUBSUB_IDENTITY_BYTE_RATE") },
+                topic: Rates { publishes: limit("PUBSUB_TOPIC_PUBLISH_RATE"), bytes: limit("PUBSUB_TOPIC_BYTE_RATE") },
+                max_subscriptions: limit("PUBSUB_MAX_SUBSCRIPTIONS"),
+                max_topic_subscribers: limit("PUBSUB_MAX_TOPIC_SUBSCRIBERS"),
+                disconnect_after: limit("PUBSUB_THROTTLE_DISCONNECT_AFTER"),
+            },
+            quotas: Quotas {
+                max_key_length: limit("PUBSUB_MAX_KEY_LENGTH"),
+                max_value_size: limit("PUBSUB_MAX_VALUE_SIZE"),
+                max_collection_members: limit("PUBSUB_MAX_COLLECTION_MEMBERS"),
+                max_clients: limit("PUBSUB_MAX_CLIENTS"),
+            },
+            admin_token: env::var("PUBSUB_ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            import_snapshot: env::var("PUBSUB_IMPORT_SNAPSHOT").ok().map(PathBuf::from),
+        }
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::from_env)
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                warn!("Ignoring invalid value {:?} for {}", value, name);
+                default
+            }
+        },
+        Err(_) => default
+    }
+}
+
+/// A limit read from `name`, where 0 or no value means unlimited.
+fn limit<T: FromStr + Default + PartialEq>(name: &str) -> Option<T> {
+    Some(env_or(name, T::default())).filter(|limit| *limit != T::default())
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',').map(|item| item.trim().trim_end_matches('/')).filter(|item| !item.is_empty()).map(String::from).collect()
+}
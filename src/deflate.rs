@@ -0,0 +1,330 @@
+use std::{io::{self, Cursor}, pin::Pin, task::{ready, Context, Poll}};
+use bytes::{Buf, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::protocol::frame::{coding::{Data, OpCode}, Frame, FrameHeader};
+
+/// Name of the permessage-deflate WebSocket extension (RFC 7692) in `Sec-WebSocket-Extensions`.
+pub const EXTENSION: &str = "permessage-deflate";
+/// The extension as accepted by the server. Each message the server sends is deflated on its own, so
+/// that the server keeps no compression context per socket.
+pub const ACCEPTED: &str = "permessage-deflate; server_no_context_takeover";
+/// The empty block a sync flush ends with, which senders strip from deflated messages.
+const TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+/// Messages larger than this, once inflated, close the connection, as tungstenite does by default.
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Whether the server accepts one of the permessage-deflate offers of a `Sec-WebSocket-Extensions`
+/// header. Offers asking the server for a window under 15 bits are declined, as the deflater cannot
+/// use smaller windows.
+pub fn negotiate(offered: Option<&str>) -> bool {
+    offered.unwrap_or_default().split(',').any(|offer| {
+        let mut params = offer.split(';').map(str::trim);
+        params.next() == Some(EXTENSION) && params.all(|param| match param.split_once('=') {
+            Some(("server_max_window_bits", bits)) => bits.trim_matches('"') == "15",
+            Some(("client_max_window_bits", bits)) => bits.trim_matches('"').parse::<u8>().is_ok_and(|bits| (8..=15).contains(&bits)),
+            Some(_) => false,
+            None => matches!(param, "server_no_context_takeover" | "client_no_context_takeover" | "client_max_window_bits")
+        })
+    })
+}
+
+/// The byte stream under a server socket. With permessage-deflate, it inflates the compressed messages
+/// of the client and deflates the data frames of the server of at least `threshold` bytes, so that the
+/// WebSocket protocol above only ever sees plain frames.
+pub struct Transport<S> {
+    inner: S,
+    deflate: bool,
+    threshold: usize,
+    inflater: Decompress,
+    deflater: Compress,
+    /// Bytes read that do not make a whole frame yet, and the frames ready for the protocol above.
+    read_raw: BytesMut,
+    read_ready: BytesMut,
+    /// Header and payload so far of the compressed message being received in fragments.
+    message: Option<(FrameHeader, Vec<u8>)>,
+    /// Bytes written that do not make a whole frame yet, and the frames ready for the client.
+    write_raw: BytesMut,
+    write_ready: BytesMut
+}
+
+impl<S> Transport<S> {
+    pub fn new(inner: S, deflate: bool, threshold: usize) -> Transport<S> {
+        Transport {
+            inner,
+            deflate,
+            threshold,
+            inflater: Decompress::new(false),
+            deflater: Compress::new(Compression::fast(), false),
+            read_raw: BytesMut::new(),
+            read_ready: BytesMut::new(),
+            message: None,
+            write_raw: BytesMut::new(),
+            write_ready: BytesMut::new()
+        }
+    }
+
+    /// Moves the whole frames of `read_raw` to `read_ready`, compressed messages inflated into a single frame.
+    fn inbound(&mut self) -> io::Result<()> {
+        while let Some((header, payload)) = take_frame(&mut self.read_raw)? {
+            let (mut first, message) = match (header.opcode, self.message.take()) {
+                (OpCode::Data(Data::Continue), Some((first, mut message))) => {
+                    message.extend_from_slice(&payload);
+                    if message.len() > MAX_MESSAGE_SIZE {
+                        return Err(invalid("compressed message too large"));
+                    }
+                    if !header.is_final {
+                        self.message = Some((first, message));
+                        continue;
+                    }
+                    (first, message)
+                },
+                (OpCode::Data(Data::Text | Data::Binary), None) if header.rsv1 => {
+                    if !header.is_final {
+                        self.message = Some((header, payload));
+                        continue;
+                    }
+                    (header, payload)
+                },
+                (_, message) => {
+                    // Control frames may come between fragments. Anything else, protocol errors included,
+                    // is the business of the protocol above.
+                    self.message = message;
+                    write_frame(&mut self.read_ready, header, payload)?;
+                    continue;
+                }
+            };
+            let inflated = self.inflate(message)?;
+            first.is_final = true;
+            first.rsv1 = false;
+            write_frame(&mut self.read_ready, first, inflated)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the whole frames of `write_raw` to `write_ready`, deflating the unfragmented data frames large enough.
+    fn outbound(&mut self) -> io::Result<()> {
+        while let Some((mut header, payload)) = take_frame(&mut self.write_raw)? {
+            let data = matches!(header.opcode, OpCode::Data(Data::Text | Data::Binary));
+            if data && header.is_final && payload.len() >= self.threshold {
+                let deflated = self.deflate(&payload)?;
+                if deflated.len() < payload.len() {
+                    header.rsv1 = true;
+                    write_frame(&mut self.write_ready, header, deflated)?;
+                    continue;
+                }
+            }
+            write_frame(&mut self.write_ready, header, payload)?;
+        }
+        Ok(())
+    }
+
+    fn inflate(&mut self, mut message: Vec<u8>) -> io::Result<Vec<u8>> {
+        message.extend_from_slice(&TAIL);
+        let mut inflated = Vec::with_capacity(message.len() * 4);
+        let mut input = &message[..];
+        loop {
+            let before = self.inflater.total_in();
+            self.inflater.decompress_vec(input, &mut inflated, FlushDecompress::Sync).map_err(|err| invalid(&err.to_string()))?;
+            input = &input[(self.inflater.total_in() - before) as usize..];
+            if input.is_empty() && inflated.len() < inflated.capacity() {
+                return Ok(inflated);
+            }
+            if inflated.len() > MAX_MESSAGE_SIZE {
+                return Err(invalid("inflated message too large"));
+            }
+            inflated.reserve(inflated.capacity());
+        }
+    }
+
+    fn deflate(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        self.deflater.reset();
+        let mut deflated = Vec::with_capacity(payload.len() / 2 + 64);
+        let mut input = payload;
+        loop {
+            let before = self.deflater.total_in();
+            self.deflater.compress_vec(input, &mut deflated, FlushCompress::Sync).map_err(|err| invalid(&err.to_string()))?;
+            input = &input[(self.deflater.total_in() - before) as usize..];
+            if input.is_empty() && deflated.len() < deflated.capacity() {
+                break;
+            }
+            deflated.reserve(deflated.capacity());
+        }
+        if deflated.ends_with(&TAIL) {
+            deflated.truncate(deflated.len() - TAIL.len());
+        }
+        Ok(deflated)
+    }
+}
+
+/// Removes the first frame of `buffer` once it holds all of it, with its payload unmasked.
+fn take_frame(buffer: &mut BytesMut) -> io::Result<Option<(FrameHeader, Vec<u8>)>> {
+    let mut cursor = Cursor::new(&buffer[..]);
+    let (header, length) = match FrameHeader::parse(&mut cursor).map_err(|err| invalid(&err.to_string()))? {
+        Some(parsed) => parsed,
+        None => return Ok(None)
+    };
+    if length > MAX_MESSAGE_SIZE as u64 {
+        return Err(invalid("frame too large"));
+    }
+    let start = cursor.position() as usize;
+    if buffer.len() < start + length as usize {
+        return Ok(None);
+    }
+    buffer.advance(start);
+    let mut payload = buffer.split_to(length as usize).to_vec();
+    if let Some(mask) = header.mask {
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+    }
+    Ok(Some((header, payload)))
+}
+
+/// Formats a frame, masking its payload again if the header has a mask.
+fn write_frame(buffer: &mut BytesMut, header: FrameHeader, payload: Vec<u8>) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(header.len(payload.len() as u64) + payload.len());
+    Frame::from_payload(header, payload).format(&mut bytes).map_err(|err| invalid(&err.to_string()))?;
+    buffer.extend_from_slice(&bytes);
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("permessage-deflate: {}", message))
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Transport<S> {
+    /// Writes out the frames ready for the client.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_ready.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_ready))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_ready.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Transport<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.deflate {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let mut chunk = [0; 8192];
+        while this.read_ready.is_empty() {
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.read_raw.extend_from_slice(read.filled());
+            this.inbound()?;
+        }
+        let count = buf.remaining().min(this.read_ready.len());
+        buf.put_slice(&this.read_ready.split_to(count));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Transport<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.deflate {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        ready!(this.poll_drain(cx))?;
+        this.write_raw.extend_from_slice(buf);
+        this.outbound()?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::{tungstenite::{protocol::{frame::{coding::{Control, Data, OpCode}, Frame, FrameHeader}, Role}, Message}, WebSocketStream};
+    use super::{negotiate, take_frame, Transport, TAIL};
+
+    #[test]
+    fn test_negotiate() {
+        assert!(!negotiate(None));
+        assert!(negotiate(Some("permessage-deflate")));
+        assert!(negotiate(Some("permessage-deflate; client_max_window_bits")));
+        assert!(negotiate(Some("x-webkit-deflate-frame, permessage-deflate; server_no_context_takeover")));
+        assert!(negotiate(Some("permessage-deflate; server_max_window_bits=10, permessage-deflate")));
+        assert!(!negotiate(Some("permessage-deflate; server_max_window_bits=10")));
+        assert!(!negotiate(Some("permessage-deflate; unknown")));
+    }
+
+    /// Deflates as a client does, keeping its context across messages.
+    fn deflate(deflater: &mut Compress, payload: &[u8]) -> Vec<u8> {
+        let mut deflated = Vec::with_capacity(payload.len() + 64);
+        deflater.compress_vec(payload, &mut deflated, FlushCompress::Sync).unwrap();
+        deflated.truncate(deflated.len() - TAIL.len());
+        deflated
+    }
+
+    fn client_frame(opcode: OpCode, is_final: bool, rsv1: bool, payload: Vec<u8>) -> Vec<u8> {
+        let header = FrameHeader { is_final, rsv1, opcode, mask: Some([1, 2, 3, 4]), ..FrameHeader::default() };
+        let mut bytes = Vec::new();
+        Frame::from_payload(header, payload).format(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_transport() {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let mut socket = WebSocketStream::from_raw_socket(Transport::new(server, true, 64), Role::Server, None).await;
+
+        let text = "hello ".repeat(100);
+        let mut deflater = Compress::new(Compression::default(), false);
+        let deflated = deflate(&mut deflater, text.as_bytes());
+        let (first, rest) = deflated.split_at(deflated.len() / 2);
+        client.write_all(&client_frame(OpCode::Data(Data::Text), false, true, first.to_vec())).await.unwrap();
+        client.write_all(&client_frame(OpCode::Control(Control::Ping), true, false, Vec::new())).await.unwrap();
+        client.write_all(&client_frame(OpCode::Data(Data::Continue), true, false, rest.to_vec())).await.unwrap();
+        client.write_all(&client_frame(OpCode::Data(Data::Text), true, false, b"plain".to_vec())).await.unwrap();
+        client.write_all(&client_frame(OpCode::Data(Data::Binary), true, true, deflate(&mut deflater, text.as_bytes()))).await.unwrap();
+        assert_eq!(socket.next().await.unwrap().unwrap(), Message::Ping(Vec::new()));
+        assert_eq!(socket.next().await.unwrap().unwrap(), Message::text(text.clone()));
+        assert_eq!(socket.next().await.unwrap().unwrap(), Message::text("plain"));
+        assert_eq!(socket.next().await.unwrap().unwrap(), Message::binary(text.clone()));
+
+        socket.send(Message::text(text.clone())).await.unwrap();
+        socket.send(Message::text("small")).await.unwrap();
+        let mut received = BytesMut::from(&[0; 1 << 16][..]);
+        let length = client.read(&mut received).await.unwrap();
+        received.truncate(length);
+        let mut frames = Vec::new();
+        while let Some(frame) = take_frame(&mut received).unwrap() {
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].0.opcode, OpCode::Control(Control::Pong));
+        assert!(frames[1].0.rsv1);
+        let payload = [&frames[1].1[..], &TAIL].concat();
+        let mut inflated = Vec::with_capacity(text.len() + 64);
+        Decompress::new(false).decompress_vec(&payload, &mut inflated, FlushDecompress::Sync).unwrap();
+        assert_eq!(inflated, text.as_bytes());
+        assert!(!frames[2].0.rsv1);
+        assert_eq!(frames[2].1, b"small");
+    }
+}
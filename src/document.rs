@@ -0,0 +1,187 @@
+use serde_json::{json, Map, Value};
+
+/// Sets the value at a JSON Pointer `path` (RFC 6901), creating the final object key or appending
+/// to an array with `-`. An empty path replaces the whole document.
+pub fn set_path(document: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *document = value;
+        return Ok(());
+    }
+    let (parent, token) = split_path(path)?;
+    let parent = match document.pointer_mut(parent) {
+        Some(parent) => parent,
+        None => return Err(format!("Path {} does not exist", parent))
+    };
+    if parent.is_null() {
+        *parent = Value::Object(Map::new());
+    }
+    match parent {
+        Value::Object(object) => {
+            object.insert(token, value);
+            Ok(())
+        },
+        Value::Array(array) => {
+            let index = if token == "-" { array.len() } else { parse_index(&token)? };
+            if index < array.len() {
+                array[index] = value;
+                Ok(())
+            } else if index == array.len() {
+                array.push(value);
+                Ok(())
+            } else {
+                Err(format!("Index {} is out of bounds", index))
+            }
+        },
+        _ => Err(format!("Cannot set {} on a scalar value", path))
+    }
+}
+
+/// Applies `patch` as a JSON Merge Patch (RFC 7386) to the value at `path`, creating it if missing.
+pub fn merge_path(document: &mut Value, path: &str, patch: Value) -> Result<(), String> {
+    if document.pointer(path).is_none() {
+        set_path(document, path, Value::Null)?;
+    }
+    match document.pointer_mut(path) {
+        Some(target) => {
+            merge(target, patch);
+            Ok(())
+        },
+        None => Err(format!("Path {} does not exist", path))
+    }
+}
+
+/// Removes the value at `path`.
+pub fn delete_path(document: &mut Value, path: &str) -> Result<(), String> {
+    if path.is_empty() {
+        return Err(String::from("Cannot delete the document root, use Unset instead"));
+    }
+    let (parent, token) = split_path(path)?;
+    let removed = match document.pointer_mut(parent) {
+        Some(Value::Object(object)) => object.remove(&token).is_some(),
+        Some(Value::Array(array)) => {
+            let index = parse_index(&token)?;
+            if index < array.len() {
+                array.remove(index);
+                true
+            } else {
+                false
+            }
+        },
+        _ => false
+    };
+    if removed {
+        Ok(())
+    } else {
+        Err(format!("Path {} does not exist", path))
+    }
+}
+
+/// Computes a JSON Patch (RFC 6902) that turns `before` into `after`. Objects are diffed key by key,
+/// anything else that changed is replaced wholesale.
+pub fn diff(before: &Value, after: &Value) -> Value {
+    let mut operations = Vec::new();
+    diff_into(before, after, String::new(), &mut operations);
+    Value::Array(operations)
+}
+
+fn diff_into(before: &Value, after: &Value, path: String, operations: &mut Vec<Value>) {
+    if before == after {
+        return;
+    }
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            for key in before.keys().filter(|key| !after.contains_key(*key)) {
+                operations.push(json!({ "op": "remove", "path": child_path(&path, key) }));
+            }
+            for (key, value) in after {
+                match before.get(key) {
+                    Some(previous) => diff_into(previous, value, child_path(&path, key), operations),
+                    None => operations.push(json!({ "op": "add", "path": child_path(&path, key), "value": value }))
+                }
+            }
+        },
+        _ => operations.push(json!({ "op": "replace", "path": path, "value": after }))
+    }
+}
+
+fn merge(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            if let Value::Object(object) = target {
+                for (key, value) in patch {
+                    if value.is_null() {
+                        object.remove(&key);
+                    } else {
+                        merge(object.entry(key).or_insert(Value::Null), value);
+                    }
+                }
+            }
+        },
+        patch => *target = patch
+    }
+}
+
+fn split_path(path: &str) -> Result<(&str, String), String> {
+    match path.rfind('/') {
+        Some(index) => Ok((&path[..index], path[index + 1..].replace("~1", "/").replace("~0", "~"))),
+        None => Err(format!("Invalid path {}, paths must start with /", path))
+    }
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+fn parse_index(token: &str) -> Result<usize, String> {
+    token.parse::<usize>().map_err(|_| format!("Invalid array index {}", token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_path() {
+        let mut document = Value::Null;
+        assert!(set_path(&mut document, "/name", json!("a")).is_ok());
+        assert!(set_path(&mut document, "/tags", json!([])).is_ok());
+        assert!(set_path(&mut document, "/tags/-", json!("x")).is_ok());
+        assert!(set_path(&mut document, "/tags/0", json!("y")).is_ok());
+        assert!(set_path(&mut document, "/missing/name", json!("a")).is_err());
+        assert_eq!(document, json!({ "name": "a", "tags": ["y"] }));
+    }
+
+    #[test]
+    fn test_merge_path() {
+        let mut document = json!({ "user": { "name": "a", "age": 1 } });
+        assert!(merge_path(&mut document, "/user", json!({ "age": null, "city": "b" })).is_ok());
+        assert!(merge_path(&mut document, "/settings", json!({ "dark": true })).is_ok());
+        assert_eq!(document, json!({ "user": { "name": "a", "city": "b" }, "settings": { "dark": true } }));
+    }
+
+    #[test]
+    fn test_delete_path() {
+        let mut document = json!({ "a/b": 1, "list": [1, 2] });
+        assert!(delete_path(&mut document, "/a~1b").is_ok());
+        assert!(delete_path(&mut document, "/list/0").is_ok());
+        assert!(delete_path(&mut document, "/missing").is_err());
+        assert!(delete_path(&mut document, "").is_err());
+        assert_eq!(document, json!({ "list": [2] }));
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = json!({ "name": "a", "old": true, "nested": { "count": 1 } });
+        let after = json!({ "name": "a", "new": [1], "nested": { "count": 2 } });
+        assert_eq!(diff(&before, &after), json!([
+            { "op": "remove", "path": "/old" },
+            { "op": "replace", "path": "/nested/count", "value": 2 },
+            { "op": "add", "path": "/new", "value": [1] }
+        ]));
+        assert_eq!(diff(&Value::Null, &json!("a")), json!([{ "op": "replace", "path": "", "value": "a" }]));
+        assert_eq!(diff(&before, &before), json!([]));
+    }
+}
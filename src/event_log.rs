@@ -0,0 +1,525 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, path::{Path, PathBuf}, sync::Arc};
+use arc_swap::ArcSwap;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use tokio::{fs::{self, OpenOptions}, io::AsyncWriteExt, sync::{mpsc::{self, Receiver, Sender}, oneshot::{self, error::RecvError}}};
+use crate::store::Responder;
+
+/// Commands of the event log actor, which keeps the latest events published on the topics that have
+/// durable consumers, along with the read positions of those consumers.
+#[derive(Debug)]
+pub enum LogCommand {
+    Append {
+        topic: String,
+        value: Value,
+    },
+    CreateConsumer {
+        topic: String,
+        name: String,
+        start: Option<u64>,
+        responder: Responder<Result<ConsumerInfo, String>>,
+    },
+    GetConsumer {
+        topic: String,
+        name: String,
+        responder: Responder<Option<ConsumerInfo>>,
+    },
+    ResetConsumer {
+        topic: String,
+        name: String,
+        offset: u64,
+        responder: Responder<Result<ConsumerInfo, String>>,
+    },
+    DeleteConsumer {
+        topic: String,
+        name: String,
+        responder: Responder<bool>,
+    },
+    Fetch {
+        topic: String,
+        name: String,
+        count: usize,
+        responder: Responder<Result<Vec<LoggedEvent>, String>>,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoggedEvent {
+    pub offset: u64,
+    pub value: Value
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Consumer {
+    topic: String,
+    name: String,
+    offset: u64
+}
+
+/// A consumer's read position relative to the end of its topic's log.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ConsumerInfo {
+    pub topic: String,
+    pub name: String,
+    pub offset: u64,
+    pub head: u64,
+    pub lag: u64
+}
+
+/// Sender of the event log actor, along with the topics that have consumers as last published by the
+/// actor. Publishes read those topics to skip the log of topics nobody consumes without waiting on the actor.
+#[derive(Clone, Debug)]
+pub struct EventLogTx {
+    sender: Sender<LogCommand>,
+    consumed: Arc<ArcSwap<HashSet<String>>>
+}
+
+impl EventLogTx {
+    pub fn sender(&self) -> Sender<LogCommand> {
+        self.sender.clone()
+    }
+
+    pub fn is_consumed(&self, topic: &str) -> bool {
+        self.consumed.load().contains(topic)
+    }
+}
+
+/// Spawns the event log actor, loading what was persisted in `dir`. Each topic keeps its latest `retention` events.
+pub fn spawn(dir: Option<PathBuf>, retention: usize) -> EventLogTx {
+    let (sender, log_rx) = mpsc::channel::<LogCommand>(32);
+    let consumed = Arc::new(ArcSwap::from_pointee(HashSet::new()));
+    tokio::spawn(run(log_rx, dir, retention, consumed.clone()));
+    EventLogTx { sender, consumed }
+}
+
+pub struct EventLog;
+impl EventLog {
+    /// Appends to the log of `topic` if it has consumers. The event is queued on the actor without waiting for it.
+    pub async fn append(topic: String, value: Value, log_tx: EventLogTx) {
+        if log_tx.is_consumed(&topic) {
+            send(LogCommand::Append { topic, value }, log_tx.sender).await;
+        }
+    }
+
+    pub async fn create_consumer(topic: String, name: String, start: Option<u64>, log_tx: EventLogTx) -> Result<Result<ConsumerInfo, String>, RecvError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        send(LogCommand::CreateConsumer { topic, name, start, responder: resp_tx }, log_tx.sender).await;
+        resp_rx.await
+    }
+
+    pub async fn get_consumer(topic: String, name: String, log_tx: EventLogTx) -> Result<Option<ConsumerInfo>, RecvError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        send(LogCommand::GetConsumer { topic, name, responder: resp_tx }, log_tx.sender).await;
+        resp_rx.await
+    }
+
+    pub async fn reset_consumer(topic: String, name: String, offset: u64, log_tx: EventLogTx) -> Result<Result<ConsumerInfo, String>, RecvError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        send(LogCommand::ResetConsumer { topic, name, offset, responder: resp_tx }, log_tx.sender).await;
+        resp_rx.await
+    }
+
+    pub async fn delete_consumer(topic: String, name: String, log_tx: EventLogTx) -> Result<bool, RecvError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        send(LogCommand::DeleteConsumer { topic, name, responder: resp_tx }, log_tx.sender).await;
+        resp_rx.await
+    }
+
+    /// Returns up to `count` events from the consumer's position and moves the position past them.
+    pub async fn fetch(topic: String, name: String, count: usize, log_tx: EventLogTx) -> Result<Result<Vec<LoggedEvent>, String>, RecvError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        send(LogCommand::Fetch { topic, name, count, responder: resp_tx }, log_tx.sender).await;
+        resp_rx.await
+    }
+}
+
+async fn send(command: LogCommand, log_tx: Sender<LogCommand>) {
+    match log_tx.send(command).await {
+        Ok(result) => debug!("#event_log success: {:?}", result),
+        Err(err) => error!("#event_log error: {}", err)
+    }
+}
+
+/// The events kept for a topic: the latest ones, from offset `first` on.
+#[derive(Debug, Default)]
+struct TopicLog {
+    first: u64,
+    events: VecDeque<LoggedEvent>,
+    /// Lines in the topic's file, which is compacted once they reach twice the retention.
+    persisted: usize
+}
+
+impl TopicLog {
+    fn head(&self) -> u64 {
+        self.first + self.events.len() as u64
+    }
+}
+
+/// The event log's state. Only topics with consumers are logged, each keeping its latest `retention` events;
+/// consumers behind those skip ahead. With a data directory, each topic's events are appended to
+/// `{dir}/topics/{name}.log` as JSON lines, named by `file_name`, and consumers are kept in `{dir}/consumers.json`.
+pub struct EventLogState {
+    dir: Option<PathBuf>,
+    retention: usize,
+    topics: HashMap<String, TopicLog>,
+    consumers: HashMap<(String, String), Consumer>
+}
+
+impl EventLogState {
+    fn new(dir: Option<PathBuf>, retention: usize) -> EventLogState {
+        EventLogState { dir, retention: retention.max(1), topics: HashMap::new(), consumers: HashMap::new() }
+    }
+
+    pub async fn load(dir: Option<PathBuf>, retention: usize) -> std::io::Result<EventLogState> {
+        let mut state = EventLogState::new(dir.clone(), retention);
+        let dir = match dir {
+            Some(dir) => dir,
+            None => return Ok(state)
+        };
+        fs::create_dir_all(dir.join("topics")).await?;
+
+        match fs::read_to_string(dir.join("consumers.json")).await {
+            Ok(contents) => {
+                let consumers: Vec<Consumer> = serde_json::from_str(&contents).unwrap_or_default();
+                for consumer in consumers {
+                    state.consumers.insert((consumer.topic.clone(), consumer.name.clone()), consumer);
+                }
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err)
+        }
+
+        let mut files = HashSet::new();
+        for topic in state.consumed() {
+            let path = dir.join("topics").join(file_name(&topic));
+            let lines = match fs::read_to_string(&path).await {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err)
+            };
+            let mut log = TopicLog::default();
+            for event in lines.lines().filter_map(|line| serde_json::from_str::<LoggedEvent>(line).ok()) {
+                log.persisted += 1;
+                log.events.push_back(event);
+            }
+            state.trim(&mut log);
+            log.first = log.events.front().map_or(0, |event| event.offset);
+            files.insert(path);
+            state.topics.insert(topic, log);
+        }
+
+        // The logs of topics whose consumers were all deleted are not needed anymore.
+        let mut entries = fs::read_dir(dir.join("topics")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !files.contains(&entry.path()) {
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+        info!("Loaded the event log from {:?}: {} topics, {} consumers", dir, state.topics.len(), state.consumers.len());
+        Ok(state)
+    }
+
+    pub async fn handle(&mut self, cmd: LogCommand) {
+        match cmd {
+            LogCommand::Append { topic, value } => {
+                // The last consumer of the topic may have been deleted since the publish checked for one.
+                if !self.consumers.keys().any(|(consumed, _)| *consumed == topic) {
+                    return;
+                }
+                let mut log = self.topics.remove(&topic).unwrap_or_default();
+                let event = LoggedEvent { offset: log.head(), value };
+                if let Some(dir) = &self.dir {
+                    match append_line(&dir.join("topics").join(file_name(&topic)), &event).await {
+                        Ok(_) => log.persisted += 1,
+                        Err(err) => error!("Error persisting event {} of topic {}: {}", event.offset, topic, err)
+                    }
+                }
+                log.events.push_back(event);
+                self.trim(&mut log);
+                if log.persisted >= self.retention * 2 {
+                    self.compact(&topic, &mut log).await;
+                }
+                self.topics.insert(topic, log);
+            },
+            LogCommand::CreateConsumer { topic, name, start, responder } => {
+                let key = (topic.clone(), name.clone());
+                let result = if self.consumers.contains_key(&key) {
+                    Err(format!("Consumer {} already exists on topic {}", name, topic))
+                } else {
+                    let (first, head) = self.bounds(&topic);
+                    self.consumers.insert(key.clone(), Consumer { topic, name, offset: start.unwrap_or(head).clamp(first, head) });
+                    self.save_consumers().await;
+                    Ok(self.info(&self.consumers[&key]))
+                };
+                info!("Create consumer. Result: {:?}", result);
+                let _ = responder.send(result);
+            },
+            LogCommand::GetConsumer { topic, name, responder } => {
+                let result = self.consumers.get(&(topic, name)).map(|consumer| self.info(consumer));
+                let _ = responder.send(result);
+            },
+            LogCommand::ResetConsumer { topic, name, offset, responder } => {
+                let (first, head) = self.bounds(&topic);
+                let result = match self.consumers.get_mut(&(topic.clone(), name.clone())) {
+                    Some(consumer) => {
+                        consumer.offset = offset.clamp(first, head);
+                        Ok(())
+                    },
+                    None => Err(format!("Consumer {} does not exist on topic {}", name, topic))
+                };
+                let result = match result {
+                    Ok(_) => {
+                        self.save_consumers().await;
+                        Ok(self.info(&self.consumers[&(topic, name)]))
+                    },
+                    Err(err) => Err(err)
+                };
+                info!("Reset consumer. Result: {:?}", result);
+                let _ = responder.send(result);
+            },
+            LogCommand::DeleteConsumer { topic, name, responder } => {
+                let result = self.consumers.remove(&(topic.clone(), name)).is_some();
+                if result {
+                    self.save_consumers().await;
+                    if !self.consumed().contains(&topic) {
+                        self.drop_log(&topic).await;
+                    }
+                }
+                let _ = responder.send(result);
+            },
+            LogCommand::Fetch { topic, name, count, responder } => {
+                let result = match self.consumers.get_mut(&(topic.clone(), name.clone())) {
+                    Some(consumer) => {
+                        let events: Vec<LoggedEvent> = match self.topics.get(&topic) {
+                            Some(log) => {
+                                consumer.offset = consumer.offset.max(log.first);
+                                log.events.iter().skip((consumer.offset - log.first) as usize).take(count).cloned().collect()
+                            },
+                            None => Vec::new()
+                        };
+                        consumer.offset += events.len() as u64;
+                        Ok(events)
+                    },
+                    None => Err(format!("Consumer {} does not exist on topic {}", name, topic))
+                };
+                if matches!(&result, Ok(events) if !events.is_empty()) {
+                    self.save_consumers().await;
+                }
+                let _ = responder.send(result);
+            }
+        }
+    }
+
+    /// Topics with at least one consumer.
+    fn consumed(&self) -> HashSet<String> {
+        self.consumers.keys().map(|(topic, _)| topic.clone()).collect()
+    }
+
+    /// The offsets of the oldest event kept for `topic` and of the next one.
+    fn bounds(&self, topic: &str) -> (u64, u64) {
+        self.topics.get(topic).map_or((0, 0), |log| (log.first, log.head()))
+    }
+
+    /// Drops the events beyond the retention, oldest first.
+    fn trim(&self, log: &mut TopicLog) {
+        while log.events.len() > self.retention {
+            log.events.pop_front();
+            log.first += 1;
+        }
+    }
+
+    fn info(&self, consumer: &Consumer) -> ConsumerInfo {
+        let (_, head) = self.bounds(&consumer.topic);
+        ConsumerInfo {
+            topic: consumer.topic.clone(),
+            name: consumer.name.clone(),
+            offset: consumer.offset,
+            head,
+            lag: head.saturating_sub(consumer.offset)
+        }
+    }
+
+    /// Rewrites the file of `topic` with the events it still keeps.
+    async fn compact(&self, topic: &str, log: &mut TopicLog) {
+        let dir = match &self.dir {
+            Some(dir) => dir.join("topics"),
+            None => return
+        };
+        let mut contents = Vec::new();
+        for event in &log.events {
+            contents.extend(serde_json::to_vec(event).unwrap_or_default());
+            contents.push(b'\n');
+        }
+        let path = dir.join(file_name(topic));
+        let temporary = path.with_extension("log.tmp");
+        let result = match fs::write(&temporary, contents).await {
+            Ok(_) => fs::rename(&temporary, &path).await,
+            Err(err) => Err(err)
+        };
+        match result {
+            Ok(_) => log.persisted = log.events.len(),
+            Err(err) => error!("Error compacting the log of topic {}: {}", topic, err)
+        }
+    }
+
+    async fn drop_log(&mut self, topic: &str) {
+        self.topics.remove(topic);
+        if let Some(dir) = &self.dir {
+            match fs::remove_file(dir.join("topics").join(file_name(topic))).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => error!("Error removing the log of topic {}: {}", topic, err),
+                _ => debug!("Dropped the log of topic {}, which has no consumers left", topic)
+            }
+        }
+    }
+
+    async fn save_consumers(&self) {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return
+        };
+        let consumers: Vec<&Consumer> = self.consumers.values().collect();
+        let temporary = dir.join("consumers.json.tmp");
+        let result = match fs::write(&temporary, serde_json::to_vec(&consumers).unwrap_or_default()).await {
+            Ok(_) => fs::rename(&temporary, dir.join("consumers.json")).await,
+            Err(err) => Err(err)
+        };
+        if let Err(err) = result {
+            error!("Error persisting consumers: {}", err);
+        }
+    }
+}
+
+/// The event log actor. After each command it publishes the topics with consumers, before answering the
+/// next command, so that publishes made once a consumer is created are logged.
+pub async fn run(mut log_rx: Receiver<LogCommand>, dir: Option<PathBuf>, retention: usize, consumed: Arc<ArcSwap<HashSet<String>>>) {
+    let mut state = match EventLogState::load(dir.clone(), retention).await {
+        Ok(state) => state,
+        Err(err) => {
+            error!("Error loading the event log from {:?}, starting empty: {}", dir, err);
+            EventLogState::new(dir, retention)
+        }
+    };
+    consumed.store(Arc::new(state.consumed()));
+    while let Some(cmd) = log_rx.recv().await {
+        let changes_consumers = matches!(cmd, LogCommand::CreateConsumer { .. } | LogCommand::DeleteConsumer { .. });
+        // Creations and deletions reply from `handle`, so the topics are published ahead of them.
+        if let LogCommand::CreateConsumer { topic, .. } = &cmd {
+            let mut topics = state.consumed();
+            topics.insert(topic.clone());
+            consumed.store(Arc::new(topics));
+        }
+        state.handle(cmd).await;
+        if changes_consumers {
+            consumed.store(Arc::new(state.consumed()));
+        }
+    }
+}
+
+async fn append_line(path: &Path, event: &LoggedEvent) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path).await?;
+    let mut line = serde_json::to_vec(event).unwrap_or_default();
+    line.push(b'\n');
+    file.write_all(&line).await
+}
+
+/// Bytes of a topic kept, hex encoded, in the name of its file.
+const FILE_NAME_PREFIX: usize = 64;
+
+/// Topics may contain any character and be of any length, so their file names are the hex encoding of their
+/// first bytes followed by a hash of the whole topic, well within the 255 bytes file systems allow.
+fn file_name(topic: &str) -> String {
+    let prefix: String = topic.bytes().take(FILE_NAME_PREFIX).map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{:016x}.log", prefix, fnv1a(topic.as_bytes()))
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher is the same across builds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("a/b"), format!("612f62-{:016x}.log", fnv1a(b"a/b")));
+        let long = "x".repeat(1000);
+        assert!(file_name(&long).len() < 255);
+        assert_ne!(file_name(&long), file_name(&"x".repeat(999)));
+    }
+
+    #[tokio::test]
+    async fn test_consumers() {
+        let log_tx = spawn(None, 100);
+        let (topic, name) = (String::from("topic"), String::from("workers"));
+
+        EventLog::append(topic.clone(), json!(0), log_tx.clone()).await;
+        let created = EventLog::create_consumer(topic.clone(), name.clone(), Some(0), log_tx.clone()).await.unwrap().unwrap();
+        assert_eq!((created.offset, created.head, created.lag), (0, 0, 0));
+        assert!(log_tx.is_consumed(&topic));
+        assert!(EventLog::create_consumer(topic.clone(), name.clone(), None, log_tx.clone()).await.unwrap().is_err());
+        EventLog::append(topic.clone(), json!(1), log_tx.clone()).await;
+        EventLog::append(topic.clone(), json!(2), log_tx.clone()).await;
+
+        let events = EventLog::fetch(topic.clone(), name.clone(), 1, log_tx.clone()).await.unwrap().unwrap();
+        assert_eq!(events, vec![LoggedEvent { offset: 0, value: json!(1) }]);
+        let events = EventLog::fetch(topic.clone(), name.clone(), 10, log_tx.clone()).await.unwrap().unwrap();
+        assert_eq!(events, vec![LoggedEvent { offset: 1, value: json!(2) }]);
+        assert_eq!(EventLog::get_consumer(topic.clone(), name.clone(), log_tx.clone()).await.unwrap().unwrap().lag, 0);
+
+        let reset = EventLog::reset_consumer(topic.clone(), name.clone(), 1, log_tx.clone()).await.unwrap().unwrap();
+        assert_eq!(reset.lag, 1);
+        assert!(EventLog::delete_consumer(topic.clone(), name.clone(), log_tx.clone()).await.unwrap());
+        assert!(EventLog::fetch(topic.clone(), name, 1, log_tx.clone()).await.unwrap().is_err());
+        assert!(!log_tx.is_consumed(&topic));
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
+        let log_tx = spawn(None, 2);
+        let (topic, name) = (String::from("topic"), String::from("slow"));
+        EventLog::create_consumer(topic.clone(), name.clone(), None, log_tx.clone()).await.unwrap().unwrap();
+        for value in 0..5 {
+            EventLog::append(topic.clone(), json!(value), log_tx.clone()).await;
+        }
+
+        // The consumer fell behind the retention and skips to the oldest event kept.
+        let events = EventLog::fetch(topic.clone(), name.clone(), 10, log_tx.clone()).await.unwrap().unwrap();
+        assert_eq!(events, vec![LoggedEvent { offset: 3, value: json!(3) }, LoggedEvent { offset: 4, value: json!(4) }]);
+        let reset = EventLog::reset_consumer(topic, name, 0, log_tx).await.unwrap().unwrap();
+        assert_eq!((reset.offset, reset.head), (3, 5));
+    }
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let dir = std::env::temp_dir().join(format!("pub-sub-rust-{}", Uuid::new_v4()));
+        let (topic, name) = (String::from("a/b"), String::from("reader"));
+        {
+            let (log_rx_tx, log_rx) = mpsc::channel::<LogCommand>(32);
+            let consumed = Arc::new(ArcSwap::from_pointee(HashSet::new()));
+            let log_tx = EventLogTx { sender: log_rx_tx, consumed: consumed.clone() };
+            let actor = tokio::spawn(run(log_rx, Some(dir.clone()), 2, consumed));
+            EventLog::create_consumer(topic.clone(), name.clone(), None, log_tx.clone()).await.unwrap().unwrap();
+            EventLog::create_consumer(String::from("gone"), name.clone(), None, log_tx.clone()).await.unwrap().unwrap();
+            for value in 1..=5 {
+                EventLog::append(topic.clone(), json!({ "n": value }), log_tx.clone()).await;
+            }
+            EventLog::append(String::from("gone"), json!(1), log_tx.clone()).await;
+            EventLog::fetch(topic.clone(), name.clone(), 1, log_tx.clone()).await.unwrap().unwrap();
+            EventLog::delete_consumer(String::from("gone"), name.clone(), log_tx.clone()).await.unwrap();
+            drop(log_tx);
+            actor.await.unwrap();
+        }
+        let files: Vec<_> = std::fs::read_dir(dir.join("topics")).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        assert!(std::fs::read_to_string(dir.join("topics").join(file_name(&topic))).unwrap().lines().count() < 5);
+
+        let log_tx = spawn(Some(dir.clone()), 2);
+        let events = EventLog::fetch(topic, name, 10, log_tx).await.unwrap().unwrap();
+        assert_eq!(events, vec![LoggedEvent { offset: 4, value: json!({ "n": 5 }) }]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -1,16 +1,53 @@
-use crate::serialize::{SocketRequest};
-use crate::store::{Client, Store, Subscribers};
-use crate::command::Command;
-use tokio::sync::mpsc::Sender;
-use serde_json::{json};
-use warp::ws::Message;
+use crate::serialize::{DeadLetter, DirectEnvelope, ErrorCode, EventFormat, EventKind, Hello, Usage, KeyspaceEvent, PresenceAction, PresenceEvent, RegisterResponse, ClientInfo, ScanQuery, SnapshotQuery, TopicInfo, RequestEnvelope, SocketError, SocketEvent, ReplicationQuery, SocketRequest, StoredValue, WillOptions};
+use crate::config::config;
+use crate::shard::StoreShards;
+use crate::subscriptions::SubscriptionsTx;
+use crate::codec::{Encoding, Frames};
+use crate::protocol::Session;
+use crate::replication::{self, Backlog, Replication, ReplicationCommand, StreamLine};
+use crate::snapshot::{self, SnapshotLine};
+use crate::cluster::{Cluster, ClusterCommand, ForwardedEvent, PeerTopics};
+use crate::event_log::{EventLog, EventLogTx};
+use std::{collections::HashMap, time::{Duration, Instant}};
+use crate::store::{Client, Inboxes, PendingEvent, QueueGroups, Retained, ScanFilter, Store, Subscribers, DEFAULT_SCAN_COUNT};
+use crate::command::{Command, PathUpdate};
+use crate::document::diff;
+use tokio::sync::mpsc::{self, Sender, UnboundedSender};
+use tokio::time;
+use bytes::Bytes;
+use warp::hyper::Body;
+use serde::Serialize;
+use serde_json::{json, Value};
+use warp::http::header::SEC_WEBSOCKET_PROTOCOL;
 use warp::{Rejection, hyper::StatusCode};
-use crate::Reply;
-use crate::ws;
+use warp::Reply;
+use crate::ws::{self, Channels, Handshake};
 use crate::serialize::RequestAction;
 use log::{warn, error};
 use uuid::Uuid;
 
+/// Topics under this prefix carry the store's keyspace events and cannot be published to.
+const KEYSPACE_PREFIX: &str = "__keyspace__/";
+
+/// Rejection of a request that broke a quota, reported to the client with its error code.
+#[derive(Debug)]
+pub struct Refused {
+    pub error: ErrorCode,
+    pub message: String
+}
+
+impl warp::reject::Reject for Refused {}
+
+fn refused(error: ErrorCode, message: String) -> Rejection {
+    warp::reject::custom(Refused { error, message })
+}
+
+/// Rejection of an admin or cluster request that did not carry its token.
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
 pub async fn register_handler(clients_tx: Sender<Command<Client>>) -> Result<impl Reply, Rejection> {
     // TODO: generate uuid and return to the client
     let user_id = Uuid::new_v4();
@@ -18,14 +55,21 @@ pub async fn register_handler(clients_tx: Sender<Command<Client>>) -> Result<imp
     // TODO: does this sender need to be populated?
     let client = Client {
         user_id: user_id.to_string(),
-        sender: None
+        sender: None,
+        ..Default::default()
     };
 
-    match Client::set_client(client, clients_tx.clone()).await {
-        Ok(_) => {
-            Ok(json!({
-                "url": format!("ws://127.0.0.1:8000/ws/{}", user_id),
-            }).to_string())
+    let max = config().quotas.max_clients;
+    match Client::register_client(client, max, clients_tx.clone()).await {
+        Ok(true) => {
+            Ok(warp::reply::with_status(json!(RegisterResponse {
+                url: format!("ws://127.0.0.1:{}/ws/{}", config().port, user_id),
+            }).to_string(), StatusCode::OK))
+        },
+        Ok(false) => {
+            warn!("Refusing a registration, {} clients are registered", max.unwrap_or_default());
+            let error = json!({ "error": ErrorCode::TooManyClients, "message": format!("At most {} clients may be registered", max.unwrap_or_default()) });
+            Ok(warp::reply::with_status(error.to_string(), StatusCode::SERVICE_UNAVAILABLE))
         },
         Err(_) => Err(warp::reject::reject())
     }
@@ -38,12 +82,30 @@ pub async fn unregister_handler(user_id: String, clients_tx: Sender<Command<Clie
     }
 }
 
-pub async fn ws_handler(ws: warp::ws::Ws, user_id: String, subscriptions_tx: Sender<Command<Client>>, clients_tx: Sender<Command<Client>>, store_tx: Sender<Command<String>>) -> Result<impl Reply, Rejection> {
+/// Opens the socket of a registered client. Frames are JSON text unless the client offers the
+/// MessagePack subprotocol, which the response then confirms. Messages are compressed with
+/// permessage-deflate when the client offers it.
+pub async fn ws_handler(handshake: Handshake, user_id: String, protocols: Option<String>, will: WillOptions, channels: Channels) -> Result<impl Reply, Rejection> {
     println!("ws handler: {}", user_id.to_string().clone());
-    let client = Client::get_client(user_id.clone(), clients_tx.clone()).await;
+    let client = Client::get_client(user_id.clone(), channels.clients_tx.clone()).await;
+    let negotiated = Encoding::negotiate(protocols.as_deref());
 
     match client {
-        Ok(Some(client)) => Ok(ws.on_upgrade(move |socket| ws::client_connection(socket, user_id, client, subscriptions_tx, clients_tx, store_tx))),
+        Ok(Some(mut client)) => {
+            let will = ws::will_request(&user_id, will);
+            // The will is held to the quotas now, and to the rate limits only if it is published.
+            if let Some((error, message)) = will.as_ref().and_then(|request| request.exceeds(&config().quotas)) {
+                debug!("Refusing the will of client {}: {}", user_id, message);
+                let error = json!({ "error": error, "message": message });
+                return Ok(warp::reply::with_status(error.to_string(), StatusCode::BAD_REQUEST).into_response());
+            }
+            client.encoding = negotiated.unwrap_or_default();
+            let headers = negotiated.map(|encoding| (SEC_WEBSOCKET_PROTOCOL.as_str(), encoding.subprotocol())).into_iter().collect();
+            match handshake.on_upgrade(headers, move |socket| ws::client_connection(socket, user_id, client, will, channels)) {
+                Some(response) => Ok(response),
+                None => Err(warp::reject::reject())
+            }
+        },
         _ => Err(warp::reject::not_found())
     }
 }
@@ -52,87 +114,301 @@ pub async fn health_handler() -> Result<StatusCode, Rejection> {
     Ok(StatusCode::OK)
 }
 
-pub async fn publish_handler(body: SocketRequest, user_id: String, subscriptions_tx: Sender<Command<Client>>, store_tx: Sender<Command<String>>) -> Result<impl Reply, Rejection> {
-    if let Some(message) = body.message {
-        match body.action {
-            RequestAction::Set => {
-                match Store::set(body.topic.clone(), message.clone(), store_tx).await {
-                    Ok(_) => alert_subscribers(body.topic, message, user_id, subscriptions_tx).await,
-                    Err(_) => Err(warp::reject::reject())
-                }
-            },
-            RequestAction::Unset => {
-                match Store::unset(body.topic.clone(), store_tx).await {
-                    Ok(_) => alert_subscribers(body.topic, String::from(""), user_id, subscriptions_tx).await,
-                    Err(_) => Err(warp::reject::reject())
-                }
-            },
-            RequestAction::AddToCollection => {
-                match Store::add_to_collection(body.topic, message.clone(), store_tx).await {
-                    Ok(_) => Ok(StatusCode::OK),
-                    Err(_) => Err(warp::reject::reject())
-                }
-            },
-            RequestAction::RemoveFromCollection => {
-                match Store::remove_value_from_collection(body.topic, message.clone(), store_tx).await {
-                    Ok(_) => Ok(StatusCode::OK),
-                    Err(_) => Err(warp::reject::reject())
+pub async fn publish_handler(body: SocketRequest, user_id: String, subscriptions_tx: SubscriptionsTx, store_tx: StoreShards, log_tx: EventLogTx, retained_tx: Sender<Command<Value>>, cluster_tx: Sender<ClusterCommand>) -> Result<impl Reply, Rejection> {
+    if body.topic.starts_with(KEYSPACE_PREFIX) {
+        error!("Error: {} is a reserved keyspace topic", body.topic);
+        return Err(warp::reject::reject());
+    }
+    if replication::is_replica() {
+        debug!("{:?} on {} refused, this node is a read-only replica", body.action, body.topic);
+        return Err(refused(ErrorCode::ReadOnlyReplica, String::from("This node is a read-only replica, write to its primary")));
+    }
+    let retain = body.retain.unwrap_or(false);
+    match body.action {
+        RequestAction::Set => {
+            let message = required(body.message)?;
+            match Store::set(body.topic.clone(), message.clone(), store_tx).await {
+                Ok(previous) => {
+                    if retain {
+                        retain_document(body.topic.clone(), message.clone(), retained_tx).await;
+                    }
+                    alert_document_subscribers(body.topic, previous, message, user_id, subscriptions_tx, log_tx, cluster_tx).await
+                },
+                Err(_) => Err(warp::reject::reject())
+            }
+        },
+        RequestAction::Unset => {
+            match Store::unset(body.topic.clone(), store_tx).await {
+                Ok(previous) => {
+                    if retain {
+                        retain_document(body.topic.clone(), Value::Null, retained_tx).await;
+                    }
+                    alert_document_subscribers(body.topic, previous, Value::Null, user_id, subscriptions_tx, log_tx, cluster_tx).await
+                },
+                Err(_) => Err(warp::reject::reject())
+            }
+        },
+        RequestAction::SetPath | RequestAction::MergePath | RequestAction::DeletePath => {
+            let path = required(body.path)?;
+            let update = match body.action {
+                RequestAction::SetPath => PathUpdate::Set(required(body.message)?),
+                RequestAction::MergePath => PathUpdate::Merge(required(body.message)?),
+                _ => PathUpdate::Delete
+            };
+            match Store::update(body.topic.clone(), path, update, store_tx).await {
+                Ok(Ok((previous, document))) => {
+                    if retain {
+                        retain_document(body.topic.clone(), document.clone(), retained_tx).await;
+                    }
+                    alert_document_subscribers(body.topic, previous, document, user_id, subscriptions_tx, log_tx, cluster_tx).await
+                },
+                Ok(Err(err)) => {
+                    error!("Error updating {}: {}", body.topic, err);
+                    Err(warp::reject::reject())
+                },
+                Err(_) => Err(warp::reject::reject())
+            }
+        },
+        RequestAction::AddToCollection => {
+            match Store::add_to_collection(body.topic, required(body.message)?, store_tx).await {
+                Ok(Ok(_)) => Ok(StatusCode::OK),
+                Ok(Err(message)) => Err(refused(ErrorCode::CollectionFull, message)),
+                Err(_) => Err(warp::reject::reject())
+            }
+        },
+        RequestAction::RemoveFromCollection => {
+            match Store::remove_value_from_collection(body.topic, required(body.message)?, store_tx).await {
+                Ok(_) => Ok(StatusCode::OK),
+                Err(_) => Err(warp::reject::reject())
+            }
+        },
+        RequestAction::PushToList => {
+            let message = required(body.message)?;
+            match Store::push_to_list(body.topic.clone(), message.clone(), body.front.unwrap_or(false), store_tx).await {
+                Ok(Ok(_)) => notify_subscribers(body.action, body.topic, message, user_id, subscriptions_tx, log_tx, cluster_tx).await,
+                Ok(Err(message)) => Err(refused(ErrorCode::CollectionFull, message)),
+                Err(_) => Err(warp::reject::reject())
+            }
+        },
+        RequestAction::PopFromList => {
+            match Store::pop_from_list(body.topic.clone(), body.front.unwrap_or(false), store_tx).await {
+                Ok(Some(value)) => notify_subscribers(body.action, body.topic, value, user_id, subscriptions_tx, log_tx, cluster_tx).await,
+                Ok(None) => Ok(StatusCode::OK),
+                Err(_) => Err(warp::reject::reject())
+            }
+        },
+        RequestAction::SetField => {
+            let field = required(body.field)?;
+            let message = required(body.message)?;
+            match Store::set_field(body.topic.clone(), field.clone(), message.clone(), store_tx).await {
+                Ok(Ok(_)) => notify_subscribers(body.action, body.topic, json!({ field: message }), user_id, subscriptions_tx, log_tx, cluster_tx).await,
+                Ok(Err(message)) => Err(refused(ErrorCode::CollectionFull, message)),
+                Err(_) => Err(warp::reject::reject())
+            }
+        },
+        RequestAction::DeleteField => {
+            let field = required(body.field)?;
+            match Store::delete_field(body.topic.clone(), field.clone(), store_tx).await {
+                Ok(Some(_)) => notify_subscribers(body.action, body.topic, json!(field), user_id, subscriptions_tx, log_tx, cluster_tx).await,
+                Ok(None) => Ok(StatusCode::OK),
+                Err(_) => Err(warp::reject::reject())
+            }
+        },
+        RequestAction::AddToSortedSet => {
+            let message = required(body.message)?;
+            let score = required(body.score)?;
+            match Store::add_to_sorted_set(body.topic.clone(), message.clone(), score, store_tx).await {
+                Ok(Ok(true)) => notify_subscribers(body.action, body.topic, json!([message, score]), user_id, subscriptions_tx, log_tx, cluster_tx).await,
+                Ok(Ok(false)) => Ok(StatusCode::OK),
+                Ok(Err(message)) => Err(refused(ErrorCode::CollectionFull, message)),
+                Err(_) => Err(warp::reject::reject())
+            }
+        },
+        _ => {
+            error!("Error: publish_handler must be called with a request that modifies the store");
+            Err(warp::reject::reject())
+        }
+    }
+}
+
+/// Answers a read request by sending the result back over the requesting client's socket.
+/// Replicas only mirror strings and collections, so they refuse reads of lists, hashes and sorted sets.
+pub async fn query_handler(body: SocketRequest, user_id: String, clients_tx: Sender<Command<Client>>, store_tx: StoreShards) -> Result<impl Reply, Rejection> {
+    let replicated = !matches!(body.action, RequestAction::GetListRange | RequestAction::GetField | RequestAction::GetSortedSetRangeByRank | RequestAction::GetSortedSetRangeByScore);
+    if !replicated && replication::is_replica() {
+        debug!("{:?} on {} refused, this node is a replica without lists, hashes and sorted sets", body.action, body.topic);
+        return Err(refused(ErrorCode::ReadOnlyReplica, String::from("Lists, hashes and sorted sets are not replicated, read them from the primary")));
+    }
+    let result = match body.action {
+        RequestAction::Get => {
+            Store::get(body.topic.clone(), store_tx).await.map(|value| json!(value))
+        },
+        RequestAction::GetCollection => {
+            Store::get_collection(body.topic.clone(), store_tx).await.map(|values| json!(values))
+        },
+        RequestAction::GetListRange => {
+            Store::get_list_range(body.topic.clone(), body.start.unwrap_or(0), body.stop.unwrap_or(-1), store_tx).await.map(|values| json!(values))
+        },
+        RequestAction::GetField => {
+            Store::get_field(body.topic.clone(), required(body.field)?, store_tx).await.map(|value| json!(value))
+        },
+        RequestAction::GetSortedSetRangeByRank => {
+            Store::get_sorted_set_range_by_rank(body.topic.clone(), body.start.unwrap_or(0), body.stop.unwrap_or(-1), store_tx).await.map(|values| json!(values))
+        },
+        RequestAction::GetSortedSetRangeByScore => {
+            Store::get_sorted_set_range_by_score(body.topic.clone(), body.min.unwrap_or(f64::NEG_INFINITY), body.max.unwrap_or(f64::INFINITY), store_tx).await.map(|values| json!(values))
+        },
+        RequestAction::Scan => {
+            let filter = ScanFilter { after: body.cursor, prefix: body.topic.clone(), pattern: body.pattern };
+            Store::scan(filter, body.count.unwrap_or(DEFAULT_SCAN_COUNT), store_tx).await.map(|page| json!(page))
+        },
+        _ => {
+            error!("Error: query_handler must be called with a read request");
+            return Err(warp::reject::reject())
+        }
+    };
+
+    match result {
+        Ok(value) => {
+            let event = SocketEvent { action: body.action, topic: body.topic, value };
+            send_to_client(user_id, json!(event).to_string(), clients_tx).await
+        },
+        Err(_) => Err(warp::reject::reject())
+    }
+}
+
+fn required<T>(value: Option<T>) -> Result<T, Rejection> {
+    value.ok_or_else(warp::reject::reject)
+}
+
+async fn send_to_client(user_id: String, value: String, clients_tx: Sender<Command<Client>>) -> Result<StatusCode, Rejection> {
+    match Client::get_client(user_id.clone(), clients_tx).await {
+        Ok(Some(Client { sender: Some(sender), encoding, .. })) => {
+            match sender.send(Ok(encoding.encode(value))) {
+                Ok(_) => Ok(StatusCode::OK),
+                Err(_) => {
+                    warn!("Error sending reply to client: {:?}", user_id);
+                    Err(warp::reject::reject())
                 }
-            },
-            _ => {
-                error!("Error: publish_handler must be called with a request of either Set, Remove, or Add");
-                Err(warp::reject::reject())
             }
+        },
+        _ => {
+            warn!("Sender not found on client {}", user_id);
+            Err(warp::reject::reject())
         }
+    }
+}
+
+/// Tells a client why its request could not be completed.
+pub async fn send_error(user_id: String, error: ErrorCode, action: RequestAction, topic: String, message: String, clients_tx: Sender<Command<Client>>) -> Result<StatusCode, Rejection> {
+    let error = SocketError { error, action, topic, message };
+    send_to_client(user_id, json!(error).to_string(), clients_tx).await
+}
+
+/// Keeps `document` as the retained message of `topic`, or clears it when null.
+async fn retain_document(topic: String, document: Value, retained_tx: Sender<Command<Value>>) {
+    let result = if document.is_null() {
+        Retained::clear(topic.clone(), retained_tx).await
     } else {
-        Err(warp::reject::reject())
+        Retained::set(topic.clone(), document, retained_tx).await
+    };
+    if result.is_err() {
+        error!("Error retaining the message of topic {}", topic);
     }
 }
 
-async fn alert_subscribers(topic: String, value: String, user_id: String, subscriptions_tx: Sender<Command<Client>>) -> Result<StatusCode, Rejection> {
-    match Subscribers::get_subscribers(topic.clone(), subscriptions_tx).await {
-        Ok(Some(subscribers)) => {
-            for client in subscribers {
-                if client.user_id == user_id {
-                    continue;
-                }
-                match client.sender {
-                    Some(sender) => {
-                        match sender.send(Ok(Message::text(value.clone()))) {
-                            Ok(_) => debug!("Subscriber alerted: {:?}", &client.user_id),
-                            Err(_) => warn!("Error sending update to subscriber: {:?}", &client.user_id)
-                        }
-                    }
-                    None => {
-                        warn!("Sender not found on client {}", &client.user_id)
-                    }
-                }
+
+async fn notify_subscribers(action: RequestAction, topic: String, value: Value, user_id: String, subscriptions_tx: SubscriptionsTx, log_tx: EventLogTx, cluster_tx: Sender<ClusterCommand>) -> Result<StatusCode, Rejection> {
+    let event = json!(SocketEvent { action, topic: topic.clone(), value });
+    EventLog::append(topic.clone(), event.clone(), log_tx).await;
+    forward_to_peers(ForwardedEvent { topic: topic.clone(), kind: EventKind::Change, value: event.to_string(), patch: None, user_id: user_id.clone() }, cluster_tx).await;
+    alert_subscribers(topic, EventKind::Change, event.to_string(), None, user_id, subscriptions_tx).await
+}
+
+/// Alerts subscribers of a document with either its new value or the patch from `previous`, per their subscription format.
+async fn alert_document_subscribers(topic: String, previous: Option<Value>, document: Value, user_id: String, subscriptions_tx: SubscriptionsTx, log_tx: EventLogTx, cluster_tx: Sender<ClusterCommand>) -> Result<StatusCode, Rejection> {
+    let patch = diff(&previous.unwrap_or(Value::Null), &document);
+    EventLog::append(topic.clone(), document.clone(), log_tx).await;
+    forward_to_peers(ForwardedEvent { topic: topic.clone(), kind: EventKind::Document, value: document.to_string(), patch: Some(patch.to_string()), user_id: user_id.clone() }, cluster_tx).await;
+    alert_subscribers(topic, EventKind::Document, document.to_string(), Some(patch.to_string()), user_id, subscriptions_tx).await
+}
+
+async fn forward_to_peers(event: ForwardedEvent, cluster_tx: Sender<ClusterCommand>) {
+    let topic = event.topic.clone();
+    match Cluster::forward(event, cluster_tx).await {
+        Ok(0) => {},
+        Ok(count) => debug!("Forwarded topic {} to {} peers", topic, count),
+        Err(_) => error!("Error forwarding topic {} to the cluster", topic)
+    }
+}
+
+/// Sends an event of `kind` to one subscriber, as a patch if it asked for those and enveloped with its kind if its
+/// subscription is reliable. Unless enveloped, the frame is shared with the other subscribers of the same encoding.
+async fn deliver<'a>(client: &Client, topic: &str, kind: EventKind, value: &mut Frames<'a>, patch: Option<&mut Frames<'a>>) {
+    let (kind, frames) = match (client.options.format, patch) {
+        (EventFormat::Patch, Some(patch)) => (EventKind::Patch, patch),
+        _ => (kind, value)
+    };
+    let message = if client.options.reliable && client.sender.is_some() {
+        client.encoding.encode(client.track_delivery(topic, kind, String::from(frames.text())).await)
+    } else {
+        frames.get(client.encoding)
+    };
+    match &client.sender {
+        Some(sender) => {
+            match sender.send(Ok(message)) {
+                Ok(_) => debug!("Subscriber alerted: {:?}", &client.user_id),
+                Err(_) => warn!("Error sending update to subscriber: {:?}", &client.user_id)
+            }
+        }
+        None => {
+            warn!("Sender not found on client {}", &client.user_id)
+        }
+    }
+}
+
+async fn alert_subscribers(topic: String, kind: EventKind, value: String, patch: Option<String>, user_id: String, subscriptions_tx: SubscriptionsTx) -> Result<StatusCode, Rejection> {
+    match Subscribers::get_subscribers(&topic, &subscriptions_tx) {
+        Some(subscribers) => {
+            let subscribers = subscribers.iter().filter(|client| client.user_id != user_id).collect();
+            let mut value = Frames::new(&value);
+            let mut patch = patch.as_deref().map(Frames::new);
+            let cursors = subscriptions_tx.group_cursors(&topic);
+            for client in QueueGroups::select(subscribers, cursors.as_deref()) {
+                deliver(client, &topic, kind, &mut value, patch.as_mut()).await;
             }
             Ok(StatusCode::OK)
         },
-        Ok(None) => {
+        None => {
             debug!("No clients found subscribed to topic {}, skipping", topic.clone());
             Ok(StatusCode::OK)
         }
-        Err(_) => {
-            error!("Error getting subscribers.");
-            Err(warp::reject::reject())
-        }
     }
 }
 
-pub async fn subscription_handler(body: SocketRequest, user_id: String, subscriptions_tx: Sender<Command<Client>>, clients_tx: Sender<Command<Client>>) -> Result<impl Reply, Rejection> {
+pub async fn subscription_handler(body: SocketRequest, user_id: String, subscriptions_tx: SubscriptionsTx, clients_tx: Sender<Command<Client>>, retained_tx: Sender<Command<Value>>, cluster_tx: Sender<ClusterCommand>) -> Result<impl Reply, Rejection> {
     let client = Client::get_client(user_id, clients_tx).await;
-    if let Ok(Some(client)) = client {
+    if let Ok(Some(mut client)) = client {
         match body.action {
             RequestAction::Subscribe => {
-                match Subscribers::add_subscriber(body.topic.clone(), client, subscriptions_tx).await {
+                client.options.format = body.format.unwrap_or_default();
+                client.options.group = body.group;
+                client.options.reliable = body.reliable.unwrap_or(false);
+                if body.presence.unwrap_or(false) {
+                    client.options.presence = Some(body.message.unwrap_or(Value::Null));
+                }
+                match Subscribers::add_subscriber(body.topic.clone(), client.clone(), subscriptions_tx.clone()).await {
                     Ok(result) => {
                         if result {
                             debug!("Subscribing to topic {}", body.topic.clone());
-                            return Ok(StatusCode::OK)
+                            Cluster::announce(cluster_tx).await;
+                            if let Ok(Some(retained)) = Retained::get(body.topic.clone(), retained_tx).await {
+                                let (value, patch) = (retained.to_string(), diff(&Value::Null, &retained).to_string());
+                                deliver(&client, &body.topic, EventKind::Document, &mut Frames::new(&value), Some(&mut Frames::new(&patch))).await;
+                            }
+                            announce_presence(PresenceAction::Join, body.topic, client, subscriptions_tx).await
                         } else {
-                            return Err(warp::reject::reject())
+                            Err(warp::reject::reject())
                         }
 
                     }
@@ -140,10 +416,18 @@ pub async fn subscription_handler(body: SocketRequest, user_id: String, subscrip
                 }
             },
             RequestAction::Unsubscribe => {
-                match Subscribers::remove_subscriber(body.topic.clone(), client, subscriptions_tx).await {
-                    Ok(_) => {
+                let subscription = Subscribers::get_subscribers(&body.topic, &subscriptions_tx)
+                    .and_then(|subscribers| subscribers.iter().find(|subscriber| **subscriber == client).cloned());
+                match Subscribers::remove_subscriber(body.topic.clone(), client, subscriptions_tx.clone()).await {
+                    Ok(removed) => {
                         debug!("Unsubscribing to topic {}", body.topic.clone());
-                        Ok(StatusCode::OK)
+                        if removed {
+                            Cluster::announce(cluster_tx).await;
+                        }
+                        match subscription {
+                            Some(subscription) if removed => announce_presence(PresenceAction::Leave, body.topic, subscription, subscriptions_tx).await,
+                            _ => Ok(StatusCode::OK)
+                        }
                     },
                     Err(_) => Err(warp::reject::reject())
                 }
@@ -158,13 +442,567 @@ pub async fn subscription_handler(body: SocketRequest, user_id: String, subscrip
     }
 }
 
+/// Answers a client's Hello with the version and features both sides settled on, which then apply
+/// to the rest of its connection.
+pub async fn hello_handler(body: SocketRequest, user_id: String, clients_tx: Sender<Command<Client>>) -> Result<Session, Rejection> {
+    let hello: Hello = match body.message.map(serde_json::from_value) {
+        Some(Ok(hello)) => hello,
+        _ => {
+            send_error(user_id, ErrorCode::UnsupportedVersion, body.action, body.topic, String::from("A Hello declares its version in its message"), clients_tx).await?;
+            return Err(warp::reject::reject());
+        }
+    };
+    match Session::negotiate(hello) {
+        Ok(session) => {
+            info!("Client {} speaks protocol version {} with {:?}", user_id, session.version, session.features);
+            let event = SocketEvent { action: body.action, topic: body.topic, value: json!(session.reply()) };
+            send_to_client(user_id, json!(event).to_string(), clients_tx).await?;
+            Ok(session)
+        },
+        Err(reply) => {
+            let message = format!("Only protocol versions {} to {} are supported", reply.min_version, reply.max_version);
+            send_error(user_id, ErrorCode::UnsupportedVersion, body.action, body.topic, message, clients_tx).await?;
+            Err(warp::reject::reject())
+        }
+    }
+}
+
+/// Replies to the requesting client with what it currently holds against the server's quotas.
+pub async fn usage_handler(body: SocketRequest, user_id: String, subscriptions: usize, clients_tx: Sender<Command<Client>>) -> Result<impl Reply, Rejection> {
+    let pending_events = match Client::get_client(user_id.clone(), clients_tx.clone()).await {
+        Ok(Some(client)) => client.pending.lock().await.len(),
+        Ok(None) => 0,
+        Err(_) => return Err(warp::reject::reject())
+    };
+    let usage = Usage { subscriptions, max_subscriptions: config().rate_limits.max_subscriptions, pending_events, quotas: config().quotas };
+    let event = SocketEvent { action: body.action, topic: body.topic, value: json!(usage) };
+    send_to_client(user_id, json!(event).to_string(), clients_tx).await
+}
+
+/// Replies to the requesting client with the members of a topic that subscribed with presence enabled.
+pub async fn presence_handler(body: SocketRequest, user_id: String, subscriptions_tx: SubscriptionsTx, clients_tx: Sender<Command<Client>>) -> Result<impl Reply, Rejection> {
+    let members: Vec<Value> = Subscribers::get_subscribers(&body.topic, &subscriptions_tx).unwrap_or_default().iter()
+        .filter_map(|subscriber| subscriber.options.presence.clone().map(|meta| json!({ "user_id": subscriber.user_id, "meta": meta })))
+        .collect();
+    let event = SocketEvent { action: body.action, topic: body.topic, value: json!(members) };
+    send_to_client(user_id, json!(event).to_string(), clients_tx).await
+}
+
+/// Delivers a message to the single client named by `topic` and replies to the sender with the delivery status.
+pub async fn direct_message_handler(body: SocketRequest, user_id: String, clients_tx: Sender<Command<Client>>) -> Result<impl Reply, Rejection> {
+    let envelope = DirectEnvelope { from: user_id.clone(), message: body.message.unwrap_or(Value::Null) };
+    let delivered = match Client::get_client(body.topic.clone(), clients_tx.clone()).await {
+        Ok(Some(Client { sender: Some(sender), encoding, .. })) => sender.send(Ok(encoding.encode(json!(envelope).to_string()))).is_ok(),
+        Ok(_) => false,
+        Err(_) => return Err(warp::reject::reject())
+    };
+    if !delivered {
+        debug!("Recipient {} is offline", body.topic);
+    }
+    let event = SocketEvent { action: body.action, topic: body.topic, value: json!({ "delivered": delivered }) };
+    send_to_client(user_id, json!(event).to_string(), clients_tx).await
+}
+
+/// Delivers a request to one subscriber of the topic, or all of them with `fanout`, through an ephemeral reply inbox.
+/// The requester is told the inbox id straight away and later receives the first Reply, or a Timeout error.
+pub async fn request_handler(body: SocketRequest, user_id: String, subscriptions_tx: SubscriptionsTx, clients_tx: Sender<Command<Client>>, inbox_tx: Sender<Command<String>>) -> Result<impl Reply, Rejection> {
+    let responders: Vec<Client> = Subscribers::get_subscribers(&body.topic, &subscriptions_tx).unwrap_or_default().iter()
+        .filter(|subscriber| subscriber.user_id != user_id && subscriber.sender.is_some())
+        .cloned()
+        .collect();
+    if responders.is_empty() {
+        return send_error(user_id, ErrorCode::NoResponders, body.action, body.topic, String::from("No subscribers to handle the request"), clients_tx).await;
+    }
+
+    let inbox = Uuid::new_v4();
+    if Inboxes::open(inbox.to_string(), user_id.clone(), inbox_tx.clone()).await.is_err() {
+        return Err(warp::reject::reject());
+    }
+    let envelope = RequestEnvelope { from: user_id.clone(), reply_to: inbox.to_string(), topic: body.topic.clone(), message: body.message.unwrap_or(Value::Null) };
+    let text = json!(envelope).to_string();
+    let targets = if body.fanout.unwrap_or(false) {
+        responders
+    } else {
+        let index = (inbox.as_u128() % responders.len() as u128) as usize;
+        vec![responders[index].clone()]
+    };
+    for responder in &targets {
+        if let Some(sender) = &responder.sender {
+            if sender.send(Ok(responder.encoding.encode(text.clone()))).is_err() {
+                warn!("Error sending request to subscriber: {:?}", &responder.user_id);
+            }
+        }
+    }
+
+    let timeout = body.timeout.map(Duration::from_millis).unwrap_or(config().request_timeout);
+    let (requester, topic, action) = (user_id.clone(), body.topic.clone(), body.action);
+    let timeout_clients_tx = clients_tx.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        if let Ok(Some(_)) = Inboxes::close(inbox.to_string(), inbox_tx).await {
+            let _ = send_error(requester, ErrorCode::Timeout, action, topic, format!("No reply within {}ms", timeout.as_millis()), timeout_clients_tx).await;
+        }
+    });
+
+    let event = SocketEvent { action: body.action, topic: body.topic, value: json!({ "inbox": inbox.to_string(), "delivered_to": targets.len() }) };
+    send_to_client(user_id, json!(event).to_string(), clients_tx).await
+}
+
+/// Routes a Reply to the requester waiting on the inbox named by `topic`. Only the first Reply is delivered.
+pub async fn reply_handler(body: SocketRequest, user_id: String, clients_tx: Sender<Command<Client>>, inbox_tx: Sender<Command<String>>) -> Result<impl Reply, Rejection> {
+    match Inboxes::close(body.topic.clone(), inbox_tx).await {
+        Ok(Some(requester)) => {
+            let event = SocketEvent { action: body.action, topic: body.topic, value: body.message.unwrap_or(Value::Null) };
+            send_to_client(requester, json!(event).to_string(), clients_tx).await
+        },
+        Ok(None) => send_error(user_id, ErrorCode::UnknownInbox, body.action, body.topic, String::from("The request was already answered or timed out"), clients_tx).await,
+        Err(_) => Err(warp::reject::reject())
+    }
+}
+
+/// Stops the redelivery of the reliable event whose id is the request's message.
+pub async fn ack_handler(body: SocketRequest, user_id: String, clients_tx: Sender<Command<Client>>) -> Result<impl Reply, Rejection> {
+    let id = match body.message {
+        Some(Value::String(id)) => id,
+        _ => return Err(warp::reject::reject())
+    };
+    match Client::get_client(user_id, clients_tx).await {
+        Ok(Some(client)) => {
+            if !client.acknowledge(&id).await {
+                debug!("Ack for unknown event {}", id);
+            }
+            Ok(StatusCode::OK)
+        },
+        _ => Err(warp::reject::reject())
+    }
+}
+
+/// Resends the client's reliable events that were not acked in time, or all of them with `force` as on a reconnect.
+/// Events out of attempts are moved to the `__dead_letter__/{topic}` topic instead.
+pub async fn redelivery_handler(client: Client, force: bool, subscriptions_tx: SubscriptionsTx) -> Result<impl Reply, Rejection> {
+    let sender = match &client.sender {
+        Some(sender) => sender,
+        None => return Ok(StatusCode::OK)
+    };
+    let mut dead_letters = Vec::new();
+    {
+        let mut pending = client.pending.lock().await;
+        let ack_timeout = config().ack_timeout;
+        pending.retain(|id, event| {
+            if !force && event.sent_at.elapsed() < ack_timeout {
+                return true;
+            }
+            if event.attempts >= config().max_delivery_attempts {
+                warn!("Event {} for {} was not acked after {} attempts", id, client.user_id, event.attempts);
+                dead_letters.push(event.clone());
+                return false;
+            }
+            event.attempts += 1;
+            event.sent_at = Instant::now();
+            if sender.send(Ok(client.encoding.encode(event.text.clone()))).is_err() {
+                warn!("Error redelivering event {} to {}", id, client.user_id);
+            }
+            true
+        });
+    }
+    dead_letter(&client.user_id, dead_letters, subscriptions_tx).await;
+    Ok(StatusCode::OK)
+}
+
+/// Unregisters a client that stayed offline with unacked events since `since`, moving its events to the
+/// dead-letter topics. Clients that reconnected meanwhile are left alone.
+pub async fn expiry_handler(user_id: String, since: Instant, clients_tx: Sender<Command<Client>>, subscriptions_tx: SubscriptionsTx) -> Result<impl Reply, Rejection> {
+    let client = match Client::expire_client(user_id.clone(), since, clients_tx).await {
+        Ok(Some(client)) => client,
+        Ok(None) => return Ok(StatusCode::OK),
+        Err(_) => return Err(warp::reject::reject())
+    };
+    let events: Vec<PendingEvent> = client.pending.lock().await.drain().map(|(_, event)| event).collect();
+    warn!("Client {} stayed offline with {} unacked events, unregistering it", user_id, events.len());
+    dead_letter(&user_id, events, subscriptions_tx).await;
+    Ok(StatusCode::OK)
+}
+
+/// Publishes each event on the `__dead_letter__/{topic}` topic of its own.
+async fn dead_letter(user_id: &str, events: Vec<PendingEvent>, subscriptions_tx: SubscriptionsTx) {
+    for event in events {
+        let dead_letter = DeadLetter {
+            user_id: String::from(user_id),
+            attempts: event.attempts,
+            event: serde_json::from_str(&event.text).unwrap_or(Value::Null)
+        };
+        let _ = alert_subscribers(format!("__dead_letter__/{}", event.topic), EventKind::DeadLetter, json!(dead_letter).to_string(), None, String::from(user_id), subscriptions_tx.clone()).await;
+    }
+}
+
+/// Delivers a publish forwarded by another node of the cluster to this node's subscribers.
+pub async fn cluster_publish_handler(event: ForwardedEvent, subscriptions_tx: SubscriptionsTx) -> Result<impl Reply, Rejection> {
+    alert_subscribers(event.topic, event.kind, event.value, event.patch, event.user_id, subscriptions_tx).await
+}
+
+/// Records the topics another node of the cluster has subscribers for.
+pub async fn cluster_topics_handler(topics: PeerTopics, cluster_tx: Sender<ClusterCommand>) -> Result<impl Reply, Rejection> {
+    if !config().cluster_peers.contains(&topics.node) {
+        warn!("Refusing the topics of {}, which is not a configured peer", topics.node);
+        return Ok(StatusCode::FORBIDDEN);
+    }
+    Cluster::sync_peer(topics, cluster_tx).await;
+    Ok(StatusCode::OK)
+}
+
+/// Lists the peers of this node and the topics they have subscribers for.
+pub async fn cluster_peers_handler(cluster_tx: Sender<ClusterCommand>) -> Result<impl Reply, Rejection> {
+    match Cluster::get_peers(cluster_tx).await {
+        Ok(peers) => Ok(json!(peers).to_string()),
+        Err(_) => Err(warp::reject::reject())
+    }
+}
+
+/// Streams this node's store mutations from offset `from` as JSON lines, with a heartbeat line every
+/// heartbeat interval, for a replica to apply.
+pub async fn replication_stream_handler(query: ReplicationQuery, replication_tx: UnboundedSender<ReplicationCommand>, store_tx: StoreShards) -> Result<impl Reply, Rejection> {
+    let from = query.from.unwrap_or(0);
+    let (backlog, mut live) = match Replication::follow(from, replication_tx).await {
+        Ok(follow) => follow,
+        Err(_) => return Err(warp::reject::reject())
+    };
+    let (mut body_tx, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut head = from;
+        match backlog {
+            Backlog::Mutations(entries) => for entry in entries {
+                head = entry.offset + 1;
+                if body_tx.send_data(stream_line(StreamLine::Mutation(entry))).await.is_err() {
+                    return;
+                }
+            },
+            Backlog::Resync { head: resync_head } => {
+                head = resync_head;
+                if !send_resync(&mut body_tx, store_tx, head).await {
+                    return;
+                }
+            }
+        }
+        let mut heartbeat = time::interval(config().heartbeat_interval);
+        loop {
+            let line = tokio::select! {
+                entry = live.recv() => match entry {
+                    Some(entry) => {
+                        head = entry.offset + 1;
+                        StreamLine::Mutation(entry)
+                    },
+                    None => break
+                },
+                _ = heartbeat.tick() => StreamLine::Heartbeat { head }
+            };
+            if body_tx.send_data(stream_line(line)).await.is_err() {
+                debug!("Replica stopped following at offset {}", head);
+                break;
+            }
+        }
+    });
+    Ok(warp::http::Response::new(body))
+}
+
+fn stream_line<T: Serialize>(line: T) -> Bytes {
+    let mut line = serde_json::to_vec(&line).unwrap_or_default();
+    line.push(b'\n');
+    Bytes::from(line)
+}
+
+/// Sends a follower the string and collection values of the store, which replicas mirror, then the
+/// offset to follow from. Mutations made during the export are replayed after it, which is harmless as
+/// replaying a mutation gives the same result.
+async fn send_resync(body_tx: &mut warp::hyper::body::Sender, store_tx: StoreShards, head: u64) -> bool {
+    if body_tx.send_data(stream_line(StreamLine::Reset)).await.is_err() {
+        return false;
+    }
+    let (lines_tx, mut lines_rx) = mpsc::channel::<SnapshotLine>(64);
+    let export = tokio::spawn(snapshot::export(store_tx, None, lines_tx));
+    while let Some(line) = lines_rx.recv().await {
+        if let SnapshotLine::Key { key, value: value @ (StoredValue::String(_) | StoredValue::Collection(_)) } = line {
+            if body_tx.send_data(stream_line(StreamLine::Restore { key, value })).await.is_err() {
+                return false;
+            }
+        }
+    }
+    if !matches!(export.await, Ok(Ok(_))) {
+        error!("Error exporting the store to resync a replica");
+        return false;
+    }
+    body_tx.send_data(stream_line(StreamLine::Resynced { head })).await.is_ok()
+}
+
+pub async fn replication_status_handler(replication_tx: UnboundedSender<ReplicationCommand>) -> Result<impl Reply, Rejection> {
+    match Replication::get_status(replication_tx).await {
+        Ok(status) => Ok(json!(status).to_string()),
+        Err(_) => Err(warp::reject::reject())
+    }
+}
+
+/// Promotes a replica to primary: it stops following its primary and accepts writes.
+pub async fn promote_handler() -> Result<impl Reply, Rejection> {
+    Ok(json!({ "promoted": replication::promote() }).to_string())
+}
+
+/// Lets an admin request through when it carries `Authorization: Bearer <token>` with the configured
+/// admin token. The admin routes are not found at all when no token is configured.
+pub async fn admin_auth_handler(authorization: Option<String>) -> Result<(), Rejection> {
+    bearer_auth("admin", config().admin_token.as_deref(), authorization)
+}
+
+pub async fn cluster_auth_handler(authorization: Option<String>) -> Result<(), Rejection> {
+    bearer_auth("cluster", config().cluster_secret.as_deref(), authorization)
+}
+
+/// Lets through the requests that carry `token` as their bearer token. The routes are hidden when there is no token.
+fn bearer_auth(kind: &str, token: Option<&str>, authorization: Option<String>) -> Result<(), Rejection> {
+    let token = match token {
+        Some(token) => token,
+        None => return Err(warp::reject::not_found())
+    };
+    let offered = authorization.as_deref().and_then(|value| value.strip_prefix("Bearer ")).unwrap_or_default();
+    if tokens_match(offered.as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        warn!("Refusing a {} request without a valid token", kind);
+        Err(warp::reject::custom(Unauthorized))
+    }
+}
+
+fn tokens_match(offered: &[u8], token: &[u8]) -> bool {
+    offered.len() == token.len() && offered.iter().zip(token).fold(0, |diff, (left, right)| diff | (left ^ right)) == 0
+}
+
+pub async fn auth_rejection_handler(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    match rejection.find::<Unauthorized>() {
+        Some(_) => Ok(warp::reply::with_status(json!({ "error": "Unauthorized" }).to_string(), StatusCode::UNAUTHORIZED)),
+        None => Err(rejection)
+    }
+}
+
+/// Lists the registered clients with the topics they are subscribed to on this node.
+pub async fn admin_clients_handler(subscriptions_tx: SubscriptionsTx, clients_tx: Sender<Command<Client>>) -> Result<impl Reply, Rejection> {
+    let user_ids = Client::get_user_ids(clients_tx.clone()).await.map_err(|_| warp::reject::reject())?;
+    let mut topics = HashMap::<String, Vec<String>>::new();
+    for (topic, subscribers) in subscriptions_tx.snapshot().iter() {
+        for subscriber in subscribers.iter() {
+            topics.entry(subscriber.user_id.clone()).or_default().push(topic.clone());
+        }
+    }
+    let mut clients = Vec::new();
+    for user_id in user_ids {
+        if let Ok(Some(client)) = Client::get_client(user_id, clients_tx.clone()).await {
+            let mut subscriptions = topics.remove(&client.user_id).unwrap_or_default();
+            subscriptions.sort();
+            clients.push(ClientInfo {
+                connected: client.sender.as_ref().is_some_and(|sender| !sender.is_closed()),
+                pending_events: client.pending.lock().await.len(),
+                user_id: client.user_id,
+                subscriptions
+            });
+        }
+    }
+    clients.sort_by(|left, right| left.user_id.cmp(&right.user_id));
+    Ok(json!(clients).to_string())
+}
+
+/// Ends the connection of a client, which stops reading its socket and sends it a close, and removes
+/// it from every topic. It stays registered only while it has reliable events pending, as after any
+/// other disconnect.
+pub async fn admin_disconnect_handler(user_id: String, subscriptions_tx: SubscriptionsTx, clients_tx: Sender<Command<Client>>, cluster_tx: Sender<ClusterCommand>) -> Result<impl Reply, Rejection> {
+    let client = match Client::get_client(user_id.clone(), clients_tx).await {
+        Ok(Some(client)) => client,
+        Ok(None) => return Ok(warp::reply::with_status(json!({ "error": format!("Client {} is not registered", user_id) }).to_string(), StatusCode::NOT_FOUND)),
+        Err(_) => return Err(warp::reject::reject())
+    };
+    // A permit is kept until the connection waits on it, so the shutdown is never missed.
+    let connected = match (&client.sender, &client.shutdown) {
+        (Some(sender), Some(shutdown)) if !sender.is_closed() => {
+            shutdown.notify_one();
+            true
+        },
+        _ => false
+    };
+    warn!("Disconnecting client {} on an admin request. Connected: {}", user_id, connected);
+    disconnect_handler(user_id, subscriptions_tx, cluster_tx).await?;
+    Ok(warp::reply::with_status(json!({ "disconnected": connected }).to_string(), StatusCode::OK))
+}
+
+/// Lists the topics with subscribers on this node.
+pub async fn admin_topics_handler(subscriptions_tx: SubscriptionsTx) -> Result<impl Reply, Rejection> {
+    let mut topics: Vec<TopicInfo> = subscriptions_tx.snapshot().iter()
+        .map(|(topic, subscribers)| TopicInfo { topic: topic.clone(), subscribers: subscribers.len() })
+        .collect();
+    topics.sort_by(|left, right| left.topic.cmp(&right.topic));
+    Ok(json!(topics).to_string())
+}
+
+/// Unsubscribes every subscriber of a topic, telling each with an Unsubscribe event, and clears its
+/// retained message.
+pub async fn admin_purge_handler(topic: String, subscriptions_tx: SubscriptionsTx, retained_tx: Sender<Command<Value>>, cluster_tx: Sender<ClusterCommand>) -> Result<impl Reply, Rejection> {
+    let subscribers = Subscribers::get_subscribers(&topic, &subscriptions_tx).unwrap_or_default();
+    let event = json!(SocketEvent { action: RequestAction::Unsubscribe, topic: topic.clone(), value: json!({ "purged": true }) }).to_string();
+    let mut unsubscribed = 0;
+    for subscriber in subscribers.iter() {
+        match Subscribers::remove_subscriber(topic.clone(), subscriber.clone(), subscriptions_tx.clone()).await {
+            Ok(true) => {
+                unsubscribed += 1;
+                if let Some(sender) = &subscriber.sender {
+                    let _ = sender.send(Ok(subscriber.encoding.encode(event.clone())));
+                }
+            },
+            Ok(false) => (),
+            Err(_) => return Err(warp::reject::reject())
+        }
+    }
+    if unsubscribed > 0 {
+        Cluster::announce(cluster_tx).await;
+    }
+    let retained = Retained::clear(topic.clone(), retained_tx).await.map_err(|_| warp::reject::reject())?.is_some();
+    warn!("Purged topic {} on an admin request: {} subscribers, retained message: {}", topic, unsubscribed, retained);
+    Ok(json!({ "unsubscribed": unsubscribed, "retained": retained }).to_string())
+}
+
+/// A page of the keys of the store, filtered and resumed as by a Scan.
+pub async fn admin_scan_handler(query: ScanQuery, store_tx: StoreShards) -> Result<impl Reply, Rejection> {
+    let filter = ScanFilter { after: query.cursor, prefix: query.prefix.unwrap_or_default(), pattern: query.pattern };
+    match Store::scan(filter, query.count.unwrap_or(DEFAULT_SCAN_COUNT), store_tx).await {
+        Ok(page) => Ok(json!(page).to_string()),
+        Err(_) => Err(warp::reject::reject())
+    }
+}
+
+/// Streams a snapshot of the store, and of the retained messages when asked, as JSON lines. The body
+/// is aborted rather than ended if the export fails, so that a truncated snapshot is noticed.
+pub async fn admin_export_handler(query: SnapshotQuery, store_tx: StoreShards, retained_tx: Sender<Command<Value>>) -> Result<impl Reply, Rejection> {
+    let (lines_tx, mut lines_rx) = mpsc::channel::<SnapshotLine>(64);
+    let retained_tx = query.retained.unwrap_or(false).then_some(retained_tx);
+    let export = tokio::spawn(snapshot::export(store_tx, retained_tx, lines_tx));
+    let (mut body_tx, body) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(line) = lines_rx.recv().await {
+            if body_tx.send_data(stream_line(line)).await.is_err() {
+                debug!("Snapshot download stopped by the client");
+                return;
+            }
+        }
+        if !matches!(export.await, Ok(Ok(_))) {
+            error!("Error exporting a snapshot");
+            body_tx.abort();
+        }
+    });
+    Ok(warp::reply::with_header(warp::http::Response::new(body), "content-type", "application/x-ndjson"))
+}
+
+/// Loads a snapshot sent as the request body, merged into the current state or replacing it. Nothing
+/// is written when a line of the snapshot is invalid.
+pub async fn admin_import_handler(query: SnapshotQuery, body: Bytes, store_tx: StoreShards, retained_tx: Sender<Command<Value>>) -> Result<impl Reply, Rejection> {
+    if replication::is_replica() {
+        return Ok(warp::reply::with_status(json!({ "error": "This node is a read-only replica" }).to_string(), StatusCode::CONFLICT));
+    }
+    let lines = match std::str::from_utf8(&body).map_err(|err| err.to_string()).and_then(snapshot::parse) {
+        Ok(lines) => lines,
+        Err(message) => return Ok(warp::reply::with_status(json!({ "error": message }).to_string(), StatusCode::BAD_REQUEST))
+    };
+    match snapshot::import(lines, query.mode.unwrap_or_default(), store_tx, retained_tx).await {
+        Ok(summary) => Ok(warp::reply::with_status(json!(summary).to_string(), StatusCode::OK)),
+        Err(_) => Err(warp::reject::reject())
+    }
+}
+
+/// Every value stored under a key, tagged with its kind.
+pub async fn admin_key_handler(key: String, store_tx: StoreShards) -> Result<impl Reply, Rejection> {
+    match Store::inspect(key.clone(), store_tx).await {
+        Ok(values) if values.is_empty() => Ok(warp::reply::with_status(json!({ "error": format!("Key {} does not exist", key) }).to_string(), StatusCode::NOT_FOUND)),
+        Ok(values) => Ok(warp::reply::with_status(json!(values).to_string(), StatusCode::OK)),
+        Err(_) => Err(warp::reject::reject())
+    }
+}
+
+/// Deletes every value stored under a key, whatever its kind.
+pub async fn admin_delete_key_handler(key: String, store_tx: StoreShards) -> Result<impl Reply, Rejection> {
+    if replication::is_replica() {
+        return Ok(warp::reply::with_status(json!({ "error": "This node is a read-only replica" }).to_string(), StatusCode::CONFLICT));
+    }
+    match Store::delete(key, store_tx).await {
+        Ok(deleted) => Ok(warp::reply::with_status(json!({ "deleted": deleted }).to_string(), StatusCode::OK)),
+        Err(_) => Err(warp::reject::reject())
+    }
+}
+
+/// Tells the watchers of `__keyspace__/{key}` that the store changed `key`.
+pub async fn keyspace_handler(event: KeyspaceEvent, subscriptions_tx: SubscriptionsTx) -> Result<impl Reply, Rejection> {
+    // The event comes from the store rather than a client, so no subscriber is skipped as its publisher.
+    alert_subscribers(format!("{}{}", KEYSPACE_PREFIX, event.key), EventKind::Keyspace, json!(event).to_string(), None, String::new(), subscriptions_tx).await
+}
+
+/// Creates, inspects, resets, deletes or reads from the durable consumer named in the request, replying with the
+/// consumer's position or the fetched events.
+pub async fn consumer_handler(body: SocketRequest, user_id: String, clients_tx: Sender<Command<Client>>, log_tx: EventLogTx) -> Result<impl Reply, Rejection> {
+    let name = required(body.consumer)?;
+    let start = body.start.and_then(|start| u64::try_from(start).ok());
+    let result = match body.action {
+        RequestAction::CreateConsumer => EventLog::create_consumer(body.topic.clone(), name, start, log_tx).await.map(|result| result.map(|info| json!(info))),
+        RequestAction::GetConsumer => EventLog::get_consumer(body.topic.clone(), name.clone(), log_tx).await
+            .map(|info| info.map(|info| json!(info)).ok_or(format!("Consumer {} does not exist on topic {}", name, body.topic))),
+        RequestAction::ResetConsumer => EventLog::reset_consumer(body.topic.clone(), name, start.unwrap_or(0), log_tx).await.map(|result| result.map(|info| json!(info))),
+        RequestAction::DeleteConsumer => EventLog::delete_consumer(body.topic.clone(), name, log_tx).await.map(|deleted| Ok(json!({ "deleted": deleted }))),
+        RequestAction::FetchFromConsumer => EventLog::fetch(body.topic.clone(), name, body.count.unwrap_or(1), log_tx).await.map(|result| result.map(|events| json!(events))),
+        _ => {
+            error!("Error: consumer_handler must be called with a consumer request");
+            return Err(warp::reject::reject())
+        }
+    };
+    match result {
+        Ok(Ok(value)) => {
+            let event = SocketEvent { action: body.action, topic: body.topic, value };
+            send_to_client(user_id, json!(event).to_string(), clients_tx).await
+        },
+        Ok(Err(message)) => send_error(user_id, ErrorCode::InvalidConsumer, body.action, body.topic, message, clients_tx).await,
+        Err(_) => Err(warp::reject::reject())
+    }
+}
+
+/// Removes a disconnected client from every topic, announcing it as having left where it had presence enabled.
+pub async fn disconnect_handler(user_id: String, subscriptions_tx: SubscriptionsTx, cluster_tx: Sender<ClusterCommand>) -> Result<impl Reply, Rejection> {
+    let client = Client {
+        user_id,
+        sender: None,
+        ..Default::default()
+    };
+    match Subscribers::remove_subscriber_from_all(client, subscriptions_tx.clone()).await {
+        Ok(subscriptions) => {
+            if !subscriptions.is_empty() {
+                Cluster::announce(cluster_tx).await;
+            }
+            for (topic, subscription) in subscriptions {
+                let _ = announce_presence(PresenceAction::Leave, topic, subscription, subscriptions_tx.clone()).await;
+            }
+            Ok(StatusCode::OK)
+        },
+        Err(_) => Err(warp::reject::reject())
+    }
+}
+
+async fn announce_presence(presence: PresenceAction, topic: String, subscriber: Client, subscriptions_tx: SubscriptionsTx) -> Result<StatusCode, Rejection> {
+    match subscriber.options.presence {
+        Some(meta) => {
+            let event = PresenceEvent { presence, topic: topic.clone(), user_id: subscriber.user_id.clone(), meta };
+            alert_subscribers(topic, EventKind::Presence, json!(event).to_string(), None, subscriber.user_id, subscriptions_tx).await
+        },
+        None => Ok(StatusCode::OK)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use warp::hyper::StatusCode;
     use crate::command::Command;
-    use crate::store::{Client, Clients};
+    use crate::store::{self, Client, Clients, Subscribers};
+    use crate::subscriptions;
     use tokio::sync::mpsc;
-    use crate::Reply;
+    use warp::Reply;
     use std::collections::HashMap;
     use tokio::sync::Mutex;
     use std::sync::Arc;
@@ -172,28 +1010,34 @@ mod tests {
     use super::register_handler;
     use super::unregister_handler;
     use super::health_handler;
+    use super::direct_message_handler;
+    use super::reply_handler;
+    use super::keyspace_handler;
+    use super::{admin_purge_handler, admin_topics_handler, expiry_handler};
+    use crate::serialize::{EventKind, KeyspaceEvent, RequestAction};
+    use crate::serialize::SocketRequest;
+
+    /// Spawns the clients actor with `clients` registered, returning what it holds and its sender.
+    fn spawn_clients(clients: impl IntoIterator<Item = Client>) -> (Clients, mpsc::Sender<Command<Client>>) {
+        let clients: Clients = Arc::new(Mutex::new(clients.into_iter().map(|client| (client.user_id.clone(), client)).collect()));
+        (clients.clone(), store::spawn_clients(clients))
+    }
+
+    /// A client with an open socket, and what the socket receives.
+    fn connected(user_id: &str) -> (Client, mpsc::UnboundedReceiver<Result<warp::ws::Message, warp::Error>>) {
+        let (sender, socket) = mpsc::unbounded_channel();
+        (Client { user_id: user_id.to_string(), sender: Some(sender), ..Default::default() }, socket)
+    }
 
     #[tokio::test]
     async fn test_register_handler() {
-        let (clients_tx, mut clients_rx) = mpsc::channel::<Command<Client>>(32);
-        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
-
-        tokio::spawn(async move {
-            while let Some(cmd) = clients_rx.recv().await {
-                match cmd {
-                    Command::SetItem { key, value, responder } => {
-                        let result = clients.lock().await.insert(key, value);
-                        let _ = responder.send(result);
-                    },
-                    _ => panic!()
-                }
-            }
-        });
+        let (clients, clients_tx) = spawn_clients([]);
 
         let result = register_handler(clients_tx).await;
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap().into_response().status(), 200);
+        assert_eq!(clients.lock().await.len(), 1);
     }
 
     #[tokio::test]
@@ -202,7 +1046,8 @@ mod tests {
         let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
         let client = Client {
             user_id: String::from("1"),
-            sender: None
+            sender: None,
+            ..Default::default()
         };
         clients.lock().await.insert("1".to_string(), client);
 
@@ -231,4 +1076,143 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), StatusCode::OK);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_direct_message_handler() {
+        let (sender, mut sender_rx) = connected("1");
+        let (recipient, mut recipient_rx) = connected("2");
+        let (_, clients_tx) = spawn_clients([sender, recipient]);
+
+        let request = |topic: &str| -> SocketRequest {
+            serde_json::from_value(serde_json::json!({ "action": "DirectMessage", "user_id": "1", "topic": topic, "message": { "text": "hi" } })).unwrap()
+        };
+
+        let result = direct_message_handler(request("2"), "1".to_string(), clients_tx.clone()).await;
+        assert!(result.is_ok());
+        let delivered = recipient_rx.recv().await.unwrap().unwrap();
+        assert_eq!(delivered.to_str().unwrap(), r#"{"from":"1","message":{"text":"hi"}}"#);
+        let status = sender_rx.recv().await.unwrap().unwrap();
+        assert!(status.to_str().unwrap().contains(r#""delivered":true"#));
+
+        let result = direct_message_handler(request("3"), "1".to_string(), clients_tx).await;
+        assert!(result.is_ok());
+        let status = sender_rx.recv().await.unwrap().unwrap();
+        assert!(status.to_str().unwrap().contains(r#""delivered":false"#));
+        assert!(recipient_rx.try_recv().is_err());
+        assert!(sender_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reply_handler() {
+        let (inbox_tx, mut inbox_rx) = mpsc::channel::<Command<String>>(32);
+        let (requester, mut requester_rx) = connected("1");
+        let (responder, mut responder_rx) = connected("2");
+        let (_, clients_tx) = spawn_clients([requester, responder]);
+        tokio::spawn(async move {
+            let mut inboxes = HashMap::from([("inbox".to_string(), "1".to_string())]);
+            while let Some(cmd) = inbox_rx.recv().await {
+                match cmd {
+                    Command::UnsetItem { key, responder } => {
+                        let _ = responder.send(inboxes.remove(&key));
+                    },
+                    _ => panic!()
+                }
+            }
+        });
+
+        let reply = || -> SocketRequest {
+            serde_json::from_value(serde_json::json!({ "action": "Reply", "user_id": "2", "topic": "inbox", "message": 42 })).unwrap()
+        };
+
+        let result = reply_handler(reply(), "2".to_string(), clients_tx.clone(), inbox_tx.clone()).await;
+        assert!(result.is_ok());
+        let delivered = requester_rx.recv().await.unwrap().unwrap();
+        assert_eq!(delivered.to_str().unwrap(), r#"{"action":"Reply","topic":"inbox","value":42}"#);
+
+        let result = reply_handler(reply(), "2".to_string(), clients_tx, inbox_tx).await;
+        assert!(result.is_ok());
+        let error = responder_rx.recv().await.unwrap().unwrap();
+        assert!(error.to_str().unwrap().contains(r#""error":"UnknownInbox""#));
+        // The requester gets the first reply only.
+        assert!(requester_rx.try_recv().is_err());
+        assert!(responder_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_handler() {
+        let subscriptions_tx = subscriptions::spawn();
+        let (watcher_tx, mut watcher_rx) = mpsc::unbounded_channel();
+        let watcher = Client {
+            user_id: "1".to_string(),
+            sender: Some(watcher_tx),
+            ..Default::default()
+        };
+        Subscribers::add_subscriber("__keyspace__/cart".to_string(), watcher, subscriptions_tx.clone()).await.unwrap();
+
+        let event = KeyspaceEvent { key: "cart".to_string(), action: RequestAction::PushToList };
+        let result = keyspace_handler(event, subscriptions_tx).await;
+        assert!(result.is_ok());
+        let delivered = watcher_rx.recv().await.unwrap().unwrap();
+        assert_eq!(delivered.to_str().unwrap(), r#"{"action":"PushToList","key":"cart"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_admin_purge_handler() {
+        let subscriptions_tx = subscriptions::spawn();
+        let (retained_tx, mut retained_rx) = mpsc::channel::<Command<serde_json::Value>>(32);
+        let (cluster_tx, _cluster_rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            while let Some(cmd) = retained_rx.recv().await {
+                if let Command::UnsetItem { responder, .. } = cmd {
+                    let _ = responder.send(None);
+                }
+            }
+        });
+        let (subscriber_tx, mut subscriber_rx) = mpsc::unbounded_channel();
+        let subscriber = Client { user_id: "1".to_string(), sender: Some(subscriber_tx), ..Default::default() };
+        Subscribers::add_subscriber("prices".to_string(), subscriber, subscriptions_tx.clone()).await.unwrap();
+        Subscribers::add_subscriber("prices".to_string(), Client { user_id: "2".to_string(), ..Default::default() }, subscriptions_tx.clone()).await.unwrap();
+
+        let topics = admin_topics_handler(subscriptions_tx.clone()).await.unwrap().into_response();
+        let body = warp::hyper::body::to_bytes(topics.into_body()).await.unwrap();
+        assert_eq!(body, r#"[{"subscribers":2,"topic":"prices"}]"#);
+
+        let purged = admin_purge_handler("prices".to_string(), subscriptions_tx.clone(), retained_tx, cluster_tx).await.unwrap().into_response();
+        let body = warp::hyper::body::to_bytes(purged.into_body()).await.unwrap();
+        assert_eq!(body, r#"{"retained":false,"unsubscribed":2}"#);
+        assert!(subscriptions_tx.subscribers("prices").is_none());
+        let notified = subscriber_rx.recv().await.unwrap().unwrap();
+        assert_eq!(notified.to_str().unwrap(), r#"{"action":"Unsubscribe","topic":"prices","value":{"purged":true}}"#);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_handler() {
+        let subscriptions_tx = subscriptions::spawn();
+        let (watcher, mut watcher_rx) = connected("2");
+        Subscribers::add_subscriber("__dead_letter__/orders".to_string(), watcher, subscriptions_tx.clone()).await.unwrap();
+
+        let since = std::time::Instant::now();
+        let offline = Client { user_id: "1".to_string(), offline_since: Some(since), ..Default::default() };
+        offline.track_delivery("orders", EventKind::Document, String::from("42")).await;
+        let (clients, clients_tx) = spawn_clients([offline.clone()]);
+
+        // A client that went offline again since is expired by the later expiry only.
+        expiry_handler("1".to_string(), std::time::Instant::now(), clients_tx.clone(), subscriptions_tx.clone()).await.unwrap();
+        assert!(clients.lock().await.contains_key("1"));
+
+        // Nor is a client that reconnected.
+        let (reconnected, _socket) = connected("1");
+        clients.lock().await.insert("1".to_string(), Client { pending: offline.pending.clone(), ..reconnected });
+        expiry_handler("1".to_string(), since, clients_tx.clone(), subscriptions_tx.clone()).await.unwrap();
+        assert!(clients.lock().await["1"].sender.is_some());
+        assert_eq!(offline.pending.lock().await.len(), 1);
+        clients.lock().await.insert("1".to_string(), offline.clone());
+
+        expiry_handler("1".to_string(), since, clients_tx, subscriptions_tx).await.unwrap();
+        assert!(clients.lock().await.is_empty());
+        assert!(offline.pending.lock().await.is_empty());
+        let dead_letter: serde_json::Value = serde_json::from_str(watcher_rx.recv().await.unwrap().unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(dead_letter["user_id"], "1");
+        assert_eq!(dead_letter["event"]["value"], 42);
+    }
+}
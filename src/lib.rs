@@ -0,0 +1,24 @@
+// `Client` hashes and compares by `user_id` only, so the interior mutability of its sender never affects set membership.
+#![allow(clippy::mutable_key_type)]
+
+pub mod serialize;
+pub mod handler;
+pub mod ws;
+pub mod store;
+pub mod command;
+pub mod document;
+pub mod config;
+pub mod event_log;
+pub mod cluster;
+pub mod replication;
+pub mod shard;
+pub mod subscriptions;
+pub mod codec;
+pub mod deflate;
+pub mod protocol;
+pub mod limits;
+pub mod snapshot;
+pub mod client;
+
+#[macro_use]
+extern crate log;
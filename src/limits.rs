@@ -0,0 +1,280 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex}, time::Instant};
+use crate::config::{RateLimits, Rates};
+use crate::serialize::RequestAction;
+
+/// Buckets of identities and topics are forgotten once full, as they would be for a new one, when
+/// there are more than this many.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64) -> TokenBucket {
+        TokenBucket { rate, tokens: rate, refilled_at: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * self.rate).min(self.rate);
+        self.refilled_at = now;
+    }
+
+    pub fn allows(&mut self, amount: f64) -> bool {
+        self.refill();
+        self.tokens >= amount
+    }
+
+    pub fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.rate
+    }
+}
+
+/// The limit a throttled request ran into.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    ConnectionPublishes,
+    ConnectionBytes,
+    IdentityPublishes,
+    IdentityBytes,
+    TopicPublishes,
+    TopicBytes,
+    Subscriptions,
+    TopicSubscribers
+}
+
+#[derive(Debug, Clone, Default)]
+struct Buckets {
+    publishes: Option<TokenBucket>,
+    bytes: Option<TokenBucket>
+}
+
+impl Buckets {
+    fn new(rates: Rates) -> Buckets {
+        Buckets { publishes: rates.publishes.map(TokenBucket::new), bytes: rates.bytes.map(TokenBucket::new) }
+    }
+
+    /// Which of the publish and byte buckets cannot afford the request, if any.
+    fn check(&mut self, publish: bool, bytes: usize) -> (bool, bool) {
+        let publishes = publish && self.publishes.as_mut().is_some_and(|bucket| !bucket.allows(1.0));
+        let bytes = self.bytes.as_mut().is_some_and(|bucket| !bucket.allows(bytes as f64));
+        (publishes, bytes)
+    }
+
+    fn take(&mut self, publish: bool, bytes: usize) {
+        if let (true, Some(bucket)) = (publish, self.publishes.as_mut()) {
+            bucket.take(1.0);
+        }
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.take(bytes as f64);
+        }
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.publishes.as_mut().is_none_or(TokenBucket::is_full) && self.bytes.as_mut().is_none_or(TokenBucket::is_full)
+    }
+}
+
+/// The buckets shared by every connection: one per user id and one per topic.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limits: Arc<RateLimits>,
+    identities: Arc<Mutex<HashMap<String, Buckets>>>,
+    topics: Arc<Mutex<HashMap<String, Buckets>>>
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter { limits: Arc::new(limits), identities: Arc::default(), topics: Arc::default() }
+    }
+
+    pub fn connection(&self) -> ConnectionLimits {
+        ConnectionLimits { buckets: Buckets::new(self.limits.connection), subscriptions: HashSet::new(), throttled: 0 }
+    }
+
+    /// Takes the tokens of a request of `bytes` bytes from the connection, identity and topic buckets,
+    /// unless one of them cannot afford it, in which case none is charged.
+    pub fn admit(&self, connection: &mut ConnectionLimits, user_id: &str, action: RequestAction, topic: &str, bytes: usize) -> Result<(), Limit> {
+        let publish = is_publish(action);
+        let result = self.charge(connection, user_id, publish, topic, bytes);
+        if let Some(max) = self.limits.max_subscriptions {
+            if result.is_ok() && action == RequestAction::Subscribe && !connection.subscriptions.contains(topic) && connection.subscriptions.len() >= max {
+                connection.throttled += 1;
+                return Err(Limit::Subscriptions);
+            }
+        }
+        match result {
+            Ok(_) => connection.throttled = 0,
+            Err(_) => connection.throttled += 1
+        }
+        result
+    }
+
+    /// Refuses to subscribe the socket to a topic that already has `subscribers` other subscribers, unless it
+    /// is subscribed to it already.
+    pub fn admit_subscriber(&self, connection: &mut ConnectionLimits, topic: &str, subscribers: usize) -> Result<(), Limit> {
+        match self.limits.max_topic_subscribers {
+            Some(max) if !connection.subscriptions.contains(topic) && subscribers >= max => {
+                connection.throttled += 1;
+                Err(Limit::TopicSubscribers)
+            },
+            _ => Ok(())
+        }
+    }
+
+    fn charge(&self, connection: &mut ConnectionLimits, user_id: &str, publish: bool, topic: &str, bytes: usize) -> Result<(), Limit> {
+        match connection.buckets.check(publish, bytes) {
+            (true, _) => return Err(Limit::ConnectionPublishes),
+            (_, true) => return Err(Limit::ConnectionBytes),
+            _ => ()
+        }
+        // No bucket is kept for identities or topics when they are unlimited.
+        let mut identities = self.identities.lock().unwrap();
+        let mut identity = None;
+        if self.limits.identity != Rates::default() {
+            let buckets = identities.entry(String::from(user_id)).or_insert_with(|| Buckets::new(self.limits.identity));
+            match buckets.check(publish, bytes) {
+                (true, _) => return Err(Limit::IdentityPublishes),
+                (_, true) => return Err(Limit::IdentityBytes),
+                _ => identity = Some(buckets)
+            }
+        }
+        let mut topics = self.topics.lock().unwrap();
+        if publish && self.limits.topic != Rates::default() {
+            let buckets = topics.entry(String::from(topic)).or_insert_with(|| Buckets::new(self.limits.topic));
+            match buckets.check(true, bytes) {
+                (true, _) => return Err(Limit::TopicPublishes),
+                (_, true) => return Err(Limit::TopicBytes),
+                _ => buckets.take(true, bytes)
+            }
+        }
+        if let Some(buckets) = identity {
+            buckets.take(publish, bytes);
+        }
+        connection.buckets.take(publish, bytes);
+        forget_idle(&mut identities);
+        forget_idle(&mut topics);
+        Ok(())
+    }
+}
+
+fn forget_idle(buckets: &mut HashMap<String, Buckets>) {
+    if buckets.len() > MAX_IDLE_BUCKETS {
+        buckets.retain(|_, bucket| !bucket.is_full());
+    }
+}
+
+/// The buckets of one socket, along with the topics it subscribed to.
+#[derive(Debug)]
+pub struct ConnectionLimits {
+    buckets: Buckets,
+    subscriptions: HashSet<String>,
+    throttled: u32
+}
+
+impl ConnectionLimits {
+    pub fn subscribed(&mut self, topic: &str) {
+        self.subscriptions.insert(String::from(topic));
+    }
+
+    pub fn unsubscribed(&mut self, topic: &str) {
+        self.subscriptions.remove(topic);
+    }
+
+    pub fn subscriptions(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    /// Whether the socket has been throttled `disconnect_after` times in a row.
+    pub fn is_abusive(&self, disconnect_after: Option<u32>) -> bool {
+        disconnect_after.is_some_and(|limit| self.throttled >= limit)
+    }
+}
+
+/// Requests that change the store or reach other clients, as opposed to subscriptions and reads.
+fn is_publish(action: RequestAction) -> bool {
+    !matches!(action,
+        RequestAction::Hello | RequestAction::GetUsage | RequestAction::Subscribe | RequestAction::Unsubscribe | RequestAction::Ack |
+        RequestAction::Get | RequestAction::GetCollection | RequestAction::GetListRange | RequestAction::GetField |
+        RequestAction::GetSortedSetRangeByRank | RequestAction::GetSortedSetRangeByScore | RequestAction::GetPresence |
+        RequestAction::GetConsumer | RequestAction::FetchFromConsumer | RequestAction::Scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{RateLimits, Rates};
+    use crate::serialize::RequestAction;
+    use super::{Limit, RateLimiter};
+
+    #[test]
+    fn test_connection_and_topic_limits() {
+        let limits = RateLimits {
+            connection: Rates { publishes: Some(3.0), bytes: Some(100.0) },
+            topic: Rates { publishes: Some(2.0), bytes: None },
+            disconnect_after: Some(2),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(limits.clone());
+        let mut first = limiter.connection();
+        let mut second = limiter.connection();
+
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Set, "a", 10), Ok(()));
+        assert_eq!(limiter.admit(&mut second, "2", RequestAction::Set, "a", 10), Ok(()));
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Set, "a", 10), Err(Limit::TopicPublishes));
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Set, "b", 10), Ok(()));
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Get, "b", 200), Err(Limit::ConnectionBytes));
+        assert!(!first.is_abusive(limits.disconnect_after));
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Get, "b", 200), Err(Limit::ConnectionBytes));
+        assert!(first.is_abusive(limits.disconnect_after));
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Set, "c", 10), Ok(()));
+        assert!(!first.is_abusive(limits.disconnect_after));
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Set, "d", 10), Err(Limit::ConnectionPublishes));
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Subscribe, "d", 10), Ok(()));
+    }
+
+    #[test]
+    fn test_identity_and_subscription_limits() {
+        let limits = RateLimits {
+            identity: Rates { publishes: Some(1.0), bytes: None },
+            max_subscriptions: Some(1),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(limits);
+        let mut first = limiter.connection();
+        let mut reconnected = limiter.connection();
+
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Set, "a", 10), Ok(()));
+        assert_eq!(limiter.admit(&mut reconnected, "1", RequestAction::Set, "a", 10), Err(Limit::IdentityPublishes));
+
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Subscribe, "a", 10), Ok(()));
+        first.subscribed("a");
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Subscribe, "a", 10), Ok(()));
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Subscribe, "b", 10), Err(Limit::Subscriptions));
+        first.unsubscribed("a");
+        assert_eq!(limiter.admit(&mut first, "1", RequestAction::Subscribe, "b", 10), Ok(()));
+    }
+
+    #[test]
+    fn test_topic_subscriber_limit() {
+        let limits = RateLimits { max_topic_subscribers: Some(1), disconnect_after: Some(1), ..Default::default() };
+        let limiter = RateLimiter::new(limits.clone());
+        let mut first = limiter.connection();
+        let mut second = limiter.connection();
+
+        assert_eq!(limiter.admit_subscriber(&mut first, "a", 0), Ok(()));
+        first.subscribed("a");
+        assert_eq!(limiter.admit_subscriber(&mut first, "a", 1), Ok(()));
+        assert_eq!(limiter.admit_subscriber(&mut second, "a", 1), Err(Limit::TopicSubscribers));
+        assert!(second.is_abusive(limits.disconnect_after));
+        assert_eq!(limiter.admit_subscriber(&mut second, "b", 0), Ok(()));
+    }
+}
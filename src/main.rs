@@ -1,165 +1,160 @@
-use std::collections::HashSet;
-use std::{collections::HashMap, convert::Infallible};
+
+use std::{collections::HashMap, convert::Infallible, env, fs::{self, File}, io::{self, BufWriter}, process};
 use std::sync::Arc;
-use store::Client;
-use command::{Command};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{Sender, UnboundedSender};
 use tokio::sync::{Mutex, mpsc};
-use warp::{Filter, Reply};
-use crate::store::{Subscriptions, Clients};
-mod serialize;
-mod handler;
-mod ws;
-mod store;
-mod command;
+use warp::{Filter, Rejection, path::Tail};
+use warp::hyper::{Server, service::{make_service_fn, service_fn, Service}};
+use percent_encoding::percent_decode_str;
+use pub_sub_rust::{cluster, event_log, handler, replication, shard, snapshot, store, subscriptions};
+use pub_sub_rust::snapshot::ImportMode;
+use pub_sub_rust::command::Command;
+use pub_sub_rust::store::{Client, Clients};
+use pub_sub_rust::subscriptions::SubscriptionsTx;
+use pub_sub_rust::config::config;
+use pub_sub_rust::cluster::{ClusterCommand, ClusterState};
+use pub_sub_rust::replication::ReplicationCommand;
+use pub_sub_rust::ws::{Channels, Handshake, Upgrade};
+use pub_sub_rust::shard::StoreShards;
+use pub_sub_rust::limits::RateLimiter;
+use pub_sub_rust::serialize::{KeyspaceEvent, ScanQuery, SnapshotQuery, WillOptions};
+use serde_json::Value;
 
 #[macro_use]
 extern crate log;
 
+const USAGE: &str = "Usage:
+  pub-sub-rust                                          run the server
+  pub-sub-rust export [--retained] [--url URL] [FILE]   write a snapshot of a running server to FILE or stdout
+  pub-sub-rust import [--replace] [--url URL] FILE      load a snapshot into a running server
+The admin token is read from PUBSUB_ADMIN_TOKEN and the URL defaults to http://127.0.0.1:$PUBSUB_PORT.";
+
 #[tokio::main]
 async fn main() {
   env_logger::init();
 
+  let args: Vec<String> = env::args().skip(1).collect();
+  if let Some(command) = args.first() {
+    let result = match command.as_str() {
+      "export" => export_command(&args[1..]).await,
+      "import" => import_command(&args[1..]).await,
+      "help" | "--help" | "-h" => {
+        println!("{}", USAGE);
+        Ok(())
+      },
+      _ => Err(format!("Unknown command {}\n{}", command, USAGE))
+    };
+    if let Err(message) = result {
+      eprintln!("{}", message);
+      process::exit(1);
+    }
+    return;
+  }
+
   // TODO CWS: I wonder if this combination of Arc/Mutex is the right approach or if we could do this pattern with just an Arc and moves.
   let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
-  let subscriptions: Subscriptions = Arc::new(Mutex::new(HashMap::new()));
-  let string_store = Arc::new(Mutex::new(HashMap::new()));
-  let collection_store = Arc::new(Mutex::new(HashMap::<String, HashSet::<String>>::new()));
 
-  let (clients_tx, mut clients_rx) = mpsc::channel::<Command<Client>>(32);
-  let (subscriptions_tx, mut subscriptions_rx) = mpsc::channel::<Command<Client>>(32);
-  let (store_tx, mut store_rx) = mpsc::channel::<Command<String>>(32);
+  let clients_tx = store::spawn_clients(clients);
+  let subscriptions_tx = subscriptions::spawn();
+  let (inbox_tx, mut inbox_rx) = mpsc::channel::<Command<String>>(32);
+  // Unbounded so that a slow notifier never holds up the store actors.
+  let (keyspace_tx, mut keyspace_rx) = mpsc::unbounded_channel::<KeyspaceEvent>();
+  let (retained_tx, mut retained_rx) = mpsc::channel::<Command<Value>>(32);
+  let (cluster_tx, cluster_rx) = mpsc::channel::<ClusterCommand>(32);
+  // Unbounded like the keyspace channel, so that recording a mutation never holds up the store actors.
+  let (replication_tx, replication_rx) = mpsc::unbounded_channel::<ReplicationCommand>();
+  let store_tx = shard::spawn(config().store_shards, keyspace_tx, replication_tx.clone());
   
-  // TODO CWS: move this and other similar logic to the store implementations?
   tokio::spawn(async move {
-    while let Some(cmd) = clients_rx.recv().await {
-      // TODO: pass the data structure here so that it is the only one that has access?
+    let mut inboxes = HashMap::<String, String>::new();
+    while let Some(cmd) = inbox_rx.recv().await {
         match cmd {
-            Command::GetItem { key, responder } => {
-                info!("Get from client store: {:?}", key);
-                if let Some(result) = clients.lock().await.get(&key) {
-                  // TODO CWS: this clone is probably unecessary. What can we do with references here? And if we do that, can we include referenced variables in the logs?
-                  let _ = responder.send(Some(result.clone()));
-                }
-            },
             Command::SetItem { key, value, responder } => {
-                info!("Set value: {:?} for key: {:?} in the client store.", value, key);
-                let result = clients.lock().await.insert(key, value);
-                let _ = responder.send(result);
+                info!("Open inbox {:?} for requester {:?}.", key, value);
+                let _ = responder.send(inboxes.insert(key, value));
             },
             Command::UnsetItem { key, responder } => {
-                info!("Unset key: {:?} in the client store.", key);
-                let result = clients.lock().await.remove(&key);
-                let _ = responder.send(result);
-            },
-            _ => {
-                error!("Only Get, Set and Unset may be used with clients.");
-            }
-        }
-    }
-  });
-
-  tokio::spawn(async move {
-    while let Some(cmd) = subscriptions_rx.recv().await {
-      // TODO: pass the data structure here so that it is the only one that has access?
-        match cmd {
-            Command::GetCollection { key, responder } => {
-                if let Some(result) = subscriptions.lock().await.get(&key) {
-                  info!("Get key {:?} from the subscriptions store. Result: {:?}", key, result);
-                  // TODO CWS: this clone is probably unecessary. What can we do with references here?
-                  let _ = responder.send(Some(result.clone()));
-                }
-            },
-            Command::RemoveFromCollection { key, value, responder } => {
-                let mut subscriptions = subscriptions.lock().await;
-                let subscriptions_option = subscriptions.get_mut(&key);
-                let result = match subscriptions_option {
-                  Some(collection) => collection.remove(&value),
-                  None => false
-                };
-                info!("Remove key {:?} from the subscriptions store. Result: {:?}", key, result);
+                let result = inboxes.remove(&key);
+                info!("Close inbox {:?}. Requester: {:?}", key, result);
                 let _ = responder.send(result);
             },
-            Command::AddToCollection { key, value, responder } => {
-                let mut subscriptions = subscriptions.lock().await;
-                let subscriptions_option = subscriptions.get_mut(&key);
-                let result = match subscriptions_option {
-                  Some(subscription) => subscription.insert(value),
-                  None => {
-                    let mut subscription = HashSet::new();
-                    subscription.insert(value);
-                    let insert_result = subscriptions.insert(key, subscription);
-                    if insert_result.is_some() {
-                      false
-                    } else {
-                      true
-                    }
-                  }
-                };
-                info!("Add to collection in the subscriptions store. Result: {:?}", result);
-                let _ = responder.send(result);
-            }
             _ => {
-                error!("Only Get, Set and Unset may be used with subscriptions.");
+                error!("Only Set and Unset may be used with inboxes.");
             }
         }
     }
   });
 
   tokio::spawn(async move {
-    while let Some(cmd) = store_rx.recv().await {
-      // TODO: pass the data structure here so that it is the only one that has access?
+    let mut retained = HashMap::<String, Value>::new();
+    while let Some(cmd) = retained_rx.recv().await {
         match cmd {
             Command::GetItem { key, responder } => {
-                if let Some(result) = string_store.lock().await.get(&key) {
-                  info!("Get key {:?} in the string store. Result: {:?}", key, result);
-                  let _ = responder.send(Some(String::from(result)));
-                }
+                let result = retained.get(&key).cloned();
+                info!("Get retained message of topic {:?}. Result: {:?}", key, result);
+                let _ = responder.send(result);
             },
             Command::SetItem { key, value, responder } => {
-                let result = string_store.lock().await.insert(key, value);
-                info!("Set key in the string store. Result: {:?}. Current store: {:?}", result, string_store.lock().await);
-                let _ = responder.send(result);
+                info!("Retain message {:?} on topic {:?}.", value, key);
+                let _ = responder.send(retained.insert(key, value));
             },
             Command::UnsetItem { key, responder } => {
-                let result = string_store.lock().await.remove(&key);
-                info!("Unset key in the string store. Key: {:?}, Result: {:?}, Current store: {:?}", key, result, string_store.lock().await);
+                let result = retained.remove(&key);
+                info!("Clear retained message of topic {:?}. Previous: {:?}", key, result);
                 let _ = responder.send(result);
             },
-            Command::RemoveFromCollection { key, value, responder } => {
-                let mut collection_store = collection_store.lock().await;
-                let collection_option = collection_store.get_mut(&key);
-                let result = match collection_option {
-                  Some(collection) => collection.remove(&value),
-                  None => false
-                };
-                info!("Remove from collection in the string store. Key: {:?}, Value: {:?} Result: {:?}, Current store: {:?}", key, value, result, string_store.lock().await);
-                let _ = responder.send(result);
+            Command::GetKeys { responder } => {
+                let _ = responder.send(retained.keys().cloned().collect());
             },
-            Command::AddToCollection { key, value, responder } => {
-                let mut collection_store = collection_store.lock().await;
-                let collection_option = collection_store.get_mut(&key);
-                let result = match collection_option {
-                  Some(collection) => collection.insert(value),
-                  None => false
-                };
-                info!("Add to collection in the string store. Key: {:?}, Result: {:?}, Current store: {:?}", key, result, string_store.lock().await);
-                let _ = responder.send(result);
-            }
-            Command::GetCollection { key, responder } => {
-                let collection_store = collection_store.lock().await;
-                let collection_option = collection_store.get(&key);
-                let result = match collection_option {
-                    Some(collection) => Some(collection.clone()),
-                    None => None
-                };
-                info!("Get collection in the string store. Key: {:?}, Result: {:?}, Current store: {:?}", key, result, string_store.lock().await);
-                let _ = responder.send(result);
+            _ => {
+                error!("Only Get, Set, Unset and GetKeys may be used with retained messages.");
             }
         }
     }
   });
 
+  let keyspace_subscriptions_tx = subscriptions_tx.clone();
+  tokio::spawn(async move {
+    while let Some(event) = keyspace_rx.recv().await {
+      match handler::keyspace_handler(event, keyspace_subscriptions_tx.clone()).await {
+        Ok(_) => debug!("Keyspace watchers notified"),
+        Err(_) => error!("#keyspace_handler error")
+      }
+    }
+  });
+
+  if let Some(path) = config().import_snapshot.clone() {
+    match snapshot::import_file(&path, ImportMode::Merge, store_tx.clone(), retained_tx.clone()).await {
+      Ok(summary) => println!("Imported {}: {} values, {} retained messages", path.display(), summary.values, summary.retained),
+      Err(message) => {
+        eprintln!("Error importing {}: {}", path.display(), message);
+        process::exit(1);
+      }
+    }
+  }
+
+  let log_tx = event_log::spawn(config().data_dir.clone(), config().log_retention);
+
+  tokio::spawn(replication::run(replication_rx, config().replication_backlog));
+  if let Some(primary) = config().replica_of.clone() {
+    let secret = match config().cluster_secret.clone() {
+      Some(secret) => secret,
+      None => {
+        eprintln!("PUBSUB_REPLICA_OF requires PUBSUB_CLUSTER_SECRET");
+        process::exit(1);
+      }
+    };
+    replication::set_replica();
+    tokio::spawn(replication::follow_primary(primary, secret, store_tx.clone(), config().heartbeat_interval, config().failover_timeout));
+  }
+
+  if !config().cluster_peers.is_empty() && config().cluster_secret.is_none() {
+    eprintln!("PUBSUB_CLUSTER_PEERS requires PUBSUB_CLUSTER_SECRET");
+    process::exit(1);
+  }
+  let cluster_state = ClusterState::new(config().advertise_url.clone(), config().cluster_peers.clone(), config().cluster_secret.clone());
+  tokio::spawn(cluster::run(cluster_rx, cluster_state, subscriptions_tx.clone(), config().cluster_sync_interval));
+
   let health_route = warp::path!("health").and_then(handler::health_handler);
 
   let register = warp::path("register");
@@ -173,31 +168,244 @@ async fn main() {
       .and(with_clients(clients_tx.clone()))
       .and_then(handler::unregister_handler));
 
+  let cluster_routes = warp::path("cluster")
+    .and(warp::header::optional::<String>("authorization"))
+    .and_then(handler::cluster_auth_handler)
+    .untuple_one()
+    .and(warp::path("publish")
+      .and(warp::post())
+      .and(warp::body::json())
+      .and(with_subscriptions(subscriptions_tx.clone()))
+      .and_then(handler::cluster_publish_handler)
+      .or(warp::path("topics")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_cluster(cluster_tx.clone()))
+        .and_then(handler::cluster_topics_handler))
+      .or(warp::path("peers")
+        .and(warp::get())
+        .and(with_cluster(cluster_tx.clone()))
+        .and_then(handler::cluster_peers_handler)))
+    .recover(handler::auth_rejection_handler);
+
+  let replication_routes = warp::path("replication")
+    .and(warp::header::optional::<String>("authorization"))
+    .and_then(handler::cluster_auth_handler)
+    .untuple_one()
+    .and(warp::path("stream")
+      .and(warp::get())
+      .and(warp::query())
+      .and(with_replication(replication_tx.clone()))
+      .and(with_store(store_tx.clone()))
+      .and_then(handler::replication_stream_handler)
+      .or(warp::path("status")
+        .and(warp::get())
+        .and(with_replication(replication_tx.clone()))
+        .and_then(handler::replication_status_handler))
+      .or(warp::path("promote")
+        .and(warp::post())
+        .and_then(handler::promote_handler)))
+    .recover(handler::auth_rejection_handler);
+
+  let admin = warp::path("admin");
+  let admin_routes = admin
+    .and(warp::header::optional::<String>("authorization"))
+    .and_then(handler::admin_auth_handler)
+    .untuple_one()
+    .and(warp::path("clients")
+      .and(warp::path::end())
+      .and(warp::get())
+      .and(with_subscriptions(subscriptions_tx.clone()))
+      .and(with_clients(clients_tx.clone()))
+      .and_then(handler::admin_clients_handler)
+      .or(warp::path("clients")
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_subscriptions(subscriptions_tx.clone()))
+        .and(with_clients(clients_tx.clone()))
+        .and(with_cluster(cluster_tx.clone()))
+        .and_then(handler::admin_disconnect_handler))
+      .or(warp::path("topics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_subscriptions(subscriptions_tx.clone()))
+        .and_then(handler::admin_topics_handler))
+      .or(warp::path("topics")
+        .and(with_tail())
+        .and(warp::delete())
+        .and(with_subscriptions(subscriptions_tx.clone()))
+        .and(with_retained(retained_tx.clone()))
+        .and(with_cluster(cluster_tx.clone()))
+        .and_then(handler::admin_purge_handler))
+      .or(warp::path("snapshot")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<SnapshotQuery>())
+        .and(with_store(store_tx.clone()))
+        .and(with_retained(retained_tx.clone()))
+        .and_then(handler::admin_export_handler))
+      .or(warp::path("snapshot")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::query::<SnapshotQuery>())
+        .and(warp::body::bytes())
+        .and(with_store(store_tx.clone()))
+        .and(with_retained(retained_tx.clone()))
+        .and_then(handler::admin_import_handler))
+      .or(warp::path("keys")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<ScanQuery>())
+        .and(with_store(store_tx.clone()))
+        .and_then(handler::admin_scan_handler))
+      .or(warp::path("keys")
+        .and(with_tail())
+        .and(warp::get())
+        .and(with_store(store_tx.clone()))
+        .and_then(handler::admin_key_handler))
+      .or(warp::path("keys")
+        .and(with_tail())
+        .and(warp::delete())
+        .and(with_store(store_tx.clone()))
+        .and_then(handler::admin_delete_key_handler)))
+    .recover(handler::auth_rejection_handler);
+
   let ws_route = warp::path("ws")
-    .and(warp::ws())
+    .and(with_handshake())
     .and(warp::path::param())
-    .and(with_subscriptions(subscriptions_tx))
-    .and(with_clients(clients_tx.clone()))
-    .and(with_store(store_tx))
+    .and(warp::header::optional::<String>("sec-websocket-protocol"))
+    .and(warp::query::<WillOptions>())
+    .and(with_channels(Channels {
+      subscriptions_tx,
+      clients_tx: clients_tx.clone(),
+      store_tx,
+      inbox_tx,
+      log_tx,
+      retained_tx,
+      cluster_tx,
+      limiter: RateLimiter::new(config().rate_limits.clone())
+    }))
     .and_then(handler::ws_handler);
 
   let routes = health_route
     .or(register_routes)
+    .or(cluster_routes)
+    .or(replication_routes)
+    .or(admin_routes)
     .or(ws_route)
     .with(warp::cors().allow_any_origin());
 
+  let service = warp::service(routes);
+  let make_service = make_service_fn(move |_| {
+    let service = service.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |mut request| {
+        Upgrade::keep(&mut request);
+        service.clone().call(request)
+      }))
+    }
+  });
+  let server = match Server::try_bind(&([127, 0, 0, 1], config().port).into()) {
+    Ok(server) => server,
+    Err(err) => {
+      eprintln!("Cannot listen on port {}: {}", config().port, err);
+      process::exit(1);
+    }
+  };
   println!("Server started");
-  warp::serve(routes).run(([127, 0, 0, 1], 8000)).await;
+  if let Err(err) = server.serve(make_service).await {
+    eprintln!("Server error: {}", err);
+    process::exit(1);
+  }
+}
+
+/// The options of the snapshot commands: the server URL, whether `flag` was given, and the file.
+fn snapshot_args(args: &[String], flag: &str) -> Result<(String, bool, Option<String>), String> {
+  let mut url = format!("http://127.0.0.1:{}", config().port);
+  let mut flagged = false;
+  let mut file = None;
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--url" => url = args.next().ok_or(format!("--url needs a value\n{}", USAGE))?.trim_end_matches('/').to_string(),
+      arg if arg == flag => flagged = true,
+      arg if arg.starts_with("--") || file.is_some() => return Err(format!("Unexpected argument {}\n{}", arg, USAGE)),
+      arg => file = Some(arg.to_string())
+    }
+  }
+  Ok((url, flagged, file))
+}
+
+fn admin_token() -> Result<&'static str, String> {
+  config().admin_token.as_deref().ok_or(String::from("Set PUBSUB_ADMIN_TOKEN to the admin token of the server"))
+}
+
+async fn export_command(args: &[String]) -> Result<(), String> {
+  let (url, retained, file) = snapshot_args(args, "--retained")?;
+  let token = admin_token()?;
+  match file {
+    Some(file) => {
+      let mut out = BufWriter::new(File::create(&file).map_err(|err| format!("Cannot create {}: {}", file, err))?);
+      snapshot::download(&url, token, retained, &mut out).await
+    },
+    None => snapshot::download(&url, token, retained, &mut io::stdout().lock()).await
+  }
+}
+
+async fn import_command(args: &[String]) -> Result<(), String> {
+  let (url, replace, file) = snapshot_args(args, "--replace")?;
+  let file = file.ok_or(format!("import needs a snapshot file\n{}", USAGE))?;
+  let snapshot = fs::read(&file).map_err(|err| format!("Cannot read {}: {}", file, err))?;
+  let mode = if replace { ImportMode::Replace } else { ImportMode::Merge };
+  let summary = snapshot::upload(&url, admin_token()?, mode, snapshot).await?;
+  println!("{}", serde_json::to_string(&summary).unwrap_or_default());
+  Ok(())
 }
 
 fn with_clients(clients_tx: Sender<Command<Client>>) -> impl Filter<Extract = (Sender<Command<Client>>,), Error = Infallible> + Clone {
     warp::any().map(move || clients_tx.clone())
 }
 
-fn with_subscriptions(subscriptions_tx: Sender<Command<Client>>) -> impl Filter<Extract = (Sender<Command<Client>>,), Error = Infallible> + Clone {
+fn with_subscriptions(subscriptions_tx: SubscriptionsTx) -> impl Filter<Extract = (SubscriptionsTx,), Error = Infallible> + Clone {
     warp::any().map(move || subscriptions_tx.clone())
 }
 
-fn with_store(store_tx: Sender<Command<String>>) -> impl Filter<Extract = (Sender<Command<String>>,), Error = Infallible> + Clone {
+fn with_cluster(cluster_tx: Sender<ClusterCommand>) -> impl Filter<Extract = (Sender<ClusterCommand>,), Error = Infallible> + Clone {
+    warp::any().map(move || cluster_tx.clone())
+}
+
+fn with_replication(replication_tx: UnboundedSender<ReplicationCommand>) -> impl Filter<Extract = (UnboundedSender<ReplicationCommand>,), Error = Infallible> + Clone {
+    warp::any().map(move || replication_tx.clone())
+}
+
+fn with_retained(retained_tx: Sender<Command<Value>>) -> impl Filter<Extract = (Sender<Command<Value>>,), Error = Infallible> + Clone {
+    warp::any().map(move || retained_tx.clone())
+}
+
+fn with_store(store_tx: StoreShards) -> impl Filter<Extract = (StoreShards,), Error = Infallible> + Clone {
     warp::any().map(move || store_tx.clone())
-}
\ No newline at end of file
+}
+
+/// The rest of the path, percent-decoded, so that keys and topics may contain slashes.
+fn with_tail() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::path::tail().and_then(|tail: Tail| async move {
+        match percent_decode_str(tail.as_str()).decode_utf8() {
+            Ok(decoded) if !decoded.is_empty() => Ok(String::from(decoded)),
+            _ => Err(warp::reject::not_found())
+        }
+    })
+}
+
+/// Checks the headers of a WebSocket handshake, which the handler then finishes.
+fn with_handshake() -> impl Filter<Extract = (Handshake,), Error = Rejection> + Clone {
+    warp::ws()
+        .and(warp::header::<String>("sec-websocket-key"))
+        .and(warp::header::optional::<String>("sec-websocket-extensions"))
+        .and(warp::ext::optional::<Upgrade>())
+        .map(|_: warp::ws::Ws, key, extensions, upgrade| Handshake { key, extensions, upgrade })
+}
+
+fn with_channels(channels: Channels) -> impl Filter<Extract = (Channels,), Error = Infallible> + Clone {
+    warp::any().map(move || channels.clone())
+}
@@ -0,0 +1,127 @@
+use std::collections::BTreeSet;
+use crate::serialize::{EventFormat, Feature, Hello, HelloReply, RequestAction, SocketRequest};
+
+/// Version spoken by clients that never send a Hello. Failed requests are only logged.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// From version 2 on, failed requests are answered with a RequestFailed error.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+pub const FEATURES: [Feature; 10] = [
+    Feature::Patches,
+    Feature::Reliable,
+    Feature::QueueGroups,
+    Feature::Presence,
+    Feature::Retain,
+    Feature::DirectMessages,
+    Feature::Requests,
+    Feature::Consumers,
+    Feature::Collections,
+    Feature::Paths
+];
+
+/// What a connection negotiated in its Hello. Connections that skip the Hello speak version 1 with
+/// every feature, as clients did before the Hello existed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub version: u32,
+    pub features: BTreeSet<Feature>
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session { version: MIN_PROTOCOL_VERSION, features: FEATURES.into_iter().collect() }
+    }
+}
+
+impl Session {
+    /// Settles on the highest version both sides speak and the requested features this server has.
+    /// Fails with the reply to send when the client only speaks versions older than this server's.
+    pub fn negotiate(hello: Hello) -> Result<Session, HelloReply> {
+        let version = hello.version.min(PROTOCOL_VERSION);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(HelloReply { version, min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION, features: Vec::new() });
+        }
+        let features = hello.features.into_iter().filter(|feature| FEATURES.contains(feature)).collect();
+        Ok(Session { version, features })
+    }
+
+    pub fn reply(&self) -> HelloReply {
+        HelloReply { version: self.version, min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION, features: self.features.iter().copied().collect() }
+    }
+
+    pub fn reports_failures(&self) -> bool {
+        self.version >= 2
+    }
+
+    /// The first feature `request` needs that this session did not negotiate.
+    pub fn missing_feature(&self, request: &SocketRequest) -> Option<Feature> {
+        required_features(request).into_iter().find(|feature| !self.features.contains(feature))
+    }
+}
+
+fn required_features(request: &SocketRequest) -> Vec<Feature> {
+    let mut features = match request.action {
+        RequestAction::DirectMessage => vec![Feature::DirectMessages],
+        RequestAction::Request | RequestAction::Reply => vec![Feature::Requests],
+        RequestAction::Ack => vec![Feature::Reliable],
+        RequestAction::GetPresence => vec![Feature::Presence],
+        RequestAction::CreateConsumer | RequestAction::GetConsumer | RequestAction::ResetConsumer |
+        RequestAction::DeleteConsumer | RequestAction::FetchFromConsumer => vec![Feature::Consumers],
+        RequestAction::PushToList | RequestAction::PopFromList | RequestAction::GetListRange |
+        RequestAction::SetField | RequestAction::GetField | RequestAction::DeleteField |
+        RequestAction::AddToSortedSet | RequestAction::GetSortedSetRangeByRank | RequestAction::GetSortedSetRangeByScore => vec![Feature::Collections],
+        RequestAction::SetPath | RequestAction::MergePath | RequestAction::DeletePath => vec![Feature::Paths],
+        _ => Vec::new()
+    };
+    if request.format == Some(EventFormat::Patch) {
+        features.push(Feature::Patches);
+    }
+    if request.reliable.unwrap_or(false) {
+        features.push(Feature::Reliable);
+    }
+    if request.group.is_some() {
+        features.push(Feature::QueueGroups);
+    }
+    if request.presence.unwrap_or(false) {
+        features.push(Feature::Presence);
+    }
+    if request.retain.unwrap_or(false) {
+        features.push(Feature::Retain);
+    }
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::serialize::{EventFormat, Feature, Hello, RequestAction, SocketRequest};
+    use super::{Session, PROTOCOL_VERSION};
+
+    #[test]
+    fn test_negotiate() {
+        let session = Session::negotiate(Hello { version: 7, features: vec![Feature::Presence, Feature::Patches] }).unwrap();
+        assert_eq!(session.version, PROTOCOL_VERSION);
+        assert!(session.reports_failures());
+        assert_eq!(session.reply().features, vec![Feature::Patches, Feature::Presence]);
+
+        let hello = serde_json::from_value(serde_json::json!({ "version": 2, "features": ["Presence", "Teleport"] })).unwrap();
+        assert_eq!(Session::negotiate(hello).unwrap().reply().features, vec![Feature::Presence]);
+
+        let rejected = Session::negotiate(Hello { version: 0, features: Vec::new() }).unwrap_err();
+        assert_eq!((rejected.min_version, rejected.max_version), (1, PROTOCOL_VERSION));
+        assert!(!Session::default().reports_failures());
+    }
+
+    #[test]
+    fn test_missing_feature() {
+        let session = Session::negotiate(Hello { version: 2, features: vec![Feature::Presence] }).unwrap();
+        let mut subscribe = SocketRequest::new(RequestAction::Subscribe, String::from("1"), String::from("a"), None);
+        subscribe.presence = Some(true);
+        assert_eq!(session.missing_feature(&subscribe), None);
+        subscribe.format = Some(EventFormat::Patch);
+        assert_eq!(session.missing_feature(&subscribe), Some(Feature::Patches));
+        assert_eq!(Session::default().missing_feature(&subscribe), None);
+
+        let push = SocketRequest::new(RequestAction::PushToList, String::from("1"), String::from("a"), None);
+        assert_eq!(session.missing_feature(&push), Some(Feature::Collections));
+    }
+}
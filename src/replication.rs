@@ -0,0 +1,380 @@
+use std::{collections::VecDeque, sync::atomic::{AtomicBool, Ordering}, time::{Duration, Instant}};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use tokio::{sync::{mpsc::{self, UnboundedReceiver, UnboundedSender}, oneshot::{self, error::RecvError}}, time};
+use warp::hyper::{self, body::HttpBody, Body, Request};
+use crate::serialize::StoredValue;
+use crate::snapshot;
+use crate::store::{Responder, Store};
+use crate::shard::StoreShards;
+
+/// A change made by the store, replayed by replicas to keep their string and collection stores
+/// identical to the primary's. Path updates are replicated as a Set of the resulting document.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Mutation {
+    Set {
+        key: String,
+        value: Value
+    },
+    Unset {
+        key: String
+    },
+    AddToCollection {
+        key: String,
+        value: Value
+    },
+    RemoveFromCollection {
+        key: String,
+        value: Value
+    },
+    /// Replaces the whole collection, creating it when missing, as a restore does.
+    SetCollection {
+        key: String,
+        members: Vec<Value>
+    },
+    /// Removes every value held under the key.
+    DeleteKey {
+        key: String
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplicatedMutation {
+    pub offset: u64,
+    pub mutation: Mutation
+}
+
+/// One line of the replication stream a primary sends its replicas.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum StreamLine {
+    Mutation(ReplicatedMutation),
+    /// Starts a resync: the replica empties its store, then restores the values that follow.
+    Reset,
+    Restore {
+        key: String,
+        value: StoredValue
+    },
+    /// Ends a resync. The replica holds the primary's store and follows the mutations from `head` on.
+    Resynced {
+        head: u64
+    },
+    /// Sent every heartbeat interval with the primary's next offset, so that replicas notice a dead primary.
+    Heartbeat {
+        head: u64
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Primary,
+    Replica
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ReplicationStatus {
+    pub role: Role,
+    pub head: u64,
+    pub followers: usize
+}
+
+/// Commands of the replication actor, which keeps the log of store mutations and streams it to followers.
+#[derive(Debug)]
+pub enum ReplicationCommand {
+    Record {
+        mutation: Mutation,
+    },
+    /// Returns the mutations from `from` on and a receiver of the ones recorded afterwards.
+    Follow {
+        from: u64,
+        responder: Responder<(Backlog, UnboundedReceiver<ReplicatedMutation>)>,
+    },
+    GetStatus {
+        responder: Responder<ReplicationStatus>,
+    }
+}
+
+/// What a new follower missed before the mutations it receives live.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backlog {
+    Mutations(Vec<ReplicatedMutation>),
+    /// The follower's offset is no longer in the log, so it must be sent the store before following from `head`.
+    Resync {
+        head: u64
+    }
+}
+
+static REPLICA: AtomicBool = AtomicBool::new(false);
+
+/// Replicas refuse writes from clients until they are promoted.
+pub fn is_replica() -> bool {
+    REPLICA.load(Ordering::SeqCst)
+}
+
+pub fn role() -> Role {
+    if is_replica() { Role::Replica } else { Role::Primary }
+}
+
+pub fn set_replica() {
+    REPLICA.store(true, Ordering::SeqCst);
+}
+
+/// Makes this node a primary. Returns whether it was a replica until now.
+pub fn promote() -> bool {
+    let promoted = REPLICA.swap(false, Ordering::SeqCst);
+    if promoted {
+        warn!("Promoted to primary");
+    }
+    promoted
+}
+
+pub struct Replication;
+impl Replication {
+    pub fn record(mutation: Mutation, replication_tx: &UnboundedSender<ReplicationCommand>) {
+        if replication_tx.send(ReplicationCommand::Record { mutation }).is_err() {
+            error!("#replication error: the replication log is gone");
+        }
+    }
+
+    pub async fn follow(from: u64, replication_tx: UnboundedSender<ReplicationCommand>) -> Result<(Backlog, UnboundedReceiver<ReplicatedMutation>), RecvError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let _ = replication_tx.send(ReplicationCommand::Follow { from, responder: resp_tx });
+        resp_rx.await
+    }
+
+    pub async fn get_status(replication_tx: UnboundedSender<ReplicationCommand>) -> Result<ReplicationStatus, RecvError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let _ = replication_tx.send(ReplicationCommand::GetStatus { responder: resp_tx });
+        resp_rx.await
+    }
+}
+
+/// The last `capacity` mutations, kept for followers that reconnect, and the followers to stream new ones to.
+pub struct ReplicationState {
+    /// Offset of the oldest mutation in `log`.
+    first: u64,
+    log: VecDeque<ReplicatedMutation>,
+    capacity: usize,
+    followers: Vec<UnboundedSender<ReplicatedMutation>>
+}
+
+impl ReplicationState {
+    pub fn new(capacity: usize) -> ReplicationState {
+        ReplicationState { first: 0, log: VecDeque::new(), capacity: capacity.max(1), followers: Vec::new() }
+    }
+
+    fn head(&self) -> u64 {
+        self.first + self.log.len() as u64
+    }
+
+    fn handle(&mut self, cmd: ReplicationCommand) {
+        match cmd {
+            ReplicationCommand::Record { mutation } => {
+                let entry = ReplicatedMutation { offset: self.head(), mutation };
+                self.followers.retain(|follower| follower.send(entry.clone()).is_ok());
+                self.log.push_back(entry);
+                if self.log.len() > self.capacity {
+                    self.log.pop_front();
+                    self.first += 1;
+                }
+            },
+            ReplicationCommand::Follow { from, responder } => {
+                let (follower_tx, follower_rx) = mpsc::unbounded_channel();
+                // A follower ahead of the head followed an earlier run of this primary.
+                let backlog = match from < self.first || from > self.head() {
+                    true => {
+                        info!("New follower from offset {}, outside the log, resyncing it at {}", from, self.head());
+                        Backlog::Resync { head: self.head() }
+                    },
+                    false => {
+                        info!("New follower from offset {}", from);
+                        Backlog::Mutations(self.log.iter().skip((from - self.first) as usize).cloned().collect())
+                    }
+                };
+                self.followers.push(follower_tx);
+                let _ = responder.send((backlog, follower_rx));
+            },
+            ReplicationCommand::GetStatus { responder } => {
+                self.followers.retain(|follower| !follower.is_closed());
+                let _ = responder.send(ReplicationStatus { role: role(), head: self.head(), followers: self.followers.len() });
+            }
+        }
+    }
+}
+
+pub async fn run(mut replication_rx: UnboundedReceiver<ReplicationCommand>, capacity: usize) {
+    let mut state = ReplicationState::new(capacity);
+    while let Some(cmd) = replication_rx.recv().await {
+        state.handle(cmd);
+    }
+}
+
+/// Streams the primary's mutations into the local store until this node is promoted, either by hand
+/// or, with a `failover_timeout`, once the primary has been silent for that long.
+pub async fn follow_primary(primary: String, secret: String, store_tx: StoreShards, heartbeat_interval: Duration, failover_timeout: Option<Duration>) {
+    let http = hyper::Client::new();
+    let mut next = 0;
+    let mut last_contact = Instant::now();
+    // Without automatic failover, a stalled connection is still dropped and retried.
+    let read_timeout = failover_timeout.unwrap_or(heartbeat_interval * 3);
+    while is_replica() {
+        let request = Request::get(format!("{}/replication/stream?from={}", primary, next))
+            .header("authorization", format!("Bearer {}", secret))
+            .body(Body::empty());
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                error!("Invalid primary URL {}: {}", primary, err);
+                return;
+            }
+        };
+        match http.request(request).await {
+            Ok(mut response) if response.status().is_success() => {
+                info!("Following primary {} from offset {}", primary, next);
+                let mut buffer = Vec::new();
+                while is_replica() {
+                    let chunk = match time::timeout(read_timeout, response.body_mut().data()).await {
+                        Ok(Some(Ok(chunk))) => chunk,
+                        Ok(Some(Err(err))) => {
+                            warn!("Replication stream from {} failed: {}", primary, err);
+                            break;
+                        },
+                        Ok(None) => break,
+                        Err(_) => {
+                            warn!("Primary {} sent nothing for {:?}", primary, read_timeout);
+                            break;
+                        }
+                    };
+                    last_contact = Instant::now();
+                    buffer.extend_from_slice(&chunk);
+                    while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                        let line: Vec<u8> = buffer.drain(..=end).collect();
+                        match serde_json::from_slice::<StreamLine>(&line) {
+                            Ok(StreamLine::Mutation(entry)) => {
+                                apply(entry.mutation, store_tx.clone()).await;
+                                next = entry.offset + 1;
+                            },
+                            Ok(StreamLine::Heartbeat { head }) => debug!("Heartbeat from {}, head {}", primary, head),
+                            Ok(StreamLine::Reset) => {
+                                warn!("Primary {} no longer holds offset {}, resyncing", primary, next);
+                                if snapshot::clear_store(store_tx.clone()).await.is_err() {
+                                    error!("Error emptying the store for a resync");
+                                }
+                            },
+                            Ok(StreamLine::Restore { key, value }) => {
+                                if Store::restore(key, value, store_tx.clone()).await.is_err() {
+                                    error!("Error restoring a resynced value");
+                                }
+                            },
+                            Ok(StreamLine::Resynced { head }) => {
+                                info!("Resynced from primary {} at offset {}", primary, head);
+                                next = head;
+                            },
+                            Err(err) => error!("Invalid replication line from {}: {}", primary, err)
+                        }
+                    }
+                }
+            },
+            Ok(response) => warn!("Primary {} refused replication with {}", primary, response.status()),
+            Err(err) => warn!("Cannot reach primary {}: {}", primary, err)
+        }
+        if failover_timeout.is_some_and(|timeout| last_contact.elapsed() >= timeout) {
+            warn!("Primary {} missed its heartbeats for {:?}", primary, last_contact.elapsed());
+            promote();
+            break;
+        }
+        time::sleep(heartbeat_interval).await;
+    }
+}
+
+async fn apply(mutation: Mutation, store_tx: StoreShards) {
+    let result = match mutation {
+        Mutation::Set { key, value } => Store::set(key, value, store_tx).await.map(|_| ()),
+        Mutation::Unset { key } => Store::unset(key, store_tx).await.map(|_| ()),
+        Mutation::AddToCollection { key, value } => Store::add_to_collection(key, value, store_tx).await.map(|_| ()),
+        Mutation::RemoveFromCollection { key, value } => Store::remove_value_from_collection(key, value, store_tx).await.map(|_| ()),
+        Mutation::SetCollection { key, members } => Store::restore(key, StoredValue::Collection(members), store_tx).await.map(|_| ()),
+        Mutation::DeleteKey { key } => Store::delete(key, store_tx).await.map(|_| ())
+    };
+    if result.is_err() {
+        error!("Error applying a replicated mutation");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::{mpsc, oneshot};
+    use crate::serialize::StoredValue;
+    use crate::shard;
+    use crate::store::Store;
+    use super::{apply, run, Backlog, Mutation, Replication, ReplicatedMutation, ReplicationCommand, ReplicationState, StreamLine};
+
+    #[tokio::test]
+    async fn test_follow_backlog_and_live_mutations() {
+        let mut state = ReplicationState::new(10);
+        state.handle(ReplicationCommand::Record { mutation: Mutation::Set { key: String::from("a"), value: json!(1) } });
+        state.handle(ReplicationCommand::Record { mutation: Mutation::Unset { key: String::from("a") } });
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        state.handle(ReplicationCommand::Follow { from: 1, responder: resp_tx });
+        let (backlog, mut live) = resp_rx.await.unwrap();
+        assert_eq!(backlog, Backlog::Mutations(vec![ReplicatedMutation { offset: 1, mutation: Mutation::Unset { key: String::from("a") } }]));
+
+        state.handle(ReplicationCommand::Record { mutation: Mutation::Set { key: String::from("b"), value: json!(2) } });
+        assert_eq!(live.recv().await.unwrap().offset, 2);
+    }
+
+    #[tokio::test]
+    async fn test_resync_outside_the_log() {
+        let mut state = ReplicationState::new(2);
+        for value in 0..3 {
+            state.handle(ReplicationCommand::Record { mutation: Mutation::Set { key: String::from("a"), value: json!(value) } });
+        }
+        for (from, expected) in [(0, Backlog::Resync { head: 3 }), (4, Backlog::Resync { head: 3 }), (3, Backlog::Mutations(Vec::new()))] {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            state.handle(ReplicationCommand::Follow { from, responder: resp_tx });
+            assert_eq!(resp_rx.await.unwrap().0, expected);
+        }
+        let (resp_tx, resp_rx) = oneshot::channel();
+        state.handle(ReplicationCommand::Follow { from: 1, responder: resp_tx });
+        match resp_rx.await.unwrap().0 {
+            Backlog::Mutations(entries) => assert_eq!(entries.iter().map(|entry| entry.offset).collect::<Vec<u64>>(), vec![1, 2]),
+            backlog => panic!("expected mutations, got {:?}", backlog)
+        }
+    }
+
+    #[test]
+    fn test_stream_line_format() {
+        let line = StreamLine::Mutation(ReplicatedMutation { offset: 0, mutation: Mutation::Set { key: String::from("a"), value: json!(1) } });
+        assert_eq!(json!(line), json!({ "Mutation": { "offset": 0, "mutation": { "Set": { "key": "a", "value": 1 } } } }));
+        assert_eq!(serde_json::from_str::<StreamLine>(r#"{"Heartbeat":{"head":3}}"#).unwrap(), StreamLine::Heartbeat { head: 3 });
+        assert_eq!(json!(StreamLine::Reset), json!("Reset"));
+        assert_eq!(json!(StreamLine::Resynced { head: 3 }), json!({ "Resynced": { "head": 3 } }));
+    }
+
+    #[tokio::test]
+    async fn test_replicate_restored_collections() {
+        let (replication_tx, replication_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(replication_rx, 100));
+        let (keyspace_tx, _keyspace_rx) = mpsc::unbounded_channel();
+        let primary = shard::spawn(2, keyspace_tx.clone(), replication_tx.clone());
+        let (replica_replication_tx, _replica_replication_rx) = mpsc::unbounded_channel();
+        let replica = shard::spawn(2, keyspace_tx, replica_replication_tx);
+
+        Store::restore(String::from("a"), StoredValue::Collection(vec![json!(1), json!("x")]), primary.clone()).await.unwrap();
+        Store::add_to_collection(String::from("a"), json!(2), primary.clone()).await.unwrap().unwrap();
+        Store::restore(String::from("empty"), StoredValue::Collection(Vec::new()), primary.clone()).await.unwrap();
+        Store::restore(String::from("gone"), StoredValue::Collection(vec![json!(1)]), primary.clone()).await.unwrap();
+        Store::delete(String::from("gone"), primary.clone()).await.unwrap();
+
+        let (backlog, _live) = Replication::follow(0, replication_tx).await.unwrap();
+        let Backlog::Mutations(entries) = backlog else { panic!("expected mutations, got {:?}", backlog) };
+        for entry in entries {
+            apply(entry.mutation, replica.clone()).await;
+        }
+        assert_eq!(Store::inspect(String::from("a"), replica.clone()).await.unwrap(), vec![StoredValue::Collection(vec![json!("x"), json!(1), json!(2)])]);
+        for key in ["a", "empty", "gone"] {
+            assert_eq!(Store::inspect(String::from(key), replica.clone()).await.unwrap(), Store::inspect(String::from(key), primary.clone()).await.unwrap());
+        }
+    }
+}
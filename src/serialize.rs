@@ -1,30 +1,404 @@
-use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize, Deserializer};
+use serde_json::Value;
+use crate::config::Quotas;
+use crate::snapshot::ImportMode;
 
 
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct RegisterRequest {
     pub user_id: String,
 }
-  
-#[derive(Serialize)]
+
+#[derive(Serialize, Deserialize)]
 pub struct RegisterResponse {
     pub url: String,
 }
-  
-#[derive(Deserialize, Debug)]
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum RequestAction {
     Subscribe,
     Unsubscribe,
     Set,
     Unset,
     RemoveFromCollection,
-    AddToCollection
+    AddToCollection,
+    PushToList,
+    PopFromList,
+    GetListRange,
+    SetField,
+    GetField,
+    DeleteField,
+    AddToSortedSet,
+    GetSortedSetRangeByRank,
+    GetSortedSetRangeByScore,
+    SetPath,
+    MergePath,
+    DeletePath,
+    GetPresence,
+    DirectMessage,
+    Request,
+    Reply,
+    Ack,
+    CreateConsumer,
+    GetConsumer,
+    ResetConsumer,
+    DeleteConsumer,
+    FetchFromConsumer,
+    Get,
+    GetCollection,
+    Hello,
+    GetUsage,
+    Scan
 }
 
-#[derive(Deserialize, Debug)]
+/// What a subscriber receives when the value of a key changes: the full document, or a JSON Patch from the previous one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum EventFormat {
+    #[default]
+    Document,
+    Patch
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SocketRequest {
-    pub action: RequestAction, 
+    pub action: RequestAction,
+    pub user_id: String,
+    #[serde(default)]
+    pub topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<Value>,
+    /// JSON Pointer into the stored document for SetPath, MergePath and DeletePath.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Format of the change events delivered by a Subscribe.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<EventFormat>,
+    /// Subscribe with at-least-once delivery: events carry an id which must be acked with an Ack whose message is that id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reliable: Option<bool>,
+    /// Queue group of a Subscribe. Each message on the topic goes to one member of the group, round-robin.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Announce this Subscribe to the topic's other subscribers, with `message` as the member's metadata.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence: Option<bool>,
+    /// Keep the resulting document of a Set, Unset or path update as the topic's retained message,
+    /// delivered to every new subscriber. Unset clears it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retain: Option<bool>,
+    /// Hash field addressed by SetField, GetField and DeleteField.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    /// Deliver a Request to every subscriber of the topic rather than to one of them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fanout: Option<bool>,
+    /// Milliseconds a Request waits for its Reply, overriding the server default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Durable consumer of the topic addressed by the consumer actions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consumer: Option<String>,
+    /// Maximum number of events returned by FetchFromConsumer, or of keys returned by Scan.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+    /// Glob a Scan filters keys with: `*` matches any run of characters, `?` any single one, and `\`
+    /// escapes the next. A Scan only returns keys starting with its topic.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// Where a Scan resumes: the `cursor` of the page before. Starts from the first key when absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    /// Member score for AddToSortedSet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// Push to / pop from the head of a list instead of its tail.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub front: Option<bool>,
+    /// Inclusive rank range for GetListRange and GetSortedSetRangeByRank. Negative indexes count from the end.
+    /// Also the log offset a consumer starts from on CreateConsumer (latest when absent) or rewinds to on ResetConsumer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<i64>,
+    /// Inclusive score range for GetSortedSetRangeByScore.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>
+}
+
+impl SocketRequest {
+    /// The quota this request breaks by itself, before reaching the store.
+    pub fn exceeds(&self, quotas: &Quotas) -> Option<(ErrorCode, String)> {
+        if let Some(max) = quotas.max_key_length {
+            let longest = self.topic.len().max(self.field.as_ref().map_or(0, String::len));
+            if longest > max {
+                return Some((ErrorCode::KeyTooLong, format!("Keys are limited to {} bytes", max)));
+            }
+        }
+        if let (Some(max), Some(message)) = (quotas.max_value_size, &self.message) {
+            let size = serde_json::to_vec(message).map_or(0, |bytes| bytes.len());
+            if size > max {
+                return Some((ErrorCode::ValueTooLarge, format!("Values are limited to {} bytes, got {}", max, size)));
+            }
+        }
+        None
+    }
+
+    pub fn new(action: RequestAction, user_id: String, topic: String, message: Option<Value>) -> SocketRequest {
+        SocketRequest {
+            action,
+            user_id,
+            topic,
+            message,
+            path: None,
+            format: None,
+            reliable: None,
+            group: None,
+            presence: None,
+            retain: None,
+            field: None,
+            fanout: None,
+            timeout: None,
+            consumer: None,
+            count: None,
+            pattern: None,
+            cursor: None,
+            score: None,
+            front: None,
+            start: None,
+            stop: None,
+            min: None,
+            max: None
+        }
+    }
+}
+
+/// Published on `__keyspace__/{key}` by the store whenever `key` is changed, whoever changed it.
+#[derive(Serialize, Debug)]
+pub struct KeyspaceEvent {
+    pub key: String,
+    pub action: RequestAction
+}
+
+/// Optional capabilities of the socket protocol, declared by a client in its Hello.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Feature {
+    Patches,
+    Reliable,
+    QueueGroups,
+    Presence,
+    Retain,
+    DirectMessages,
+    Requests,
+    Consumers,
+    Collections,
+    Paths
+}
+
+/// The `message` of a Hello, sent by a client as its first request.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hello {
+    pub version: u32,
+    /// Features this server does not know of are left out, so that newer clients can still connect.
+    #[serde(default, deserialize_with = "known_features")]
+    pub features: Vec<Feature>
+}
+
+fn known_features<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Feature>, D::Error> {
+    let names = Vec::<String>::deserialize(deserializer)?;
+    Ok(names.into_iter().filter_map(|name| serde_json::from_value(Value::String(name)).ok()).collect())
+}
+
+/// The `value` of the server's answer to a Hello: the version both sides speak, the range of versions
+/// the server speaks, and the requested features it enabled.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HelloReply {
+    pub version: u32,
+    pub min_version: u32,
+    pub max_version: u32,
+    pub features: Vec<Feature>
+}
+
+/// The `value` of the answer to a GetUsage: what the client holds, next to the server's quotas.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Usage {
+    pub subscriptions: usize,
+    pub max_subscriptions: Option<usize>,
+    /// Events of reliable subscriptions awaiting an Ack.
+    pub pending_events: usize,
+    pub quotas: Quotas
+}
+
+/// A value held by the store under a key. A key holds at most one value of each kind.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "value")]
+pub enum StoredValue {
+    String(Value),
+    Collection(Vec<Value>),
+    List(Vec<Value>),
+    Hash(BTreeMap<String, Value>),
+    SortedSet(Vec<(Value, f64)>)
+}
+
+/// The `value` answering a Scan: the keys in byte order, and the cursor to pass to the next Scan,
+/// absent on the last page.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScanPage {
+    pub keys: Vec<String>,
+    pub cursor: Option<String>
+}
+
+/// A registered client as listed by the admin API.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ClientInfo {
     pub user_id: String,
+    /// Whether its socket is open, rather than registered or kept for unacked events.
+    pub connected: bool,
+    pub subscriptions: Vec<String>,
+    pub pending_events: usize
+}
+
+/// A topic with subscribers on this node, as listed by the admin API.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TopicInfo {
     pub topic: String,
-    pub message: Option<String>
+    pub subscribers: usize
+}
+
+/// Query parameters of the admin key listing, as for a Scan.
+#[derive(Deserialize, Debug, Default)]
+pub struct ScanQuery {
+    pub prefix: Option<String>,
+    pub pattern: Option<String>,
+    pub cursor: Option<String>,
+    pub count: Option<usize>
+}
+
+/// Query parameters of the admin snapshot routes: whether an export includes the retained messages,
+/// and how an import treats the current state.
+#[derive(Deserialize, Debug, Default)]
+pub struct SnapshotQuery {
+    pub retained: Option<bool>,
+    pub mode: Option<ImportMode>
+}
+
+/// Query parameters of the replication stream.
+#[derive(Deserialize, Debug)]
+pub struct ReplicationQuery {
+    pub from: Option<u64>
+}
+
+/// Query parameters of the socket route. When the connection drops without a close frame,
+/// `will_message` is Set on `will_topic` on the client's behalf.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct WillOptions {
+    pub will_topic: Option<String>,
+    /// JSON value, taken as a plain string when it does not parse.
+    pub will_message: Option<String>,
+    pub will_retain: Option<bool>
+}
+
+/// Sent to subscribers when a list, hash or sorted set changes, and back to the requesting client for reads.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SocketEvent {
+    pub action: RequestAction,
+    pub topic: String,
+    pub value: Value
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PresenceAction {
+    Join,
+    Leave
+}
+
+/// Sent to the subscribers of a topic when a member with presence enabled joins or leaves it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PresenceEvent {
+    pub presence: PresenceAction,
+    pub topic: String,
+    pub user_id: String,
+    pub meta: Value
+}
+
+/// Delivered to the recipient of a DirectMessage, whose `topic` names the recipient's user id.
+#[derive(Serialize, Debug)]
+pub struct DirectEnvelope {
+    pub from: String,
+    pub message: Value
+}
+
+/// Delivered to the responders of a Request. Their Reply must use `reply_to` as its topic.
+#[derive(Serialize, Debug)]
+pub struct RequestEnvelope {
+    pub from: String,
+    pub reply_to: String,
+    pub topic: String,
+    pub message: Value
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    NoResponders,
+    Timeout,
+    UnknownInbox,
+    InvalidConsumer,
+    UnsupportedVersion,
+    UnsupportedFeature,
+    Throttled,
+    KeyTooLong,
+    ValueTooLarge,
+    CollectionFull,
+    TooManySubscriptions,
+    TooManyClients,
+    /// The node is a replica: it refuses writes, and reads of the lists, hashes and sorted sets it does
+    /// not replicate. The primary serves them.
+    ReadOnlyReplica,
+    /// Reported from protocol version 2 on for requests that failed without a more specific error.
+    RequestFailed
+}
+
+/// Sent back to a client when its request could not be completed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SocketError {
+    pub error: ErrorCode,
+    pub action: RequestAction,
+    pub topic: String,
+    pub message: String
+}
+
+/// What a delivered event holds, so that clients need not guess it from the shape of its value.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum EventKind {
+    /// The new document of the topic.
+    #[default]
+    Document,
+    /// A JSON Patch from the previous document, for subscriptions in the Patch format.
+    Patch,
+    /// A `SocketEvent` for a change to a list, hash or sorted set.
+    Change,
+    Presence,
+    Keyspace,
+    DeadLetter
+}
+
+/// Delivered to reliable subscribers, who must Ack `id` to stop its redelivery.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReliableEvent {
+    pub id: String,
+    pub topic: String,
+    #[serde(default)]
+    pub kind: EventKind,
+    pub value: Value
+}
+
+/// Published on the dead-letter topic of an event that was never acked.
+#[derive(Serialize, Debug)]
+pub struct DeadLetter {
+    pub user_id: String,
+    pub attempts: u32,
+    pub event: Value
 }
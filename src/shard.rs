@@ -0,0 +1,463 @@
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use serde_json::Value;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedSender};
+use crate::command::{Command, PathUpdate};
+use crate::document::{set_path, merge_path, delete_path};
+use crate::replication::{Mutation, Replication, ReplicationCommand};
+use crate::serialize::{KeyspaceEvent, RequestAction, StoredValue};
+use crate::store::{SortedSet, resolve_range, member_key, member_value};
+use crate::config::config;
+
+/// Senders of the store actors. Each actor owns the keys whose hash falls on it, so commands on
+/// different keys run in parallel while commands on the same key keep their order.
+#[derive(Clone, Debug)]
+pub struct StoreShards {
+    senders: Arc<Vec<Sender<Command<Value>>>>
+}
+
+impl StoreShards {
+    pub fn new(senders: Vec<Sender<Command<Value>>>) -> StoreShards {
+        assert!(!senders.is_empty(), "the store needs at least one shard");
+        StoreShards { senders: Arc::new(senders) }
+    }
+
+    /// The senders of every actor.
+    pub fn all(&self) -> impl Iterator<Item = Sender<Command<Value>>> + '_ {
+        self.senders.iter().cloned()
+    }
+
+    /// The sender of the actor owning `key`.
+    pub fn shard(&self, key: &str) -> Sender<Command<Value>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.senders[(hasher.finish() % self.senders.len() as u64) as usize].clone()
+    }
+}
+
+/// Spawns `count` store actors and returns their senders.
+pub fn spawn(count: usize, keyspace_tx: UnboundedSender<KeyspaceEvent>, replication_tx: UnboundedSender<ReplicationCommand>) -> StoreShards {
+    let max_members = config().quotas.max_collection_members;
+    let senders = (0..count.max(1)).map(|_| {
+        let (store_tx, store_rx) = mpsc::channel::<Command<Value>>(32);
+        tokio::spawn(run(store_rx, keyspace_tx.clone(), replication_tx.clone(), max_members));
+        store_tx
+    }).collect();
+    StoreShards::new(senders)
+}
+
+/// A store actor, holding the string, collection, list, hash and sorted set values of its keys.
+/// Collections, lists, hashes and sorted sets refuse new members once they hold `max_members`.
+pub async fn run(mut store_rx: Receiver<Command<Value>>, keyspace_tx: UnboundedSender<KeyspaceEvent>, replication_tx: UnboundedSender<ReplicationCommand>, max_members: Option<usize>) {
+    let full = |key: &str, len: usize| match max_members {
+        Some(max) if len >= max => Err(format!("{} already holds the maximum of {} members", key, max)),
+        _ => Ok(())
+    };
+    let mut string_store = HashMap::<String, Value>::new();
+    let mut collection_store = HashMap::<String, HashSet<String>>::new();
+    let mut list_store = HashMap::<String, VecDeque<Value>>::new();
+    let mut hash_store = HashMap::<String, HashMap<String, Value>>::new();
+    let mut sorted_set_store = HashMap::<String, SortedSet>::new();
+    while let Some(cmd) = store_rx.recv().await {
+        match cmd {
+            Command::GetItem { key, responder } => {
+                let result = string_store.get(&key).cloned();
+                info!("Get key {:?} in the string store. Result: {:?}", key, result);
+                let _ = responder.send(result);
+            },
+            Command::SetItem { key, value, responder } => {
+                let result = string_store.insert(key.clone(), value.clone());
+                debug!("Set key {:?} in the string store", key);
+                notify_keyspace(&keyspace_tx, &key, RequestAction::Set);
+                Replication::record(Mutation::Set { key, value }, &replication_tx);
+                let _ = responder.send(result);
+            },
+            Command::UnsetItem { key, responder } => {
+                let result = string_store.remove(&key);
+                debug!("Unset key {:?} in the string store", key);
+                if result.is_some() {
+                  notify_keyspace(&keyspace_tx, &key, RequestAction::Unset);
+                  Replication::record(Mutation::Unset { key }, &replication_tx);
+                }
+                let _ = responder.send(result);
+            },
+            Command::UpdateItem { key, path, update, responder } => {
+                let previous = string_store.get(&key).cloned();
+                let mut document = previous.clone().unwrap_or(Value::Null);
+                let action = match update {
+                  PathUpdate::Set(_) => RequestAction::SetPath,
+                  PathUpdate::Merge(_) => RequestAction::MergePath,
+                  PathUpdate::Delete => RequestAction::DeletePath
+                };
+                let result = match update {
+                  PathUpdate::Set(value) => set_path(&mut document, &path, value),
+                  PathUpdate::Merge(value) => merge_path(&mut document, &path, value),
+                  PathUpdate::Delete => delete_path(&mut document, &path)
+                }.map(|_| {
+                  string_store.insert(key.clone(), document.clone());
+                  (previous, document)
+                });
+                info!("Update key in the string store. Key: {:?}, Path: {:?}, Result: {:?}", key, path, result);
+                if let Ok((_, document)) = &result {
+                  notify_keyspace(&keyspace_tx, &key, action);
+                  Replication::record(Mutation::Set { key, value: document.clone() }, &replication_tx);
+                }
+                let _ = responder.send(result);
+            },
+            Command::RemoveFromCollection { key, value, responder } => {
+                let collection_option = collection_store.get_mut(&key);
+                let result = match collection_option {
+                  Some(collection) => collection.remove(&member_key(&value)),
+                  None => false
+                };
+                debug!("Remove from collection {:?}. Result: {:?}", key, result);
+                if result {
+                  notify_keyspace(&keyspace_tx, &key, RequestAction::RemoveFromCollection);
+                  Replication::record(Mutation::RemoveFromCollection { key, value }, &replication_tx);
+                }
+                let _ = responder.send(result);
+            },
+            Command::AddToCollection { key, value, responder } => {
+                let member = member_key(&value);
+                let collection = collection_store.entry(key.clone()).or_default();
+                let result = match collection.contains(&member) {
+                  true => Ok(false),
+                  false => full(&key, collection.len()).map(|_| collection.insert(member))
+                };
+                debug!("Add to collection {:?}. Result: {:?}", key, result);
+                if result == Ok(true) {
+                  notify_keyspace(&keyspace_tx, &key, RequestAction::AddToCollection);
+                  Replication::record(Mutation::AddToCollection { key, value }, &replication_tx);
+                }
+                let _ = responder.send(result);
+            }
+            Command::GetCollection { key, responder } => {
+                let collection_option = collection_store.get(&key);
+                let result = collection_option.map(|collection| collection.iter().map(|member| member_value(member)).collect());
+                debug!("Get collection {:?}", key);
+                let _ = responder.send(result);
+            },
+            Command::PushToList { key, value, front, responder } => {
+                let list = list_store.entry(key.clone()).or_default();
+                let result = full(&key, list.len()).map(|_| {
+                  if front {
+                    list.push_front(value);
+                  } else {
+                    list.push_back(value);
+                  }
+                  list.len()
+                });
+                info!("Push to list in the list store. Key: {:?}, Length: {:?}", key, result);
+                if result.is_ok() {
+                  notify_keyspace(&keyspace_tx, &key, RequestAction::PushToList);
+                }
+                let _ = responder.send(result);
+            },
+            Command::PopFromList { key, front, responder } => {
+                let result = match list_store.get_mut(&key) {
+                  Some(list) if front => list.pop_front(),
+                  Some(list) => list.pop_back(),
+                  None => None
+                };
+                if list_store.get(&key).is_some_and(|list| list.is_empty()) {
+                  list_store.remove(&key);
+                }
+                info!("Pop from list in the list store. Key: {:?}, Result: {:?}", key, result);
+                if result.is_some() {
+                  notify_keyspace(&keyspace_tx, &key, RequestAction::PopFromList);
+                }
+                let _ = responder.send(result);
+            },
+            Command::GetListRange { key, start, stop, responder } => {
+                let result = match list_store.get(&key) {
+                  Some(list) => match resolve_range(list.len(), start, stop) {
+                    Some((start, stop)) => list.range(start..=stop).cloned().collect(),
+                    None => Vec::new()
+                  },
+                  None => Vec::new()
+                };
+                info!("Get list range in the list store. Key: {:?}, Result: {:?}", key, result);
+                let _ = responder.send(result);
+            },
+            Command::SetField { key, field, value, responder } => {
+                let hash = hash_store.entry(key.clone()).or_default();
+                let result = match hash.contains_key(&field) {
+                  true => Ok(()),
+                  false => full(&key, hash.len())
+                }.map(|_| hash.insert(field, value));
+                info!("Set field in the hash store. Key: {:?}, Result: {:?}", key, result);
+                if result.is_ok() {
+                  notify_keyspace(&keyspace_tx, &key, RequestAction::SetField);
+                }
+                let _ = responder.send(result);
+            },
+            Command::GetField { key, field, responder } => {
+                let result = hash_store.get(&key).and_then(|hash| hash.get(&field).cloned());
+                info!("Get field in the hash store. Key: {:?}, Field: {:?}, Result: {:?}", key, field, result);
+                let _ = responder.send(result);
+            },
+            Command::DeleteField { key, field, responder } => {
+                let result = hash_store.get_mut(&key).and_then(|hash| hash.remove(&field));
+                if hash_store.get(&key).is_some_and(|hash| hash.is_empty()) {
+                  hash_store.remove(&key);
+                }
+                info!("Delete field in the hash store. Key: {:?}, Field: {:?}, Result: {:?}", key, field, result);
+                if result.is_some() {
+                  notify_keyspace(&keyspace_tx, &key, RequestAction::DeleteField);
+                }
+                let _ = responder.send(result);
+            },
+            Command::AddToSortedSet { key, value, score, responder } => {
+                let set = sorted_set_store.entry(key.clone()).or_default();
+                let member = member_key(&value);
+                let result = match set.contains(&member) {
+                  true => Ok(()),
+                  false => full(&key, set.len())
+                }.map(|_| set.insert(member, score));
+                info!("Add to sorted set in the sorted set store. Key: {:?}, Result: {:?}", key, result);
+                if result == Ok(true) {
+                  notify_keyspace(&keyspace_tx, &key, RequestAction::AddToSortedSet);
+                }
+                let _ = responder.send(result);
+            },
+            Command::GetSortedSetRangeByRank { key, start, stop, responder } => {
+                let result = sorted_set_store.get(&key).map_or_else(Vec::new, |set| set.range_by_rank(start, stop).into_iter().map(|(member, score)| (member_value(&member), score)).collect());
+                info!("Get sorted set range by rank in the sorted set store. Key: {:?}, Result: {:?}", key, result);
+                let _ = responder.send(result);
+            },
+            Command::GetSortedSetRangeByScore { key, min, max, responder } => {
+                let result = sorted_set_store.get(&key).map_or_else(Vec::new, |set| set.range_by_score(min, max).into_iter().map(|(member, score)| (member_value(&member), score)).collect());
+                info!("Get sorted set range by score in the sorted set store. Key: {:?}, Result: {:?}", key, result);
+                let _ = responder.send(result);
+            },
+            Command::InspectKey { key, responder } => {
+                let mut result = Vec::new();
+                if let Some(value) = string_store.get(&key) {
+                  result.push(StoredValue::String(value.clone()));
+                }
+                if let Some(collection) = collection_store.get(&key) {
+                  let mut members: Vec<&String> = collection.iter().collect();
+                  members.sort();
+                  result.push(StoredValue::Collection(members.into_iter().map(|member| member_value(member)).collect()));
+                }
+                if let Some(list) = list_store.get(&key) {
+                  result.push(StoredValue::List(list.iter().cloned().collect()));
+                }
+                if let Some(hash) = hash_store.get(&key) {
+                  result.push(StoredValue::Hash(hash.iter().map(|(field, value)| (field.clone(), value.clone())).collect()));
+                }
+                if let Some(set) = sorted_set_store.get(&key) {
+                  result.push(StoredValue::SortedSet(set.range_by_rank(0, -1).into_iter().map(|(member, score)| (member_value(&member), score)).collect()));
+                }
+                info!("Inspect key {:?} in the store. Result: {:?}", key, result);
+                let _ = responder.send(result);
+            },
+            Command::RestoreKey { key, value, responder } => {
+                let action = restored_action(&value);
+                let result = match value {
+                  StoredValue::String(value) => {
+                    Replication::record(Mutation::Set { key: key.clone(), value: value.clone() }, &replication_tx);
+                    string_store.insert(key.clone(), value).is_some()
+                  },
+                  StoredValue::Collection(members) => {
+                    let previous = collection_store.insert(key.clone(), members.iter().map(member_key).collect());
+                    Replication::record(Mutation::SetCollection { key: key.clone(), members }, &replication_tx);
+                    previous.is_some()
+                  },
+                  StoredValue::List(values) => list_store.insert(key.clone(), values.into()).is_some(),
+                  StoredValue::Hash(fields) => hash_store.insert(key.clone(), fields.into_iter().collect()).is_some(),
+                  StoredValue::SortedSet(members) => {
+                    let mut set = SortedSet::default();
+                    for (member, score) in members {
+                      set.insert(member_key(&member), score);
+                    }
+                    sorted_set_store.insert(key.clone(), set).is_some()
+                  }
+                };
+                info!("Restore key {:?} in the store. Replaced: {:?}", key, result);
+                notify_keyspace(&keyspace_tx, &key, action);
+                let _ = responder.send(result);
+            },
+            Command::ScanKeys { filter, count, responder } => {
+                let mut result: Vec<&String> = string_store.keys()
+                  .chain(collection_store.keys())
+                  .chain(list_store.keys())
+                  .chain(hash_store.keys())
+                  .chain(sorted_set_store.keys())
+                  .filter(|key| filter.matches(key))
+                  .collect();
+                result.sort();
+                result.dedup();
+                result.truncate(count);
+                debug!("Scan the store with {:?}. Result: {:?}", filter, result);
+                let _ = responder.send(result.into_iter().cloned().collect());
+            },
+            Command::DeleteKey { key, responder } => {
+                let deleted = [
+                  string_store.remove(&key).is_some(),
+                  collection_store.remove(&key).is_some(),
+                  list_store.remove(&key).is_some(),
+                  hash_store.remove(&key).is_some(),
+                  sorted_set_store.remove(&key).is_some()
+                ].contains(&true);
+                info!("Delete key {:?} in the store. Result: {:?}", key, deleted);
+                if deleted {
+                  notify_keyspace(&keyspace_tx, &key, RequestAction::Unset);
+                  Replication::record(Mutation::DeleteKey { key }, &replication_tx);
+                }
+                let _ = responder.send(deleted);
+            },
+            _ => {
+                error!("RemoveFromAllCollections and GetKeys may not be used with the string store.");
+            }
+        }
+    }
+}
+
+/// The keyspace action of restoring `value`: the one that writes values of its type.
+fn restored_action(value: &StoredValue) -> RequestAction {
+    match value {
+        StoredValue::String(_) => RequestAction::Set,
+        StoredValue::Collection(_) => RequestAction::AddToCollection,
+        StoredValue::List(_) => RequestAction::PushToList,
+        StoredValue::Hash(_) => RequestAction::SetField,
+        StoredValue::SortedSet(_) => RequestAction::AddToSortedSet
+    }
+}
+
+fn notify_keyspace(keyspace_tx: &UnboundedSender<KeyspaceEvent>, key: &str, action: RequestAction) {
+    if keyspace_tx.send(KeyspaceEvent { key: String::from(key), action }).is_err() {
+        error!("Error sending keyspace event for key {:?}", key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use crate::command::{add_to_sorted_set, add_value_to_collection, delete_key, get_collection, get_value, inspect_key, push_to_list, restore_key, set_field, set_value};
+    use crate::serialize::{RequestAction, StoredValue};
+    use crate::store::{ScanFilter, Store};
+    use super::{run, spawn, StoreShards};
+
+    #[test]
+    fn test_shard_routing() {
+        let (first_tx, _first_rx) = mpsc::channel(32);
+        let (second_tx, _second_rx) = mpsc::channel(32);
+        let shards = StoreShards::new(vec![first_tx.clone(), second_tx.clone()]);
+        assert!(shards.shard("a").same_channel(&shards.shard("a")));
+        let keys: Vec<String> = (0..32).map(|index| index.to_string()).collect();
+        assert!(keys.iter().any(|key| shards.shard(key).same_channel(&first_tx)));
+        assert!(keys.iter().any(|key| shards.shard(key).same_channel(&second_tx)));
+    }
+
+    #[tokio::test]
+    async fn test_sharded_store() {
+        let (keyspace_tx, _keyspace_rx) = mpsc::unbounded_channel();
+        let (replication_tx, _replication_rx) = mpsc::unbounded_channel();
+        let shards = spawn(4, keyspace_tx, replication_tx);
+        for index in 0..16 {
+            let key = index.to_string();
+            set_value(key.clone(), json!(index), shards.shard(&key)).await.unwrap();
+        }
+        for index in 0..16 {
+            let key = index.to_string();
+            assert_eq!(get_value(key.clone(), shards.shard(&key)).await.unwrap(), Some(json!(index)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_members() {
+        let (keyspace_tx, _keyspace_rx) = mpsc::unbounded_channel();
+        let (replication_tx, _replication_rx) = mpsc::unbounded_channel();
+        let (store_tx, store_rx) = mpsc::channel(32);
+        tokio::spawn(run(store_rx, keyspace_tx, replication_tx, Some(2)));
+
+        assert_eq!(push_to_list(String::from("list"), json!(1), false, store_tx.clone()).await.unwrap(), Ok(1));
+        assert_eq!(push_to_list(String::from("list"), json!(2), false, store_tx.clone()).await.unwrap(), Ok(2));
+        assert!(push_to_list(String::from("list"), json!(3), false, store_tx.clone()).await.unwrap().is_err());
+
+        set_field(String::from("hash"), String::from("a"), json!(1), store_tx.clone()).await.unwrap().unwrap();
+        set_field(String::from("hash"), String::from("b"), json!(1), store_tx.clone()).await.unwrap().unwrap();
+        assert_eq!(set_field(String::from("hash"), String::from("a"), json!(2), store_tx.clone()).await.unwrap(), Ok(Some(json!(1))));
+        assert!(set_field(String::from("hash"), String::from("c"), json!(1), store_tx.clone()).await.unwrap().is_err());
+
+        // The first member creates the collection.
+        assert_eq!(add_value_to_collection(String::from("collection"), json!(1), store_tx.clone()).await.unwrap(), Ok(true));
+        assert_eq!(get_collection(String::from("collection"), store_tx.clone()).await.unwrap(), Some(vec![json!(1)]));
+        assert_eq!(add_value_to_collection(String::from("collection"), json!(2), store_tx.clone()).await.unwrap(), Ok(true));
+        assert_eq!(add_value_to_collection(String::from("collection"), json!(2), store_tx.clone()).await.unwrap(), Ok(false));
+        assert!(add_value_to_collection(String::from("collection"), json!(3), store_tx).await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inspect_and_delete_key() {
+        let (keyspace_tx, mut keyspace_rx) = mpsc::unbounded_channel();
+        let (replication_tx, _replication_rx) = mpsc::unbounded_channel();
+        let (store_tx, store_rx) = mpsc::channel(32);
+        tokio::spawn(run(store_rx, keyspace_tx, replication_tx, None));
+
+        set_value(String::from("a"), json!({ "b": 1 }), store_tx.clone()).await.unwrap();
+        push_to_list(String::from("a"), json!(2), false, store_tx.clone()).await.unwrap().unwrap();
+        add_to_sorted_set(String::from("a"), json!("c"), 3.0, store_tx.clone()).await.unwrap().unwrap();
+        // Adding the member again with its score changes nothing, and is not notified.
+        assert_eq!(add_to_sorted_set(String::from("a"), json!("c"), 3.0, store_tx.clone()).await.unwrap(), Ok(false));
+        assert_eq!(inspect_key(String::from("a"), store_tx.clone()).await.unwrap(), vec![
+            StoredValue::String(json!({ "b": 1 })),
+            StoredValue::List(vec![json!(2)]),
+            StoredValue::SortedSet(vec![(json!("c"), 3.0)])
+        ]);
+
+        assert!(delete_key(String::from("a"), store_tx.clone()).await.unwrap());
+        assert!(!delete_key(String::from("a"), store_tx.clone()).await.unwrap());
+        assert!(inspect_key(String::from("a"), store_tx.clone()).await.unwrap().is_empty());
+        let mut actions = Vec::new();
+        while let Ok(event) = keyspace_rx.try_recv() {
+            actions.push(event.action);
+        }
+        assert_eq!(actions.last(), Some(&RequestAction::Unset));
+        assert_eq!(actions.len(), 4);
+
+        restore_key(String::from("b"), StoredValue::List(vec![json!(1)]), store_tx.clone()).await.unwrap();
+        restore_key(String::from("b"), StoredValue::Hash([(String::from("f"), json!(1))].into()), store_tx).await.unwrap();
+        assert_eq!(keyspace_rx.recv().await.unwrap().action, RequestAction::PushToList);
+        assert_eq!(keyspace_rx.recv().await.unwrap().action, RequestAction::SetField);
+    }
+
+    #[tokio::test]
+    async fn test_scan_pages_across_shards() {
+        let (keyspace_tx, _keyspace_rx) = mpsc::unbounded_channel();
+        let (replication_tx, _replication_rx) = mpsc::unbounded_channel();
+        let shards = spawn(4, keyspace_tx, replication_tx);
+        for index in 0..10 {
+            let key = format!("user:{}", index);
+            set_value(key.clone(), json!(index), shards.shard(&key)).await.unwrap();
+        }
+        push_to_list(String::from("user:list"), json!(1), false, shards.shard("user:list")).await.unwrap().unwrap();
+        set_value(String::from("other"), json!(0), shards.shard("other")).await.unwrap();
+
+        let filter = |after: Option<String>| ScanFilter { after, prefix: String::from("user:"), pattern: None };
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = Store::scan(filter(cursor), 3, shards.clone()).await.unwrap();
+            assert!(page.keys.len() <= 3);
+            seen.extend(page.keys);
+            // Keys written and deleted mid-scan neither repeat nor hide the others.
+            delete_key(String::from("user:9"), shards.shard("user:9")).await.unwrap();
+            set_value(String::from("user:0a"), json!(0), shards.shard("user:0a")).await.unwrap();
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        let stable: Vec<String> = (0..9).map(|index| format!("user:{}", index)).chain([String::from("user:list")]).collect();
+        assert!(stable.iter().all(|key| seen.iter().filter(|seen| *seen == key).count() == 1));
+        assert!(!seen.contains(&String::from("other")));
+
+        let page = Store::scan(ScanFilter { pattern: Some(String::from("user:?")), ..filter(None) }, 100, shards).await.unwrap();
+        assert_eq!(page.keys, (0..9).map(|index| format!("user:{}", index)).collect::<Vec<String>>());
+        assert_eq!(page.cursor, None);
+    }
+}
@@ -0,0 +1,269 @@
+use std::{io::Write, path::Path};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use tokio::sync::{mpsc::Sender, oneshot::error::RecvError};
+use warp::hyper::{self, body::HttpBody, Body, Request};
+use crate::command::Command;
+use crate::serialize::StoredValue;
+use crate::shard::StoreShards;
+use crate::store::{Retained, ScanFilter, Store, MAX_SCAN_COUNT};
+
+/// Version of the snapshot format, declared on the first line of every snapshot.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// One line of a snapshot file, which is made of JSON lines: a header, then the values of the store,
+/// then the retained messages if they were exported.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SnapshotLine {
+    Header {
+        snapshot: u32
+    },
+    Key {
+        key: String,
+        value: StoredValue
+    },
+    Retained {
+        retained: String,
+        value: Value
+    }
+}
+
+/// How an import treats the state already on the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Values of the snapshot replace those of the same key and kind, other keys are kept.
+    #[default]
+    Merge,
+    /// Every key and retained message is deleted before the snapshot is loaded.
+    Replace
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ImportSummary {
+    /// Keys deleted beforehand by a replace.
+    pub deleted: usize,
+    /// Values written, counting a value of each kind held by a key.
+    pub values: usize,
+    pub retained: usize
+}
+
+/// Sends the header, every value of the store, then the retained messages when `retained_tx` is given.
+/// Keys are read one at a time as the export reaches them, so the snapshot is not taken at a single
+/// instant: a key written meanwhile may or may not be included. Stops early once `lines` is closed.
+pub async fn export(store_tx: StoreShards, retained_tx: Option<Sender<Command<Value>>>, lines: Sender<SnapshotLine>) -> Result<(), RecvError> {
+    if lines.send(SnapshotLine::Header { snapshot: SNAPSHOT_VERSION }).await.is_err() {
+        return Ok(());
+    }
+    let mut after = None;
+    loop {
+        let page = Store::scan(ScanFilter { after, ..Default::default() }, MAX_SCAN_COUNT, store_tx.clone()).await?;
+        for key in page.keys {
+            for value in Store::inspect(key.clone(), store_tx.clone()).await? {
+                if lines.send(SnapshotLine::Key { key: key.clone(), value }).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        after = match page.cursor {
+            Some(cursor) => Some(cursor),
+            None => break
+        };
+    }
+    if let Some(retained_tx) = retained_tx {
+        let mut topics = Retained::topics(retained_tx.clone()).await?;
+        topics.sort();
+        for topic in topics {
+            if let Some(value) = Retained::get(topic.clone(), retained_tx.clone()).await? {
+                if lines.send(SnapshotLine::Retained { retained: topic, value }).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads the lines of a snapshot, failing on the first invalid one so that nothing is imported from
+/// a corrupt file. The header is optional, but must declare a version this server reads.
+pub fn parse(text: &str) -> Result<Vec<SnapshotLine>, String> {
+    let mut lines = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value = serde_json::from_str(line).map_err(|err| format!("Line {}: {}", index + 1, err))?;
+        match serde_json::from_value(value) {
+            Ok(SnapshotLine::Header { snapshot }) if snapshot != SNAPSHOT_VERSION => {
+                return Err(format!("Line {}: unsupported snapshot version {}", index + 1, snapshot));
+            },
+            Ok(SnapshotLine::Header { .. }) => (),
+            Ok(line) => lines.push(line),
+            Err(_) => return Err(format!("Line {}: expected a header, a key with its typed value or a retained message", index + 1))
+        }
+    }
+    Ok(lines)
+}
+
+/// Writes the lines of a snapshot to the store and the retained messages. Quotas do not apply.
+pub async fn import(lines: Vec<SnapshotLine>, mode: ImportMode, store_tx: StoreShards, retained_tx: Sender<Command<Value>>) -> Result<ImportSummary, RecvError> {
+    let mut summary = ImportSummary::default();
+    if mode == ImportMode::Replace {
+        summary.deleted = clear(store_tx.clone(), retained_tx.clone()).await?;
+    }
+    for line in lines {
+        match line {
+            SnapshotLine::Key { key, value } => {
+                Store::restore(key, value, store_tx.clone()).await?;
+                summary.values += 1;
+            },
+            SnapshotLine::Retained { retained, value } => {
+                Retained::set(retained, value, retained_tx.clone()).await?;
+                summary.retained += 1;
+            },
+            SnapshotLine::Header { .. } => ()
+        }
+    }
+    info!("Imported a snapshot in {:?} mode: {:?}", mode, summary);
+    Ok(summary)
+}
+
+/// Imports the snapshot file at `path`, as a server does on start.
+pub async fn import_file(path: &Path, mode: ImportMode, store_tx: StoreShards, retained_tx: Sender<Command<Value>>) -> Result<ImportSummary, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+    let lines = parse(&text)?;
+    import(lines, mode, store_tx, retained_tx).await.map_err(|_| String::from("The store stopped during the import"))
+}
+
+/// Deletes every key and retained message, returning the number of keys.
+async fn clear(store_tx: StoreShards, retained_tx: Sender<Command<Value>>) -> Result<usize, RecvError> {
+    let deleted = clear_store(store_tx).await?;
+    for topic in Retained::topics(retained_tx.clone()).await? {
+        Retained::clear(topic, retained_tx.clone()).await?;
+    }
+    Ok(deleted)
+}
+
+/// Deletes every key of the store, returning how many there were.
+pub async fn clear_store(store_tx: StoreShards) -> Result<usize, RecvError> {
+    let mut deleted = 0;
+    let mut after = None;
+    loop {
+        let page = Store::scan(ScanFilter { after, ..Default::default() }, MAX_SCAN_COUNT, store_tx.clone()).await?;
+        for key in page.keys {
+            if Store::delete(key, store_tx.clone()).await? {
+                deleted += 1;
+            }
+        }
+        after = match page.cursor {
+            Some(cursor) => Some(cursor),
+            None => break
+        };
+    }
+    Ok(deleted)
+}
+
+/// Downloads the snapshot of the server at `url` through its admin API into `out`.
+pub async fn download(url: &str, token: &str, retained: bool, out: &mut impl Write) -> Result<(), String> {
+    let request = Request::get(format!("{}/admin/snapshot?retained={}", url, retained))
+        .header("authorization", format!("Bearer {}", token))
+        .body(Body::empty())
+        .map_err(|err| err.to_string())?;
+    let mut response = hyper::Client::new().request(request).await.map_err(|err| format!("Cannot reach {}: {}", url, err))?;
+    if !response.status().is_success() {
+        return Err(format!("The server answered {}", response.status()));
+    }
+    while let Some(chunk) = response.body_mut().data().await {
+        let chunk = chunk.map_err(|err| format!("The snapshot was cut short: {}", err))?;
+        out.write_all(&chunk).map_err(|err| err.to_string())?;
+    }
+    out.flush().map_err(|err| err.to_string())
+}
+
+/// Uploads a snapshot to the server at `url` through its admin API.
+pub async fn upload(url: &str, token: &str, mode: ImportMode, snapshot: Vec<u8>) -> Result<ImportSummary, String> {
+    let mode = serde_json::to_value(mode).map_err(|err| err.to_string())?;
+    let request = Request::post(format!("{}/admin/snapshot?mode={}", url, mode.as_str().unwrap_or_default()))
+        .header("authorization", format!("Bearer {}", token))
+        .header("content-type", "application/x-ndjson")
+        .body(Body::from(snapshot))
+        .map_err(|err| err.to_string())?;
+    let response = hyper::Client::new().request(request).await.map_err(|err| format!("Cannot reach {}: {}", url, err))?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.map_err(|err| err.to_string())?;
+    if !status.is_success() {
+        return Err(format!("The server answered {}: {}", status, String::from_utf8_lossy(&body)));
+    }
+    serde_json::from_slice(&body).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use crate::command::{get_value, set_value, Command};
+    use crate::serialize::StoredValue;
+    use crate::shard;
+    use crate::store::Store;
+    use super::{export, import, parse, ImportMode, SnapshotLine};
+
+    #[test]
+    fn test_parse() {
+        let lines = parse("{\"snapshot\":1}\n\n{\"key\":\"a\",\"value\":{\"type\":\"List\",\"value\":[1]}}\n{\"retained\":\"t\",\"value\":{\"b\":2}}\n").unwrap();
+        assert_eq!(lines, vec![
+            SnapshotLine::Key { key: String::from("a"), value: StoredValue::List(vec![json!(1)]) },
+            SnapshotLine::Retained { retained: String::from("t"), value: json!({ "b": 2 }) }
+        ]);
+        assert_eq!(parse("{\"snapshot\":2}").unwrap_err(), "Line 1: unsupported snapshot version 2");
+        assert_eq!(parse("{\"snapshot\":1}\n{\"key\":\"a\"}").unwrap_err(), "Line 2: expected a header, a key with its typed value or a retained message");
+        assert!(parse("{\"key\":").unwrap_err().starts_with("Line 1: EOF"));
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import() {
+        let (keyspace_tx, _keyspace_rx) = mpsc::unbounded_channel();
+        let (replication_tx, _replication_rx) = mpsc::unbounded_channel();
+        let store_tx = shard::spawn(2, keyspace_tx, replication_tx);
+        let (retained_tx, mut retained_rx) = mpsc::channel::<Command<serde_json::Value>>(32);
+        tokio::spawn(async move {
+            let mut retained = HashMap::new();
+            while let Some(cmd) = retained_rx.recv().await {
+                match cmd {
+                    Command::GetItem { key, responder } => { let _ = responder.send(retained.get(&key).cloned()); },
+                    Command::SetItem { key, value, responder } => { let _ = responder.send(retained.insert(key, value)); },
+                    Command::UnsetItem { key, responder } => { let _ = responder.send(retained.remove(&key)); },
+                    Command::GetKeys { responder } => { let _ = responder.send(retained.keys().cloned().collect()); },
+                    _ => ()
+                }
+            }
+        });
+        Store::set(String::from("a"), json!({ "x": 1 }), store_tx.clone()).await.unwrap();
+        Store::push_to_list(String::from("b"), json!(2), false, store_tx.clone()).await.unwrap().unwrap();
+        set_value(String::from("topic"), json!("kept"), retained_tx.clone()).await.unwrap();
+
+        let (lines_tx, mut lines_rx) = mpsc::channel(8);
+        export(store_tx.clone(), Some(retained_tx.clone()), lines_tx).await.unwrap();
+        let mut lines = Vec::new();
+        while let Some(line) = lines_rx.recv().await {
+            lines.push(line);
+        }
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], SnapshotLine::Header { snapshot: 1 });
+        assert_eq!(lines[3], SnapshotLine::Retained { retained: String::from("topic"), value: json!("kept") });
+
+        Store::set(String::from("a"), json!("changed"), store_tx.clone()).await.unwrap();
+        Store::set(String::from("c"), json!(3), store_tx.clone()).await.unwrap();
+        let summary = import(lines.clone(), ImportMode::Merge, store_tx.clone(), retained_tx.clone()).await.unwrap();
+        assert_eq!((summary.deleted, summary.values, summary.retained), (0, 2, 1));
+        assert_eq!(Store::get(String::from("a"), store_tx.clone()).await.unwrap(), Some(json!({ "x": 1 })));
+        assert_eq!(Store::get(String::from("c"), store_tx.clone()).await.unwrap(), Some(json!(3)));
+
+        let summary = import(lines, ImportMode::Replace, store_tx.clone(), retained_tx.clone()).await.unwrap();
+        assert_eq!(summary.deleted, 3);
+        assert_eq!(Store::get(String::from("c"), store_tx.clone()).await.unwrap(), None);
+        assert_eq!(Store::get_list_range(String::from("b"), 0, -1, store_tx).await.unwrap(), vec![json!(2)]);
+        assert_eq!(get_value(String::from("topic"), retained_tx).await.unwrap(), Some(json!("kept")));
+    }
+}
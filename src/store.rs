@@ -1,25 +1,59 @@
-use std::{collections::{HashMap, HashSet}, hash::Hash, hash::Hasher, sync::{Arc}};
-use tokio::{sync::{Mutex, mpsc::{self, Sender}, oneshot::{self, error::RecvError}}};
+use std::{cmp::Ordering, collections::{BTreeMap, BTreeSet, HashMap, HashSet}, hash::Hash, hash::Hasher, sync::{Arc, atomic::Ordering as AtomicOrdering}, time::Instant};
+use tokio::{sync::{Mutex, Notify, mpsc::{self, Receiver, Sender}, oneshot::{self, error::RecvError}}};
+use serde_json::Value;
 use warp::ws::Message;
-use crate::command::{Command, get_value, set_value, remove_value, get_collection, add_value_to_collection, remove_value_from_collection};
+use crate::serialize::{EventFormat, EventKind, ReliableEvent, ScanPage, StoredValue};
+use serde_json::json;
+use uuid::Uuid;
+use crate::command::{Command, PathUpdate, get_value, set_value, insert_value, remove_value, expire_value, update_value, get_collection, add_value_to_collection, remove_value_from_collection, remove_value_from_all_collections, get_keys, push_to_list, pop_from_list, get_list_range, set_field, get_field, delete_field, add_to_sorted_set, get_sorted_set_range_by_rank, get_sorted_set_range_by_score, inspect_key, delete_key, scan_keys, restore_key};
 use mockall::automock;
+use crate::shard::StoreShards;
+use crate::subscriptions::{GroupCursors, SubscriptionsTx};
+use crate::codec::Encoding;
 
 pub type Responder<T> = oneshot::Sender<T>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Client {
     pub user_id: String,
-    pub sender: Option<mpsc::UnboundedSender<Result<Message, warp::Error>>>
+    pub sender: Option<mpsc::UnboundedSender<Result<Message, warp::Error>>>,
+    /// Ends the connection of `sender` from the server side, whatever the client sends meanwhile.
+    pub shutdown: Option<Arc<Notify>>,
+    /// Encoding of the frames sent on `sender`.
+    pub encoding: Encoding,
+    /// Options of a subscription, only meaningful on the copies held in the subscriptions store.
+    pub options: SubscriptionOptions,
+    /// Events of reliable subscriptions awaiting an Ack, shared by every copy of the client.
+    pub pending: PendingEvents,
+    /// When the client disconnected with unacked events, if it has not reconnected since.
+    pub offline_since: Option<Instant>
+}
+
+pub type PendingEvents = Arc<Mutex<HashMap<String, PendingEvent>>>;
+
+#[derive(Clone, Debug)]
+pub struct PendingEvent {
+    pub topic: String,
+    pub text: String,
+    pub attempts: u32,
+    pub sent_at: Instant
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SubscriptionOptions {
+    pub format: EventFormat,
+    /// Metadata announced to the other subscribers of the topic when presence is enabled.
+    pub presence: Option<Value>,
+    /// Queue group sharing the topic's messages with the other subscribers of the same name.
+    pub group: Option<String>,
+    /// Deliver events with an id and redeliver them until acked.
+    pub reliable: bool
 }
 
 impl PartialEq for Client {
     fn eq(&self, other: &Client) -> bool {
         self.user_id == other.user_id
     }
-
-    fn ne(&self, other: &Client) -> bool {
-        self.user_id != other.user_id
-    }
 }
 
 impl Eq for Client {}
@@ -40,53 +74,592 @@ impl Client {
         set_value(client.user_id.clone(), client, clients_tx).await
     }
 
+    /// Registers a new client, unless `max` clients are already registered. Returns whether it was registered.
+    pub async fn register_client(client: Client, max: Option<usize>, clients_tx: Sender<Command<Client>>) -> Result<bool, RecvError> {
+        insert_value(client.user_id.clone(), client, max, clients_tx).await
+    }
+
     pub async fn remove_client(user_id: String, clients_tx: Sender<Command<Client>>) -> Result<Option<Client>, RecvError> {
         remove_value(user_id, clients_tx).await
     }
+
+    /// Unregisters the client if it has stayed offline since `since`. Returns the client if it was unregistered.
+    pub async fn expire_client(user_id: String, since: Instant, clients_tx: Sender<Command<Client>>) -> Result<Option<Client>, RecvError> {
+        expire_value(user_id, since, clients_tx).await
+    }
+
+    pub async fn get_user_ids(clients_tx: Sender<Command<Client>>) -> Result<Vec<String>, RecvError> {
+        get_keys(clients_tx).await
+    }
+}
+
+impl Client {
+    /// Wraps `text`, an event of `kind`, in an envelope with a new event id and keeps it pending until the client acks it.
+    pub async fn track_delivery(&self, topic: &str, kind: EventKind, text: String) -> String {
+        let id = Uuid::new_v4().to_string();
+        let value = serde_json::from_str(&text).unwrap_or(Value::String(text));
+        let envelope = json!(ReliableEvent { id: id.clone(), topic: String::from(topic), kind, value }).to_string();
+        self.pending.lock().await.insert(id, PendingEvent {
+            topic: String::from(topic),
+            text: envelope.clone(),
+            attempts: 1,
+            sent_at: Instant::now()
+        });
+        envelope
+    }
+
+    /// Returns true if `id` was pending.
+    pub async fn acknowledge(&self, id: &str) -> bool {
+        self.pending.lock().await.remove(id).is_some()
+    }
 }
+
 pub type Clients = Arc<Mutex<HashMap<String, Client>>>;
 
+/// Spawns the clients actor over `clients`.
+pub fn spawn_clients(clients: Clients) -> Sender<Command<Client>> {
+    let (clients_tx, clients_rx) = mpsc::channel::<Command<Client>>(32);
+    tokio::spawn(run_clients(clients_rx, clients));
+    clients_tx
+}
+
+/// The clients actor, the only writer of the registered clients.
+pub async fn run_clients(mut clients_rx: Receiver<Command<Client>>, clients: Clients) {
+    while let Some(cmd) = clients_rx.recv().await {
+        match cmd {
+            Command::GetItem { key, responder } => {
+                info!("Get from client store: {:?}", key);
+                // TODO CWS: this clone is probably unecessary. What can we do with references here? And if we do that, can we include referenced variables in the logs?
+                let result = clients.lock().await.get(&key).cloned();
+                let _ = responder.send(result);
+            },
+            Command::SetItem { key, value, responder } => {
+                info!("Set value: {:?} for key: {:?} in the client store.", value, key);
+                let result = clients.lock().await.insert(key, value);
+                let _ = responder.send(result);
+            },
+            Command::InsertItem { key, value, max, responder } => {
+                let mut clients = clients.lock().await;
+                // Checked and inserted under one lock, so that concurrent registrations cannot exceed `max`.
+                let result = !clients.contains_key(&key) && max.is_none_or(|max| clients.len() < max);
+                if result {
+                    info!("Insert value: {:?} for key: {:?} in the client store.", value, key);
+                    clients.insert(key, value);
+                }
+                let _ = responder.send(result);
+            },
+            Command::UnsetItem { key, responder } => {
+                info!("Unset key: {:?} in the client store.", key);
+                let result = clients.lock().await.remove(&key);
+                let _ = responder.send(result);
+            },
+            Command::ExpireItem { key, since, responder } => {
+                let mut clients = clients.lock().await;
+                let offline = clients.get(&key).is_some_and(|client| client.sender.is_none() && client.offline_since == Some(since));
+                let result = if offline { clients.remove(&key) } else { None };
+                let _ = responder.send(result);
+            },
+            Command::GetKeys { responder } => {
+                let _ = responder.send(clients.lock().await.keys().cloned().collect());
+            },
+            _ => {
+                error!("Only Get, Set, Insert, Unset, Expire and GetKeys may be used with clients.");
+            }
+        }
+    }
+}
+
 pub struct Store;
 impl Store {
-    pub async fn set(key: String, value: String, store_tx: Sender<Command<String>>) -> Result<Option<String>, RecvError> {
-        set_value(key, value, store_tx).await
+    pub async fn get(key: String, store_tx: StoreShards) -> Result<Option<Value>, RecvError> {
+        let shard = store_tx.shard(&key);
+        get_value(key, shard).await
+    }
+
+    pub async fn set(key: String, value: Value, store_tx: StoreShards) -> Result<Option<Value>, RecvError> {
+        let shard = store_tx.shard(&key);
+        set_value(key, value, shard).await
+    }
+
+    pub async fn unset(key: String, store_tx: StoreShards) -> Result<Option<Value>, RecvError> {
+        let shard = store_tx.shard(&key);
+        remove_value(key, shard).await
+    }
+
+    pub async fn update(key: String, path: String, update: PathUpdate<Value>, store_tx: StoreShards) -> Result<Result<(Option<Value>, Value), String>, RecvError> {
+        let shard = store_tx.shard(&key);
+        update_value(key, path, update, shard).await
+    }
+
+    pub async fn get_collection(key: String, store_tx: StoreShards) -> Result<Option<Vec<Value>>, RecvError> {
+        let shard = store_tx.shard(&key);
+        get_collection(key, shard).await
+    }
+
+    pub async fn add_to_collection(key: String, value: Value, store_tx: StoreShards) -> Result<Result<bool, String>, RecvError> {
+        let shard = store_tx.shard(&key);
+        add_value_to_collection(key, value, shard).await
+    }
+
+    pub async fn remove_value_from_collection(key: String, value: Value, store_tx: StoreShards) -> Result<bool, RecvError> {
+        let shard = store_tx.shard(&key);
+        remove_value_from_collection(key, value, shard).await
+    }
+
+    pub async fn push_to_list(key: String, value: Value, front: bool, store_tx: StoreShards) -> Result<Result<usize, String>, RecvError> {
+        let shard = store_tx.shard(&key);
+        push_to_list(key, value, front, shard).await
+    }
+
+    pub async fn pop_from_list(key: String, front: bool, store_tx: StoreShards) -> Result<Option<Value>, RecvError> {
+        let shard = store_tx.shard(&key);
+        pop_from_list(key, front, shard).await
+    }
+
+    pub async fn get_list_range(key: String, start: i64, stop: i64, store_tx: StoreShards) -> Result<Vec<Value>, RecvError> {
+        let shard = store_tx.shard(&key);
+        get_list_range(key, start, stop, shard).await
+    }
+
+    pub async fn set_field(key: String, field: String, value: Value, store_tx: StoreShards) -> Result<Result<Option<Value>, String>, RecvError> {
+        let shard = store_tx.shard(&key);
+        set_field(key, field, value, shard).await
+    }
+
+    pub async fn get_field(key: String, field: String, store_tx: StoreShards) -> Result<Option<Value>, RecvError> {
+        let shard = store_tx.shard(&key);
+        get_field(key, field, shard).await
+    }
+
+    pub async fn delete_field(key: String, field: String, store_tx: StoreShards) -> Result<Option<Value>, RecvError> {
+        let shard = store_tx.shard(&key);
+        delete_field(key, field, shard).await
+    }
+
+    pub async fn add_to_sorted_set(key: String, value: Value, score: f64, store_tx: StoreShards) -> Result<Result<bool, String>, RecvError> {
+        let shard = store_tx.shard(&key);
+        add_to_sorted_set(key, value, score, shard).await
+    }
+
+    pub async fn get_sorted_set_range_by_rank(key: String, start: i64, stop: i64, store_tx: StoreShards) -> Result<Vec<(Value, f64)>, RecvError> {
+        let shard = store_tx.shard(&key);
+        get_sorted_set_range_by_rank(key, start, stop, shard).await
+    }
+
+    pub async fn get_sorted_set_range_by_score(key: String, min: f64, max: f64, store_tx: StoreShards) -> Result<Vec<(Value, f64)>, RecvError> {
+        let shard = store_tx.shard(&key);
+        get_sorted_set_range_by_score(key, min, max, shard).await
+    }
+
+    pub async fn inspect(key: String, store_tx: StoreShards) -> Result<Vec<StoredValue>, RecvError> {
+        let shard = store_tx.shard(&key);
+        inspect_key(key, shard).await
+    }
+
+    /// A page of at most `count` keys passing `filter`, gathered from every shard. Keys come in byte
+    /// order and each page resumes after the last key of the one before, so a scan returns every key
+    /// that exists throughout it exactly once, however the store changes in the meantime.
+    pub async fn scan(filter: ScanFilter, count: usize, store_tx: StoreShards) -> Result<ScanPage, RecvError> {
+        let count = count.clamp(1, MAX_SCAN_COUNT);
+        let mut keys = Vec::new();
+        for shard in store_tx.all() {
+            keys.extend(scan_keys(filter.clone(), count + 1, shard).await?);
+        }
+        keys.sort();
+        let cursor = match keys.len() > count {
+            true => {
+                keys.truncate(count);
+                keys.last().cloned()
+            },
+            false => None
+        };
+        Ok(ScanPage { keys, cursor })
+    }
+
+    /// Writes a value read from a snapshot, replacing the one of the same kind under `key`.
+    pub async fn restore(key: String, value: StoredValue, store_tx: StoreShards) -> Result<bool, RecvError> {
+        let shard = store_tx.shard(&key);
+        restore_key(key, value, shard).await
+    }
+
+    /// Removes the string, collection, list, hash and sorted set held under `key`.
+    pub async fn delete(key: String, store_tx: StoreShards) -> Result<bool, RecvError> {
+        let shard = store_tx.shard(&key);
+        delete_key(key, shard).await
+    }
+}
+
+/// Keys a Scan returns when it does not say how many.
+pub const DEFAULT_SCAN_COUNT: usize = 100;
+pub const MAX_SCAN_COUNT: usize = 1000;
+
+/// Which keys a scan returns: those after `after`, starting with `prefix` and matching the glob `pattern`.
+#[derive(Debug, Clone, Default)]
+pub struct ScanFilter {
+    pub after: Option<String>,
+    pub prefix: String,
+    pub pattern: Option<String>
+}
+
+impl ScanFilter {
+    pub fn matches(&self, key: &str) -> bool {
+        self.after.as_deref().is_none_or(|after| key > after)
+            && key.starts_with(&self.prefix)
+            && self.pattern.as_deref().is_none_or(|pattern| glob_match(pattern, key))
+    }
+}
+
+/// Matches `text` against a glob where `*` stands for any run of characters, `?` for any single one,
+/// and `\` makes the next character literal.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and the text position it currently stands up to, to backtrack to.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            },
+            Some('?') => {
+                p += 1;
+                t += 1;
+                continue;
+            },
+            Some('\\') if pattern.get(p + 1) == Some(&text[t]) => {
+                p += 2;
+                t += 1;
+                continue;
+            },
+            Some(literal) if *literal != '\\' && *literal == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            },
+            _ => ()
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                star = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            },
+            None => return false
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Collections and sorted sets hold their members as canonical JSON text, since `Value` is not hashable.
+pub fn member_key(value: &Value) -> String {
+    value.to_string()
+}
+
+pub fn member_value(key: &str) -> Value {
+    serde_json::from_str(key).unwrap_or_else(|_| Value::String(String::from(key)))
+}
+
+/// Resolves an inclusive, Redis-style `start..=stop` range (negative indexes count from the end)
+/// against a collection of `len` items. Returns `None` when the range is empty.
+pub fn resolve_range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if len == 0 || start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+/// Members ordered by score, ties broken by the member itself. The order is kept in a tree, with the
+/// score of each member on the side, so that adding or rescoring a member is O(log n).
+#[derive(Debug, Default, Clone)]
+pub struct SortedSet {
+    entries: BTreeSet<(Score, String)>,
+    scores: HashMap<String, f64>,
+}
+
+impl SortedSet {
+    /// Adds `member` with `score`, or updates its score. Returns true if the set changed: the member
+    /// is new or its score is.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        let score = Score::new(score);
+        match self.scores.insert(member.clone(), score.0) {
+            Some(previous) if Score(previous) == score => false,
+            Some(previous) => {
+                self.entries.remove(&(Score(previous), member.clone()));
+                self.entries.insert((score, member));
+                true
+            },
+            None => {
+                self.entries.insert((score, member));
+                true
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        self.scores.contains_key(member)
+    }
+
+    pub fn range_by_rank(&self, start: i64, stop: i64) -> Vec<(String, f64)> {
+        match resolve_range(self.entries.len(), start, stop) {
+            Some((start, stop)) => self.entries.iter().skip(start).take(stop - start + 1).map(|(score, member)| (member.clone(), score.0)).collect(),
+            None => Vec::new()
+        }
     }
 
-    pub async fn unset(key: String, store_tx: Sender<Command<String>>) -> Result<Option<String>, RecvError> {
-        remove_value(key, store_tx).await
+    pub fn range_by_score(&self, min: f64, max: f64) -> Vec<(String, f64)> {
+        if min > max {
+            return Vec::new();
+        }
+        self.entries.range((Score::new(min), String::new())..)
+            .take_while(|(score, _)| score.0 <= max)
+            .map(|(score, member)| (member.clone(), score.0))
+            .collect()
     }
+}
+
+/// A score in total order, as the keys of a tree need. Zero is kept unsigned, so that -0 and 0 rank alike.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl Score {
+    fn new(score: f64) -> Score {
+        Score(score + 0.0)
+    }
+}
 
-    pub async fn add_to_collection(key: String, value: String, store_tx: Sender<Command<String>>) -> Result<bool, RecvError> {
-        add_value_to_collection(key, value, store_tx).await
+impl PartialEq for Score {
+    fn eq(&self, other: &Score) -> bool {
+        self.cmp(other) == Ordering::Equal
     }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Score) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-    pub async fn remove_value_from_collection(key: String, value: String, store_tx: Sender<Command<String>>) -> Result<bool, RecvError> {
-        remove_value_from_collection(key, value, store_tx).await
+impl Ord for Score {
+    fn cmp(&self, other: &Score) -> Ordering {
+        self.0.total_cmp(&other.0)
     }
 }
 
 pub struct Subscribers;
 impl Subscribers {
-    pub async fn get_subscribers(topic: String, subscriptions_tx: Sender<Command<Client>>) -> Result<Option<HashSet<Client>>, RecvError> {
-        get_collection(topic, subscriptions_tx).await
+    /// The current subscribers of `topic`, read from the snapshot rather than through the actor.
+    pub fn get_subscribers(topic: &str, subscriptions_tx: &SubscriptionsTx) -> Option<Arc<Vec<Client>>> {
+        subscriptions_tx.subscribers(topic)
     }
 
-    pub async fn add_subscriber(topic: String, subscriber: Client, subscriptions_tx: Sender<Command<Client>>) -> Result<bool, RecvError> {
-        add_value_to_collection(topic, subscriber, subscriptions_tx).await
+    pub async fn add_subscriber(topic: String, subscriber: Client, subscriptions_tx: SubscriptionsTx) -> Result<bool, RecvError> {
+        // Subscriptions have no maximum, so the subscriber is always added unless already there.
+        add_value_to_collection(topic, subscriber, subscriptions_tx.sender()).await.map(|added| added.unwrap_or(false))
     }
 
-    pub async fn remove_subscriber(topic: String, subscriber: Client, subscriptions_tx: Sender<Command<Client>>) -> Result<bool, RecvError> {
-        remove_value_from_collection(topic, subscriber, subscriptions_tx).await
+    pub async fn remove_subscriber(topic: String, subscriber: Client, subscriptions_tx: SubscriptionsTx) -> Result<bool, RecvError> {
+        remove_value_from_collection(topic, subscriber, subscriptions_tx.sender()).await
+    }
+
+    /// Topics with at least one subscriber on this node.
+    pub async fn get_topics(subscriptions_tx: SubscriptionsTx) -> Result<Vec<String>, RecvError> {
+        get_keys(subscriptions_tx.sender()).await
+    }
+
+    pub async fn remove_subscriber_from_all(subscriber: Client, subscriptions_tx: SubscriptionsTx) -> Result<Vec<(String, Client)>, RecvError> {
+        remove_value_from_all_collections(subscriber, subscriptions_tx.sender()).await
     }
 }
 
 pub type Subscriptions = Arc<Mutex<HashMap<String, HashSet<Client>>>>;
 
+pub struct QueueGroups;
+impl QueueGroups {
+    /// Narrows the subscribers of a topic down to the recipients of its next message: every subscriber outside
+    /// of a queue group, plus one member of each group, taken round-robin with the topic's group cursors.
+    pub fn select<'a>(subscribers: Vec<&'a Client>, cursors: Option<&GroupCursors>) -> Vec<&'a Client> {
+        let mut recipients = Vec::new();
+        let mut groups = BTreeMap::<&str, Vec<&Client>>::new();
+        for subscriber in subscribers {
+            match subscriber.options.group.as_deref() {
+                Some(group) => groups.entry(group).or_default().push(subscriber),
+                None => recipients.push(subscriber)
+            }
+        }
+        for (group, mut members) in groups {
+            members.sort_by(|left, right| left.user_id.cmp(&right.user_id));
+            // A group that joined after the cursors were read starts from its first member.
+            let cursor = cursors.and_then(|cursors| cursors.get(group)).map_or(0, |cursor| cursor.fetch_add(1, AtomicOrdering::Relaxed));
+            recipients.push(members.swap_remove(cursor % members.len()));
+        }
+        recipients
+    }
+}
+
+/// Reply inboxes of in-flight requests, mapping each inbox id to the requesting user id.
+pub struct Inboxes;
+impl Inboxes {
+    pub async fn open(inbox: String, requester: String, inbox_tx: Sender<Command<String>>) -> Result<Option<String>, RecvError> {
+        set_value(inbox, requester, inbox_tx).await
+    }
+
+    /// Closes the inbox, returning its requester if it was still open.
+    pub async fn close(inbox: String, inbox_tx: Sender<Command<String>>) -> Result<Option<String>, RecvError> {
+        remove_value(inbox, inbox_tx).await
+    }
+}
+
+/// The last message published with `retain` on each topic, handed to new subscribers.
+pub struct Retained;
+impl Retained {
+    pub async fn get(topic: String, retained_tx: Sender<Command<Value>>) -> Result<Option<Value>, RecvError> {
+        get_value(topic, retained_tx).await
+    }
+
+    pub async fn set(topic: String, message: Value, retained_tx: Sender<Command<Value>>) -> Result<Option<Value>, RecvError> {
+        set_value(topic, message, retained_tx).await
+    }
+
+    pub async fn clear(topic: String, retained_tx: Sender<Command<Value>>) -> Result<Option<Value>, RecvError> {
+        remove_value(topic, retained_tx).await
+    }
+
+    /// Topics with a retained message.
+    pub async fn topics(retained_tx: Sender<Command<Value>>) -> Result<Vec<String>, RecvError> {
+        get_keys(retained_tx).await
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
+    use crate::serialize::EventKind;
+    use crate::subscriptions::GroupCursors;
+    use super::{glob_match, resolve_range, spawn_clients, Client, Clients, QueueGroups, ScanFilter, SortedSet, SubscriptionOptions};
+
     #[test]
     fn it_works() {
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_resolve_range() {
+        assert_eq!(resolve_range(5, 0, -1), Some((0, 4)));
+        assert_eq!(resolve_range(5, 1, 2), Some((1, 2)));
+        assert_eq!(resolve_range(5, -2, -1), Some((3, 4)));
+        assert_eq!(resolve_range(5, 3, 100), Some((3, 4)));
+        assert_eq!(resolve_range(5, 4, 2), None);
+        assert_eq!(resolve_range(0, 0, -1), None);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("user:*", "user:1"));
+        assert!(glob_match("user:*:cart", "user:12:cart"));
+        assert!(!glob_match("user:*:cart", "user:12:carts"));
+        assert!(glob_match("*a*b", "xaybzab"));
+        assert!(glob_match("item-??", "item-42"));
+        assert!(!glob_match("item-??", "item-4"));
+        assert!(glob_match("\\*", "*"));
+        assert!(!glob_match("\\*", "a"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+
+        let filter = ScanFilter { after: Some(String::from("user:1")), prefix: String::from("user:"), pattern: Some(String::from("*:cart")) };
+        assert!(filter.matches("user:2:cart"));
+        assert!(!filter.matches("user:1"));
+        assert!(!filter.matches("user:0:cart"));
+        assert!(!filter.matches("admin:2:cart"));
+    }
+
+    #[test]
+    fn test_sorted_set_ordering() {
+        let mut set = SortedSet::default();
+        assert!(set.insert(String::from("b"), 2.0));
+        assert!(set.insert(String::from("a"), 3.0));
+        assert!(set.insert(String::from("c"), 1.0));
+        assert!(set.insert(String::from("a"), 0.5));
+
+        assert_eq!(set.range_by_rank(0, -1), vec![(String::from("a"), 0.5), (String::from("c"), 1.0), (String::from("b"), 2.0)]);
+        assert_eq!(set.range_by_rank(-1, -1), vec![(String::from("b"), 2.0)]);
+        assert_eq!(set.range_by_score(0.75, 2.0), vec![(String::from("c"), 1.0), (String::from("b"), 2.0)]);
+
+        // Equal scores rank by member, and rescoring keeps a single entry.
+        assert!(set.insert(String::from("d"), 1.0));
+        assert!(!set.insert(String::from("d"), 1.0));
+        assert!(set.insert(String::from("a"), 1.0));
+        assert_eq!(set.len(), 4);
+        assert_eq!(set.range_by_score(1.0, 1.0), vec![(String::from("a"), 1.0), (String::from("c"), 1.0), (String::from("d"), 1.0)]);
+        assert_eq!(set.range_by_rank(1, 2), vec![(String::from("c"), 1.0), (String::from("d"), 1.0)]);
+        assert!(set.range_by_score(3.0, 2.0).is_empty());
+    }
+
+    #[test]
+    fn test_queue_groups_round_robin() {
+        let subscriber = |user_id: &str, group: Option<&str>| Client {
+            user_id: String::from(user_id),
+            options: SubscriptionOptions { group: group.map(String::from), ..SubscriptionOptions::default() },
+            ..Client::default()
+        };
+        let subscribers = [subscriber("a", Some("workers")), subscriber("b", Some("workers")), subscriber("c", None)];
+        let cursors = GroupCursors::from([(String::from("workers"), Default::default())]);
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            let mut recipients: Vec<String> = QueueGroups::select(subscribers.iter().collect(), Some(&cursors)).into_iter().map(|client| client.user_id.clone()).collect();
+            recipients.sort();
+            received.push(recipients);
+        }
+        assert_eq!(received, vec![vec!["a", "c"], vec!["b", "c"], vec!["a", "c"], vec!["b", "c"]]);
+    }
+
+    #[tokio::test]
+    async fn test_track_delivery_and_acknowledge() {
+        let client = Client { user_id: String::from("1"), ..Client::default() };
+        let copy = client.clone();
+
+        let envelope: serde_json::Value = serde_json::from_str(&client.track_delivery("topic", EventKind::Document, String::from(r#"{"a":1}"#)).await).unwrap();
+        assert_eq!(envelope["topic"], "topic");
+        assert_eq!(envelope["kind"], "Document");
+        assert_eq!(envelope["value"], serde_json::json!({ "a": 1 }));
+
+        let id = envelope["id"].as_str().unwrap();
+        assert_eq!(copy.pending.lock().await.len(), 1);
+        assert!(copy.acknowledge(id).await);
+        assert!(!client.acknowledge(id).await);
+        assert!(client.pending.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_register_client_quota() {
+        let clients = Clients::default();
+        let clients_tx = spawn_clients(clients.clone());
+        let client = |user_id: &str| Client { user_id: String::from(user_id), ..Client::default() };
+
+        let registrations = futures::future::join_all(["1", "2", "3"].map(|user_id| Client::register_client(client(user_id), Some(2), clients_tx.clone()))).await;
+        assert_eq!(registrations.into_iter().filter(|registered| *registered == Ok(true)).count(), 2);
+        assert_eq!(clients.lock().await.len(), 2);
+
+        // An id already registered is not replaced.
+        let registered = clients.lock().await.keys().next().cloned().unwrap();
+        assert_eq!(Client::register_client(client(&registered), None, clients_tx.clone()).await, Ok(false));
+        Client::remove_client(registered, clients_tx.clone()).await.unwrap();
+        assert_eq!(Client::register_client(client("4"), Some(2), clients_tx).await, Ok(true));
+    }
+}
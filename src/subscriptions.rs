@@ -0,0 +1,189 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use arc_swap::ArcSwap;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use crate::command::Command;
+use crate::store::Client;
+
+/// The subscribers of every topic, as last published by the subscriptions actor.
+pub type Snapshot = HashMap<String, Arc<Vec<Client>>>;
+
+/// Round-robin position of each queue group of a topic, by group name.
+pub type GroupCursors = HashMap<String, Arc<AtomicUsize>>;
+
+/// Sender of the subscriptions actor, along with the snapshot of the subscriptions it publishes after
+/// every change. Changes queue on the actor; lookups read the snapshot without waiting on the actor.
+#[derive(Clone, Debug)]
+pub struct SubscriptionsTx {
+    sender: Sender<Command<Client>>,
+    snapshot: Arc<ArcSwap<Snapshot>>,
+    cursors: Arc<ArcSwap<HashMap<String, Arc<GroupCursors>>>>
+}
+
+impl SubscriptionsTx {
+    pub fn sender(&self) -> Sender<Command<Client>> {
+        self.sender.clone()
+    }
+
+    pub fn subscribers(&self, topic: &str) -> Option<Arc<Vec<Client>>> {
+        self.snapshot.load().get(topic).cloned()
+    }
+
+    /// The cursors of the queue groups subscribed to `topic`, kept by the actor for as long as each group has members.
+    pub fn group_cursors(&self, topic: &str) -> Option<Arc<GroupCursors>> {
+        self.cursors.load().get(topic).cloned()
+    }
+
+    /// The subscribers of every topic.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.load_full()
+    }
+}
+
+/// Spawns the subscriptions actor.
+pub fn spawn() -> SubscriptionsTx {
+    let (sender, subscriptions_rx) = mpsc::channel::<Command<Client>>(32);
+    let snapshot = Arc::new(ArcSwap::from_pointee(Snapshot::new()));
+    let cursors = Arc::new(ArcSwap::from_pointee(HashMap::new()));
+    tokio::spawn(run(subscriptions_rx, snapshot.clone(), cursors.clone()));
+    SubscriptionsTx { sender, snapshot, cursors }
+}
+
+/// The subscriptions actor. It is the only writer of the subscriptions, and after each change swaps in
+/// a new snapshot that shares the subscriber lists of the topics that did not change. The cursors of
+/// the queue groups are swapped in first, so that readers of a snapshot find the cursors of its groups.
+pub async fn run(mut subscriptions_rx: Receiver<Command<Client>>, snapshot: Arc<ArcSwap<Snapshot>>, cursors: Arc<ArcSwap<HashMap<String, Arc<GroupCursors>>>>) {
+    let mut subscriptions = HashMap::<String, HashSet<Client>>::new();
+    let mut published = Snapshot::new();
+    let mut group_cursors = HashMap::<String, Arc<GroupCursors>>::new();
+    while let Some(cmd) = subscriptions_rx.recv().await {
+        let changed = match cmd {
+            Command::GetCollection { key, responder } => {
+                let result = subscriptions.get(&key).map(|subscribers| subscribers.iter().cloned().collect());
+                info!("Get key {:?} from the subscriptions store. Result: {:?}", key, result);
+                let _ = responder.send(result);
+                Vec::new()
+            },
+            Command::GetKeys { responder } => {
+                let result = subscriptions.iter().filter(|(_, subscribers)| !subscribers.is_empty()).map(|(topic, _)| topic.clone()).collect();
+                info!("Get topics from the subscriptions store. Result: {:?}", result);
+                let _ = responder.send(result);
+                Vec::new()
+            },
+            Command::RemoveFromAllCollections { value, responder } => {
+                let mut result = Vec::new();
+                for (topic, subscribers) in subscriptions.iter_mut() {
+                    if let Some(subscriber) = subscribers.take(&value) {
+                        result.push((topic.clone(), subscriber));
+                    }
+                }
+                let changed = result.iter().map(|(topic, _)| topic.clone()).collect();
+                info!("Remove {:?} from all topics in the subscriptions store. Topics: {:?}", value, changed);
+                let _ = responder.send(result);
+                changed
+            },
+            Command::RemoveFromCollection { key, value, responder } => {
+                let result = match subscriptions.get_mut(&key) {
+                    Some(collection) => collection.remove(&value),
+                    None => false
+                };
+                info!("Remove key {:?} from the subscriptions store. Result: {:?}", key, result);
+                let _ = responder.send(result);
+                if result { vec![key] } else { Vec::new() }
+            },
+            Command::AddToCollection { key, value, responder } => {
+                let result = subscriptions.entry(key.clone()).or_default().insert(value);
+                info!("Add to collection in the subscriptions store. Result: {:?}", result);
+                let _ = responder.send(Ok(result));
+                if result { vec![key] } else { Vec::new() }
+            }
+            _ => {
+                error!("Only Get, Set and Unset may be used with subscriptions.");
+                Vec::new()
+            }
+        };
+        if changed.is_empty() {
+            continue;
+        }
+        for topic in changed {
+            match subscriptions.get(&topic) {
+                Some(subscribers) if !subscribers.is_empty() => {
+                    match regroup(subscribers, group_cursors.get(&topic)) {
+                        Some(groups) => group_cursors.insert(topic.clone(), Arc::new(groups)),
+                        None => group_cursors.remove(&topic)
+                    };
+                    published.insert(topic, Arc::new(subscribers.iter().cloned().collect()));
+                },
+                _ => {
+                    subscriptions.remove(&topic);
+                    published.remove(&topic);
+                    group_cursors.remove(&topic);
+                }
+            }
+        }
+        cursors.store(Arc::new(group_cursors.clone()));
+        snapshot.store(Arc::new(published.clone()));
+    }
+}
+
+/// The cursors of the groups among `subscribers`, carrying over those of groups that still have members.
+fn regroup(subscribers: &HashSet<Client>, previous: Option<&Arc<GroupCursors>>) -> Option<GroupCursors> {
+    let groups: GroupCursors = subscribers.iter()
+        .filter_map(|subscriber| subscriber.options.group.clone())
+        .map(|group| {
+            let cursor = previous.and_then(|cursors| cursors.get(&group).cloned()).unwrap_or_default();
+            (group, cursor)
+        })
+        .collect();
+    if groups.is_empty() { None } else { Some(groups) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use crate::store::{Client, SubscriptionOptions, Subscribers};
+    use super::spawn;
+
+    #[tokio::test]
+    async fn test_snapshot_follows_changes() {
+        let subscriptions_tx = spawn();
+        let client = |user_id: &str| Client { user_id: String::from(user_id), ..Default::default() };
+        assert!(subscriptions_tx.subscribers("a").is_none());
+
+        assert!(Subscribers::add_subscriber(String::from("a"), client("1"), subscriptions_tx.clone()).await.unwrap());
+        assert!(Subscribers::add_subscriber(String::from("b"), client("1"), subscriptions_tx.clone()).await.unwrap());
+        assert!(Subscribers::add_subscriber(String::from("a"), client("2"), subscriptions_tx.clone()).await.unwrap());
+        let before = subscriptions_tx.subscribers("b").unwrap();
+        assert_eq!(subscriptions_tx.subscribers("a").unwrap().len(), 2);
+
+        assert!(Subscribers::remove_subscriber(String::from("a"), client("2"), subscriptions_tx.clone()).await.unwrap());
+        assert_eq!(subscriptions_tx.subscribers("a").unwrap().len(), 1);
+        assert!(std::sync::Arc::ptr_eq(&before, &subscriptions_tx.subscribers("b").unwrap()));
+
+        Subscribers::remove_subscriber_from_all(client("1"), subscriptions_tx.clone()).await.unwrap();
+        assert!(subscriptions_tx.subscribers("a").is_none());
+        assert!(subscriptions_tx.subscribers("b").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_group_cursors_follow_groups() {
+        let subscriptions_tx = spawn();
+        let worker = |user_id: &str| Client {
+            user_id: String::from(user_id),
+            options: SubscriptionOptions { group: Some(String::from("workers")), ..SubscriptionOptions::default() },
+            ..Default::default()
+        };
+        Subscribers::add_subscriber(String::from("jobs"), worker("1"), subscriptions_tx.clone()).await.unwrap();
+        let cursor = subscriptions_tx.group_cursors("jobs").unwrap()["workers"].clone();
+        cursor.fetch_add(1, Ordering::Relaxed);
+
+        Subscribers::add_subscriber(String::from("jobs"), worker("2"), subscriptions_tx.clone()).await.unwrap();
+        assert!(Arc::ptr_eq(&cursor, &subscriptions_tx.group_cursors("jobs").unwrap()["workers"]));
+
+        Subscribers::remove_subscriber(String::from("jobs"), worker("1"), subscriptions_tx.clone()).await.unwrap();
+        Subscribers::remove_subscriber(String::from("jobs"), worker("2"), subscriptions_tx.clone()).await.unwrap();
+        assert!(subscriptions_tx.group_cursors("jobs").is_none());
+    }
+}
@@ -1,59 +1,325 @@
-use warp::ws::{Message, WebSocket};
-use crate::{store::Client, handler::{publish_handler, subscription_handler}, serialize::{RequestAction, SocketRequest}};
-use tokio::sync::mpsc::{self, Sender};
-use futures::{StreamExt};
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use serde_json::from_str;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use warp::ws::Message;
+use warp::http::{header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, UPGRADE}, Request, Response, StatusCode};
+use warp::hyper::{upgrade::{OnUpgrade, Upgraded}, Body};
+use tokio_tungstenite::{tungstenite::{self, handshake::derive_accept_key, protocol::{frame::CloseFrame, Role}}, WebSocketStream};
+use crate::{store::Client, handler::{ack_handler, consumer_handler, direct_message_handler, disconnect_handler, expiry_handler, hello_handler, presence_handler, publish_handler, query_handler, redelivery_handler, reply_handler, request_handler, send_error, subscription_handler, usage_handler, Refused}, serialize::{ErrorCode, RequestAction, SocketRequest, WillOptions}};
+use tokio::sync::{mpsc::{self, Sender}, Notify};
+use futures::{SinkExt, StreamExt};
+use serde_json::{from_str, Value};
 use log::{info, error};
 use crate::command::{Command};
+use crate::event_log::EventLogTx;
+use crate::cluster::ClusterCommand;
+use crate::shard::StoreShards;
+use crate::subscriptions::SubscriptionsTx;
+use crate::codec::decode_request;
+use crate::deflate::{self, Transport};
+use crate::protocol::Session;
+use crate::limits::{ConnectionLimits, Limit, RateLimiter};
+use tokio::time::{self, Duration};
+use std::time::Instant;
+use crate::config::config;
 
+/// How often a connection checks its reliable events for ones to redeliver.
+const REDELIVERY_INTERVAL: Duration = Duration::from_secs(1);
 
-pub async fn client_connection(ws: WebSocket, id: String, mut client: Client, subscriptions_tx: Sender<Command<Client>>, clients_tx: Sender<Command<Client>>, store_tx: Sender<Command<String>>) {
+
+/// Liveness of a socket, from the pings the server sends it.
+#[derive(Debug)]
+struct Heartbeat {
+    missed: u32,
+    last_pong: Instant
+}
+
+impl Heartbeat {
+    fn new() -> Heartbeat {
+        Heartbeat { missed: 0, last_pong: Instant::now() }
+    }
+
+    /// Any frame from the client shows that the connection is alive, not only a pong.
+    fn alive(&mut self) {
+        self.missed = 0;
+        self.last_pong = Instant::now();
+    }
+
+    /// Counts the ping about to be sent. Returns false once `max_missed` pings went unanswered.
+    fn ping(&mut self, max_missed: u32) -> bool {
+        if self.missed >= max_missed {
+            return false;
+        }
+        self.missed += 1;
+        true
+    }
+}
+
+/// The socket of a client, over a transport that handles permessage-deflate when it was negotiated.
+pub type Socket = WebSocketStream<Transport<Upgraded>>;
+
+/// The pending upgrade of a request. The server moves it out of the request extensions, where only warp's
+/// own socket filter can take it, so that `/ws` can finish the handshake itself.
+#[derive(Clone)]
+pub struct Upgrade(Arc<Mutex<Option<OnUpgrade>>>);
+
+impl Upgrade {
+    pub fn keep(request: &mut Request<Body>) {
+        if let Some(on_upgrade) = request.extensions_mut().remove::<OnUpgrade>() {
+            request.extensions_mut().insert(Upgrade(Arc::new(Mutex::new(Some(on_upgrade)))));
+        }
+    }
+
+    fn take(&self) -> Option<OnUpgrade> {
+        self.0.lock().ok()?.take()
+    }
+}
+
+/// The WebSocket handshake of a `/ws` request, finished by the server rather than warp, whose sockets
+/// cannot negotiate permessage-deflate.
+pub struct Handshake {
+    pub key: String,
+    /// The `Sec-WebSocket-Extensions` offered by the client.
+    pub extensions: Option<String>,
+    pub upgrade: Option<Upgrade>
+}
+
+impl Handshake {
+    /// The `101 Switching Protocols` answering the handshake, with `headers` added. Once the connection is
+    /// upgraded, `on_socket` runs with the socket, deflating its messages if the client offered permessage-deflate.
+    pub fn on_upgrade<F, U>(self, headers: Vec<(&'static str, &'static str)>, on_socket: F) -> Option<Response<Body>>
+    where
+        F: FnOnce(Socket) -> U + Send + 'static,
+        U: std::future::Future<Output = ()> + Send + 'static
+    {
+        let on_upgrade = self.upgrade?.take()?;
+        let deflate = deflate::negotiate(self.extensions.as_deref());
+        let mut response = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_ACCEPT, derive_accept_key(self.key.as_bytes()));
+        if deflate {
+            response = response.header(SEC_WEBSOCKET_EXTENSIONS, deflate::ACCEPTED);
+        }
+        for (name, value) in headers {
+            response = response.header(name, value);
+        }
+        tokio::spawn(async move {
+            match on_upgrade.await {
+                Ok(upgraded) => {
+                    let transport = Transport::new(upgraded, deflate, config().compression_threshold);
+                    on_socket(WebSocketStream::from_raw_socket(transport, Role::Server, None).await).await;
+                },
+                Err(err) => error!("Error upgrading a socket: {}", err)
+            }
+        });
+        response.body(Body::empty()).ok()
+    }
+}
+
+/// Senders of the actors a socket connection talks to.
+#[derive(Clone)]
+pub struct Channels {
+    pub subscriptions_tx: SubscriptionsTx,
+    pub clients_tx: Sender<Command<Client>>,
+    pub store_tx: StoreShards,
+    pub inbox_tx: Sender<Command<String>>,
+    pub log_tx: EventLogTx,
+    pub retained_tx: Sender<Command<Value>>,
+    pub cluster_tx: Sender<ClusterCommand>,
+    pub limiter: RateLimiter
+}
+
+/// Serves the socket of a client until it closes, drops, times out or is shut down by the server.
+pub async fn client_connection<S>(ws: WebSocketStream<S>, id: String, mut client: Client, will: Option<SocketRequest>, channels: Channels)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let Channels { subscriptions_tx, clients_tx, store_tx, log_tx, retained_tx, cluster_tx, .. } = channels.clone();
     println!("client connection: {}", id.to_string().clone());
-    let (client_ws_tx, mut client_ws_rx) = ws.split();
-    let (client_tx, client_rx) = mpsc::unbounded_channel::<Result<Message, warp::Error>>();
-    let client_rx = UnboundedReceiverStream::new(client_rx); 
+    let (mut client_ws_tx, mut client_ws_rx) = ws.split();
+    let (client_tx, mut client_rx) = mpsc::unbounded_channel::<Result<Message, warp::Error>>();
 
-    tokio::task::spawn(client_rx.forward(client_ws_tx));
+    tokio::task::spawn(async move {
+        while let Some(Ok(message)) = client_rx.recv().await {
+            if client_ws_tx.send(into_frame(message)).await.is_err() {
+                return;
+            }
+        }
+        let _ = client_ws_tx.close().await;
+    });
+    let shutdown = Arc::new(Notify::new());
     client.sender = Some(client_tx);
+    client.shutdown = Some(shutdown.clone());
+    client.offline_since = None;
+    let mut connection = client.clone();
     match Client::set_client(client, clients_tx.clone()).await {
         Ok(result) => info!("set client result: {:?}", result),
         Err(err) => error!("set client error: {:?}", err)
     }
+    match redelivery_handler(connection.clone(), true, subscriptions_tx.clone()).await {
+        Ok(_) => debug!("Redelivered pending events to client {}", id),
+        Err(_) => error!("#redelivery_handler error")
+    }
 
-    while let Some(result) = client_ws_rx.next().await {
-        let message = match result {
-            Ok(message) => message,
-            Err(err) => {
-                error!("error receiving ws message for id: {}): {}", id.clone(), err);
+    let mut redelivery = time::interval(REDELIVERY_INTERVAL);
+    let mut pings = time::interval_at(time::Instant::now() + config().ping_interval, config().ping_interval);
+    let mut heartbeat = Heartbeat::new();
+    let mut closed_cleanly = false;
+    let mut session = Session::default();
+    let mut limits = channels.limiter.connection();
+    loop {
+        // Biased, so that a shutdown wins over frames already waiting. The timers are only ready when due, so they never starve the socket.
+        tokio::select! {
+            biased;
+            _ = shutdown.notified() => {
+                info!("Client {} shut down by the server", id);
+                send(&connection, Message::close_with(1000u16, "disconnected by an administrator"));
                 break;
+            },
+            _ = pings.tick() => {
+                if !heartbeat.ping(config().max_missed_pongs) {
+                    warn!("Client {} missed {} pings, last heard from {:?} ago", id, heartbeat.missed, heartbeat.last_pong.elapsed());
+                    send(&connection, Message::close_with(1001u16, "ping timeout"));
+                    break;
+                }
+                send(&connection, Message::ping(Vec::new()));
+            },
+            _ = redelivery.tick() => {
+                match redelivery_handler(connection.clone(), false, subscriptions_tx.clone()).await {
+                    Ok(_) => debug!("Checked pending events of client {}", id),
+                    Err(_) => error!("#redelivery_handler error")
+                }
+            },
+            result = client_ws_rx.next() => {
+                let message = match result {
+                    Some(Ok(frame)) => match from_frame(frame) {
+                        Some(message) => message,
+                        None => continue
+                    },
+                    Some(Err(err)) => {
+                        error!("error receiving ws message for id: {}): {}", id.clone(), err);
+                        break;
+                    },
+                    None => break
+                };
+                if message.is_close() {
+                    closed_cleanly = true;
+                    break;
+                }
+                heartbeat.alive();
+                if message.is_pong() {
+                    continue;
+                }
+                if message.is_ping() {
+                    send(&connection, Message::pong(message.into_bytes()));
+                    continue;
+                }
+                client_message(&id, message, channels.clone(), &mut session, &mut limits).await;
+                if limits.is_abusive(config().rate_limits.disconnect_after) {
+                    warn!("Client {} keeps exceeding its rate limits, disconnecting it", id);
+                    send(&connection, Message::close_with(1008u16, "rate limit exceeded"));
+                    break;
+                }
             }
-        };
-        client_message(&id, message,subscriptions_tx.clone(), clients_tx.clone(),  store_tx.clone()).await;
+        }
+    }
+
+    match disconnect_handler(id.clone(), subscriptions_tx.clone(), cluster_tx.clone()).await {
+        Ok(_) => info!("Client {} removed from its subscriptions", id),
+        Err(_) => error!("#disconnect_handler error")
     }
 
-    match Client::remove_client(id, clients_tx.clone()).await {
-        Ok(result) => info!("Client disconnected: {:?}", result.unwrap()),
-        Err(_) => error!("get value error")
+    if !closed_cleanly {
+        if let Some(request) = will {
+            let bytes = serde_json::to_string(&request).map_or(0, |request| request.len());
+            match channels.limiter.admit(&mut limits, &id, request.action, &request.topic, bytes) {
+                Ok(_) => match publish_handler(request, id.clone(), subscriptions_tx.clone(), store_tx, log_tx, retained_tx, cluster_tx).await {
+                    Ok(_) => info!("Published the will of client {}", id),
+                    Err(_) => error!("#publish_handler error")
+                },
+                Err(limit) => warn!("Dropped the will of client {}, throttled by {:?}", id, limit)
+            }
+        }
+    }
+
+    if connection.pending.lock().await.is_empty() {
+        match Client::remove_client(id.clone(), clients_tx.clone()).await {
+            Ok(Some(client)) => info!("Client disconnected: {:?}", client),
+            Ok(None) => info!("Client {} disconnected, already removed", id),
+            Err(_) => error!("get value error")
+        }
+    } else {
+        // Keep the registration so that the client can reconnect and receive its unacked events, for a while.
+        let since = Instant::now();
+        connection.sender = None;
+        connection.shutdown = None;
+        connection.offline_since = Some(since);
+        match Client::set_client(connection, clients_tx.clone()).await {
+            Ok(_) => info!("Client {} disconnected with unacked events", id),
+            Err(err) => error!("set client error: {:?}", err)
+        }
+        tokio::spawn(async move {
+            time::sleep(config().offline_timeout).await;
+            if expiry_handler(id, since, clients_tx, subscriptions_tx).await.is_err() {
+                error!("#expiry_handler error");
+            }
+        });
     }
 }
 
-async fn client_message(user_id: &str, msg: Message, subscriptions_tx: Sender<Command<Client>>, clients_tx: Sender<Command<Client>>, store_tx: Sender<Command<String>>) {
-    debug!("client message: {}, {:?}", user_id.to_string().clone(), msg.to_str());
+/// The server handles messages as warp's, which its sockets are not.
+fn into_frame(message: Message) -> tungstenite::Message {
+    if message.is_text() {
+        tungstenite::Message::Text(String::from(message.to_str().unwrap_or_default()))
+    } else if message.is_ping() {
+        tungstenite::Message::Ping(message.into_bytes())
+    } else if message.is_pong() {
+        tungstenite::Message::Pong(message.into_bytes())
+    } else if message.is_close() {
+        tungstenite::Message::Close(message.close_frame().map(|(code, reason)| CloseFrame { code: code.into(), reason: String::from(reason).into() }))
+    } else {
+        tungstenite::Message::Binary(message.into_bytes())
+    }
+}
 
-    if msg.is_ping() {
-        debug!("Ping from client {}", user_id);
+fn from_frame(frame: tungstenite::Message) -> Option<Message> {
+    match frame {
+        tungstenite::Message::Text(text) => Some(Message::text(text)),
+        tungstenite::Message::Binary(bytes) => Some(Message::binary(bytes)),
+        tungstenite::Message::Ping(bytes) => Some(Message::ping(bytes)),
+        tungstenite::Message::Pong(bytes) => Some(Message::pong(bytes)),
+        tungstenite::Message::Close(Some(frame)) => Some(Message::close_with(u16::from(frame.code), frame.reason.into_owned())),
+        tungstenite::Message::Close(None) => Some(Message::close()),
+        tungstenite::Message::Frame(_) => None
     }
+}
 
-    let message = match msg.to_str() {
-        Ok(string) => string,
-        Err(_) => {
-            error!("Error while parsing message to string");
-            return;
+fn send(client: &Client, message: Message) {
+    if let Some(sender) = &client.sender {
+        if sender.send(Ok(message)).is_err() {
+            debug!("Socket of client {} is already closed", client.user_id);
         }
+    }
+}
+
+/// The Set published for a client whose connection dropped, if it left a will.
+pub fn will_request(user_id: &str, will: WillOptions) -> Option<SocketRequest> {
+    let topic = will.will_topic?;
+    let message = match will.will_message {
+        Some(message) => from_str(&message).unwrap_or(Value::String(message)),
+        None => Value::Null
     };
+    let mut request = SocketRequest::new(RequestAction::Set, String::from(user_id), topic, Some(message));
+    request.retain = will.will_retain;
+    Some(request)
+}
+
+async fn client_message(user_id: &str, msg: Message, channels: Channels, session: &mut Session, limits: &mut ConnectionLimits) {
+    let Channels { subscriptions_tx, clients_tx, store_tx, inbox_tx, log_tx, retained_tx, cluster_tx, limiter } = channels;
+    debug!("client message: {}, {:?}", user_id.to_string().clone(), msg);
 
-    let socket_request: SocketRequest = match from_str(&message) {
+    let socket_request: SocketRequest = match decode_request(&msg) {
         Ok(request) => request,
         Err(err) => {
             error!("Error while parsing socket request: {}", err);
@@ -61,51 +327,393 @@ async fn client_message(user_id: &str, msg: Message, subscriptions_tx: Sender<Co
         }
     };
 
-    match socket_request.action {
+    let action = socket_request.action;
+    let topic = socket_request.topic.clone();
+    let errors_tx = clients_tx.clone();
+    if let Some(feature) = session.missing_feature(&socket_request) {
+        let message = format!("{:?} was not negotiated in the Hello", feature);
+        if send_error(String::from(user_id), ErrorCode::UnsupportedFeature, action, topic, message, errors_tx).await.is_err() {
+            error!("#send_error error");
+        }
+        return;
+    }
+    if let Some((error, message)) = socket_request.exceeds(&config().quotas) {
+        debug!("Client {} sent {:?} beyond its quotas: {}", user_id, action, message);
+        if send_error(String::from(user_id), error, action, topic, message, errors_tx).await.is_err() {
+            error!("#send_error error");
+        }
+        return;
+    }
+    let admitted = limiter.admit(limits, user_id, action, &topic, msg.as_bytes().len()).and_then(|_| match action {
+        RequestAction::Subscribe => {
+            let subscribers = subscriptions_tx.subscribers(&topic).map_or(0, |clients| clients.iter().filter(|client| client.user_id != user_id).count());
+            limiter.admit_subscriber(limits, &topic, subscribers)
+        },
+        _ => Ok(())
+    });
+    if let Err(limit) = admitted {
+        debug!("Client {} throttled by {:?}", user_id, limit);
+        let (error, message) = match limit {
+            Limit::Subscriptions => (ErrorCode::TooManySubscriptions, format!("Sockets may subscribe to at most {} topics", config().rate_limits.max_subscriptions.unwrap_or_default())),
+            Limit::TopicSubscribers => (ErrorCode::Throttled, format!("Topics may have at most {} subscribers", config().rate_limits.max_topic_subscribers.unwrap_or_default())),
+            limit => (ErrorCode::Throttled, format!("Exceeded the {:?} limit", limit))
+        };
+        if send_error(String::from(user_id), error, action, topic, message, errors_tx).await.is_err() {
+            error!("#send_error error");
+        }
+        return;
+    }
+
+    let outcome = match socket_request.action {
+        RequestAction::Hello => {
+            match hello_handler(socket_request, String::from(user_id), clients_tx).await {
+                Ok(negotiated) => {
+                    *session = negotiated;
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#hello_handler error");
+                    Err(rejection)
+                }
+            }
+        },
         RequestAction::Subscribe => {
-            match subscription_handler(socket_request, String::from(user_id), subscriptions_tx, clients_tx).await {
-                Ok(_) => info!("client {} subscribed successfully", user_id),
-                Err(_) => error!("#subscribe_handler error")
+            match subscription_handler(socket_request, String::from(user_id), subscriptions_tx, clients_tx, retained_tx, cluster_tx).await {
+                Ok(_) => {
+                    info!("client {} subscribed successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#subscribe_handler error");
+                    Err(rejection)
+                }
             }
         },
         RequestAction::Unsubscribe => {
-            match subscription_handler(socket_request, String::from(user_id), subscriptions_tx, clients_tx).await {
-                Ok(_) => info!("client {} unsubscribed successfully", user_id),
-                Err(_) => error!("#unsubscribe_handler error")
+            match subscription_handler(socket_request, String::from(user_id), subscriptions_tx, clients_tx, retained_tx, cluster_tx).await {
+                Ok(_) => {
+                    info!("client {} unsubscribed successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#unsubscribe_handler error");
+                    Err(rejection)
+                }
             }
         },
         RequestAction::Set => {
-            match publish_handler(socket_request, String::from(user_id), subscriptions_tx, store_tx).await {
-                Ok(_) => info!("client {} published successfully", user_id),
-                Err(_) => error!("#publish_handler error")
+            match publish_handler(socket_request, String::from(user_id), subscriptions_tx, store_tx, log_tx, retained_tx.clone(), cluster_tx.clone()).await {
+                Ok(_) => {
+                    info!("client {} published successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#publish_handler error");
+                    Err(rejection)
+                }
             }
         },
         RequestAction::Unset => {
-            match publish_handler(socket_request, String::from(user_id), subscriptions_tx, store_tx).await {
-                Ok(_) => info!("client {} published successfully", user_id),
-                Err(_) => error!("#publish_handler error")
+            match publish_handler(socket_request, String::from(user_id), subscriptions_tx, store_tx, log_tx, retained_tx.clone(), cluster_tx.clone()).await {
+                Ok(_) => {
+                    info!("client {} published successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#publish_handler error");
+                    Err(rejection)
+                }
             }
         },
         RequestAction::RemoveFromCollection => {
-            match publish_handler(socket_request, String::from(user_id),  subscriptions_tx, store_tx).await {
-                Ok(_) => info!("client {} removed value successfully", user_id),
-                Err(_) => error!("#publish_handler error")
+            match publish_handler(socket_request, String::from(user_id), subscriptions_tx, store_tx, log_tx, retained_tx.clone(), cluster_tx.clone()).await {
+                Ok(_) => {
+                    info!("client {} removed value successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#publish_handler error");
+                    Err(rejection)
+                }
 
             }
         },
         RequestAction::AddToCollection => {
-            match publish_handler(socket_request, String::from(user_id),  subscriptions_tx, store_tx).await {
-                Ok(_) => info!("client {} added value successfully", user_id),
-                Err(_) => error!("#publish_handler error")
+            match publish_handler(socket_request, String::from(user_id), subscriptions_tx, store_tx, log_tx, retained_tx.clone(), cluster_tx.clone()).await {
+                Ok(_) => {
+                    info!("client {} added value successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#publish_handler error");
+                    Err(rejection)
+                }
 
             }
+        },
+        RequestAction::SetPath | RequestAction::MergePath | RequestAction::DeletePath |
+        RequestAction::PushToList | RequestAction::PopFromList | RequestAction::SetField | RequestAction::DeleteField | RequestAction::AddToSortedSet => {
+            match publish_handler(socket_request, String::from(user_id), subscriptions_tx, store_tx, log_tx, retained_tx.clone(), cluster_tx.clone()).await {
+                Ok(_) => {
+                    info!("client {} {:?} successfully", user_id, action);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#publish_handler error");
+                    Err(rejection)
+                }
+            }
+        },
+        RequestAction::DirectMessage => {
+            match direct_message_handler(socket_request, String::from(user_id), clients_tx).await {
+                Ok(_) => {
+                    info!("client {} sent a direct message successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#direct_message_handler error");
+                    Err(rejection)
+                }
+            }
+        },
+        RequestAction::Ack => {
+            match ack_handler(socket_request, String::from(user_id), clients_tx).await {
+                Ok(_) => {
+                    debug!("client {} acked successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#ack_handler error");
+                    Err(rejection)
+                }
+            }
+        },
+        RequestAction::CreateConsumer | RequestAction::GetConsumer | RequestAction::ResetConsumer | RequestAction::DeleteConsumer | RequestAction::FetchFromConsumer => {
+            match consumer_handler(socket_request, String::from(user_id), clients_tx, log_tx).await {
+                Ok(_) => {
+                    info!("client {} {:?} successfully", user_id, action);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#consumer_handler error");
+                    Err(rejection)
+                }
+            }
+        },
+        RequestAction::Request => {
+            match request_handler(socket_request, String::from(user_id), subscriptions_tx, clients_tx, inbox_tx).await {
+                Ok(_) => {
+                    info!("client {} sent a request successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#request_handler error");
+                    Err(rejection)
+                }
+            }
+        },
+        RequestAction::Reply => {
+            match reply_handler(socket_request, String::from(user_id), clients_tx, inbox_tx).await {
+                Ok(_) => {
+                    info!("client {} replied successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#reply_handler error");
+                    Err(rejection)
+                }
+            }
+        },
+        RequestAction::GetUsage => {
+            match usage_handler(socket_request, String::from(user_id), limits.subscriptions(), clients_tx).await {
+                Ok(_) => {
+                    debug!("client {} queried its usage successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#usage_handler error");
+                    Err(rejection)
+                }
+            }
+        },
+        RequestAction::GetPresence => {
+            match presence_handler(socket_request, String::from(user_id), subscriptions_tx, clients_tx).await {
+                Ok(_) => {
+                    info!("client {} queried presence successfully", user_id);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#presence_handler error");
+                    Err(rejection)
+                }
+            }
+        },
+        RequestAction::Get | RequestAction::GetCollection | RequestAction::GetListRange | RequestAction::GetField | RequestAction::GetSortedSetRangeByRank | RequestAction::GetSortedSetRangeByScore | RequestAction::Scan => {
+            match query_handler(socket_request, String::from(user_id), clients_tx, store_tx).await {
+                Ok(_) => {
+                    info!("client {} {:?} successfully", user_id, action);
+                    Ok(())
+                },
+                Err(rejection) => {
+                    error!("#query_handler error");
+                    Err(rejection)
+                }
+            }
         }
     };
+
+    let rejection = match outcome {
+        Ok(_) => {
+            match action {
+                RequestAction::Subscribe => limits.subscribed(&topic),
+                RequestAction::Unsubscribe => limits.unsubscribed(&topic),
+                _ => ()
+            }
+            return;
+        },
+        Err(rejection) => rejection
+    };
+    let (error, message) = match rejection.find::<Refused>() {
+        Some(refused) => (refused.error, refused.message.clone()),
+        None if session.reports_failures() => (ErrorCode::RequestFailed, format!("{:?} on {} failed", action, topic)),
+        None => return
+    };
+    if send_error(String::from(user_id), error, action, topic, message, errors_tx).await.is_err() {
+        error!("#send_error error");
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::HashMap, sync::Arc};
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tokio::sync::{mpsc, Mutex};
+    use tokio::time::{timeout, Duration};
+    use tokio_tungstenite::{tungstenite::{self, protocol::Role}, WebSocketStream};
+    use warp::Reply;
+    use super::{client_connection, will_request, Channels, Heartbeat};
+    use crate::{event_log, shard, store::{self, Client}, subscriptions};
+    use crate::command::Command;
+    use crate::handler::admin_disconnect_handler;
+    use crate::limits::RateLimiter;
+    use crate::serialize::{EventKind, RequestAction, SocketRequest, WillOptions};
+
     #[test]
     fn it_works() {
     }
+
+    #[test]
+    fn test_heartbeat() {
+        let mut heartbeat = Heartbeat::new();
+        assert!(heartbeat.ping(2));
+        assert!(heartbeat.ping(2));
+        heartbeat.alive();
+        assert!(heartbeat.ping(2));
+        assert!(heartbeat.ping(2));
+        assert!(!heartbeat.ping(2));
+    }
+
+    fn spawn_channels(clients: Arc<Mutex<HashMap<String, Client>>>) -> Channels {
+        let (keyspace_tx, _keyspace_rx) = mpsc::unbounded_channel();
+        let (replication_tx, _replication_rx) = mpsc::unbounded_channel();
+        let (inbox_tx, _inbox_rx) = mpsc::channel(32);
+        let (retained_tx, mut retained_rx) = mpsc::channel::<Command<serde_json::Value>>(32);
+        tokio::spawn(async move {
+            while let Some(cmd) = retained_rx.recv().await {
+                if let Command::GetItem { responder, .. } = cmd {
+                    let _ = responder.send(None);
+                }
+            }
+        });
+        let (cluster_tx, _cluster_rx) = mpsc::channel(32);
+        Channels {
+            subscriptions_tx: subscriptions::spawn(),
+            clients_tx: store::spawn_clients(clients),
+            store_tx: shard::spawn(1, keyspace_tx, replication_tx),
+            inbox_tx,
+            log_tx: event_log::spawn(None, 10),
+            retained_tx,
+            cluster_tx,
+            limiter: RateLimiter::new(Default::default())
+        }
+    }
+
+    fn subscribe(topic: &str) -> tungstenite::Message {
+        tungstenite::Message::text(json!(SocketRequest::new(RequestAction::Subscribe, String::from("1"), String::from(topic), None)).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_admin_disconnect_ends_the_connection() {
+        let clients = Arc::new(Mutex::new(HashMap::from([(String::from("1"), Client { user_id: String::from("1"), ..Default::default() })])));
+        let channels = spawn_channels(clients.clone());
+        let Channels { subscriptions_tx, clients_tx, cluster_tx, .. } = channels.clone();
+        let (server, client) = tokio::io::duplex(4096);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut socket = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let connection = tokio::spawn(client_connection(server, String::from("1"), Client { user_id: String::from("1"), ..Default::default() }, None, channels));
+
+        socket.send(subscribe("a")).await.unwrap();
+        socket.send(tungstenite::Message::text(json!(SocketRequest::new(RequestAction::GetUsage, String::from("1"), String::new(), None)).to_string())).await.unwrap();
+        timeout(Duration::from_secs(1), socket.next()).await.unwrap().unwrap().unwrap();
+        assert!(subscriptions_tx.subscribers("a").is_some());
+
+        let disconnected = admin_disconnect_handler(String::from("1"), subscriptions_tx.clone(), clients_tx, cluster_tx).await.unwrap().into_response();
+        assert_eq!(warp::hyper::body::to_bytes(disconnected.into_body()).await.unwrap(), r#"{"disconnected":true}"#);
+        // The client ignores the close and subscribes again, which the connection no longer reads.
+        socket.send(subscribe("b")).await.unwrap();
+        timeout(Duration::from_secs(1), connection).await.unwrap().unwrap();
+        assert!(subscriptions_tx.subscribers("a").is_none());
+        assert!(subscriptions_tx.subscribers("b").is_none());
+        assert!(clients.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_admin_disconnect_keeps_clients_with_pending_events() {
+        let clients = Arc::new(Mutex::new(HashMap::from([(String::from("1"), Client { user_id: String::from("1"), ..Default::default() })])));
+        let channels = spawn_channels(clients.clone());
+        let Channels { subscriptions_tx, clients_tx, cluster_tx, .. } = channels.clone();
+        let (server, client) = tokio::io::duplex(4096);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut socket = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let registration = Client { user_id: String::from("1"), ..Default::default() };
+        let connection = tokio::spawn(client_connection(server, String::from("1"), registration.clone(), None, channels));
+
+        socket.send(subscribe("a")).await.unwrap();
+        socket.send(tungstenite::Message::text(json!(SocketRequest::new(RequestAction::GetUsage, String::from("1"), String::new(), None)).to_string())).await.unwrap();
+        timeout(Duration::from_secs(1), socket.next()).await.unwrap().unwrap().unwrap();
+        registration.track_delivery("a", EventKind::Document, String::from("{}")).await;
+
+        let disconnected = admin_disconnect_handler(String::from("1"), subscriptions_tx.clone(), clients_tx, cluster_tx).await.unwrap().into_response();
+        assert_eq!(warp::hyper::body::to_bytes(disconnected.into_body()).await.unwrap(), r#"{"disconnected":true}"#);
+        timeout(Duration::from_secs(1), connection).await.unwrap().unwrap();
+        assert!(subscriptions_tx.subscribers("a").is_none());
+        let clients = clients.lock().await;
+        let kept = clients.get("1").unwrap();
+        assert!(kept.sender.is_none());
+        assert!(kept.offline_since.is_some());
+        assert_eq!(kept.pending.lock().await.len(), 1);
+    }
+
+    #[test]
+    fn test_will_request() {
+        assert!(will_request("1", WillOptions::default()).is_none());
+
+        let will = WillOptions {
+            will_topic: Some(String::from("status/1")),
+            will_message: Some(String::from(r#"{"online":false}"#)),
+            will_retain: Some(true)
+        };
+        let request = will_request("1", will).unwrap();
+        assert_eq!(request.topic, "status/1");
+        assert_eq!(request.message, Some(json!({ "online": false })));
+        assert_eq!(request.retain, Some(true));
+
+        let will = WillOptions {
+            will_topic: Some(String::from("status/1")),
+            will_message: Some(String::from("offline")),
+            will_retain: None
+        };
+        assert_eq!(will_request("1", will).unwrap().message, Some(json!("offline")));
+    }
 }
\ No newline at end of file